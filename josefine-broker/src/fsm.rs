@@ -1,4 +1,4 @@
-use josefine_core::error::Result;
+use josefine_core::error::{JosefineError, Result};
 use josefine_raft::fsm::Fsm;
 
 #[derive(Debug)]
@@ -12,6 +12,20 @@ impl Fsm for JosefineFsm{
     fn query(&mut self, data: Vec<u8>) -> Result<Vec<u8>> {
         todo!()
     }
+
+    fn snapshot(&self) -> Result<Vec<u8>> {
+        // the broker doesn't keep any state in the state machine yet
+        Ok(Vec::new())
+    }
+
+    fn restore(&mut self, snapshot: Vec<u8>) -> Result<()> {
+        if !snapshot.is_empty() {
+            return Err(JosefineError::ApplyError {
+                error_msg: format!("unexpected {} byte snapshot for a stateless state machine", snapshot.len()),
+            });
+        }
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
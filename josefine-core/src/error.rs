@@ -9,6 +9,8 @@ pub enum JosefineError {
     ApplyError { error_msg: String },
     MessageError { error_msg: String },
     Internal { error_msg: String },
+    /// The request must be made to the raft leader. Carries the id of the leader, if it is known.
+    NotLeader { leader_id: Option<u32> },
 }
 
 impl From<std::io::Error> for JosefineError {
@@ -20,8 +22,10 @@ impl From<std::io::Error> for JosefineError {
 }
 
 impl From<serde_json::error::Error> for JosefineError {
-    fn from(_: serde_json::error::Error) -> Self {
-        unimplemented!()
+    fn from(err: serde_json::error::Error) -> Self {
+        JosefineError::Internal {
+            error_msg: err.to_string(),
+        }
     }
 }
 
@@ -1,13 +1,14 @@
-use std::time::Instant;
+use std::collections::{HashMap, VecDeque};
 
 use slog::Logger;
-use josefine_core::error::Result;
+use josefine_core::error::{JosefineError, Result};
 
+use crate::clock;
 use crate::election::{Election, ElectionStatus};
 use crate::follower::Follower;
 use crate::leader::Leader;
 use crate::progress::ReplicationProgress;
-use crate::raft::{Command, Node, NodeId};
+use crate::raft::{Command, NodeId};
 use crate::raft::Raft;
 use crate::raft::Role;
 use crate::raft::{Apply, RaftHandle, RaftRole};
@@ -17,24 +18,55 @@ use crate::rpc::Address;
 pub struct Candidate {
     pub election: Election,
     pub logger: Logger,
+    /// Whether we're still finding out if we could win, before starting the real election.
+    pub pre_vote: bool,
 }
 
 impl Raft<Candidate> {
-    pub(crate) fn seek_election(mut self) -> Result<RaftHandle> {
+    /// Asks the other voters whether they would vote for us, without incrementing our term. Only
+    /// once a quorum says they would do we start a real election, so a node that has been
+    /// partitioned away can't disrupt the cluster when it comes back.
+    pub(crate) fn seek_pre_vote(mut self) -> Result<RaftHandle> {
+        info!(self.role.logger, "Seeking pre-vote");
+        self.role.pre_vote = true;
+        let from = self.id;
+        let term = self.state.current_term + 1;
+        let (last_term, last_index) = self.last_log()?;
+
+        self.send_all(Command::PreVoteRequest {
+            term,
+            candidate_id: from,
+            last_term,
+            last_index,
+        })?;
+
+        self.apply(Command::PreVoteResponse {
+            term,
+            from,
+            granted: true,
+        })
+    }
+
+    /// Starts an election in a new term. `transfer` is set when the leader asked us to take over.
+    pub(crate) fn seek_election(mut self, transfer: bool) -> Result<RaftHandle> {
         info!(self.role.logger, "Seeking election");
+        self.role.pre_vote = false;
+        self.role.election.reset();
         self.state.voted_for = Some(self.id);
         self.state.current_term += 1;
+        self.metrics.elections.inc();
+        self.save_state()?;
         let from = self.id;
         let term = self.state.current_term;
+        let (last_term, last_index) = self.last_log()?;
 
-        for _node in &self.config.nodes {
-            self.send_all(Command::VoteRequest {
-                term,
-                candidate_id: from,
-                last_term: term,
-                last_index: self.state.last_applied,
-            })?;
-        }
+        self.send_all(Command::VoteRequest {
+            term,
+            candidate_id: from,
+            last_term,
+            last_index,
+            transfer,
+        })?;
 
         // Vote for self,
         self.apply(Command::VoteResponse {
@@ -69,17 +101,15 @@ impl Apply for Raft<Candidate> {
                     return match self.role.election.election_status() {
                         ElectionStatus::Elected => {
                             error!(self.role.logger, "This should never happen.");
-                            Ok(RaftHandle::Leader(Raft::from(self)))
+                            Ok(RaftHandle::Leader(Box::new(Raft::from(self))))
                         }
                         ElectionStatus::Voting => {
                             info!(self.role.logger, "Election ended with missing votes");
-                            self.state.voted_for = None;
                             let raft: Raft<Follower> = Raft::from(self);
                             Ok(raft.apply(Command::Timeout)?)
                         }
                         ElectionStatus::Defeated => {
                             info!(self.role.logger, "Defeated in election.");
-                            self.state.voted_for = None;
                             let raft: Raft<Follower> = Raft::from(self);
                             Ok(raft.apply(Command::Timeout)?)
                         }
@@ -88,11 +118,14 @@ impl Apply for Raft<Candidate> {
 
                 Ok(RaftHandle::Candidate(self))
             }
-            Command::VoteRequest {
-                candidate_id,
-                term: _,
-                ..
-            } => {
+            Command::VoteRequest { term, .. } if term > self.state.current_term => {
+                // a newer election has started, which we should take part in rather than
+                // holding on to our own votes
+                info!(self.role.logger, "Received higher term, transitioning to follower");
+                let raft: Raft<Follower> = Raft::from(self);
+                raft.apply(cmd)
+            }
+            Command::VoteRequest { candidate_id, .. } => {
                 self.send(
                     Address::Peer(candidate_id),
                     Command::VoteResponse {
@@ -104,15 +137,47 @@ impl Apply for Raft<Candidate> {
 
                 Ok(RaftHandle::Candidate(self))
             }
+            Command::PreVoteRequest { .. } => {
+                // we haven't heard from a leader either, so only the candidate's log matters
+                self.respond_pre_vote(&cmd, true)?;
+                Ok(RaftHandle::Candidate(self))
+            }
+            Command::PreVoteResponse { term, granted, from } => {
+                if !self.role.pre_vote || term != self.state.current_term + 1 {
+                    return Ok(RaftHandle::Candidate(self));
+                }
+
+                self.role.election.vote(from, granted);
+                match self.role.election.election_status() {
+                    ElectionStatus::Elected => {
+                        info!(self.role.logger, "Won pre-vote, starting election");
+                        self.seek_election(false)
+                    }
+                    ElectionStatus::Voting => Ok(RaftHandle::Candidate(self)),
+                    ElectionStatus::Defeated => {
+                        info!(self.role.logger, "Lost pre-vote");
+                        Ok(RaftHandle::Follower(Raft::from(self)))
+                    }
+                }
+            }
+            Command::VoteResponse { term, .. } if term > self.state.current_term => {
+                info!(self.role.logger, "Received higher term, transitioning to follower"; "term" => term);
+                self.term(term);
+                self.save_state()?;
+                Ok(RaftHandle::Follower(Raft::from(self)))
+            }
+            // a vote from an earlier election doesn't count towards this one
+            Command::VoteResponse { term, .. } if term < self.state.current_term => Ok(RaftHandle::Candidate(self)),
+            Command::VoteResponse { .. } if self.role.pre_vote => Ok(RaftHandle::Candidate(self)),
             Command::VoteResponse { granted, from, .. } => {
                 info!(self.role.logger, "Recieved vote"; "granted" => granted, "from" => from);
                 self.role.election.vote(from, granted);
                 match self.role.election.election_status() {
                     ElectionStatus::Elected => {
                         info!(self.role.logger, "I have been elected leader");
-                        let raft = Raft::from(self);
+                        let mut raft = Raft::from(self);
                         raft.heartbeat()?;
-                        Ok(RaftHandle::Leader(raft))
+                        Ok(RaftHandle::Leader(Box::new(raft)))
                     }
                     ElectionStatus::Voting => {
                         info!(self.role.logger, "We are still voting");
@@ -120,14 +185,11 @@ impl Apply for Raft<Candidate> {
                     }
                     ElectionStatus::Defeated => {
                         info!(self.role.logger, "I was defeated in the election");
-                        self.state.voted_for = None;
                         Ok(RaftHandle::Follower(Raft::from(self)))
                     }
                 }
             }
-            Command::AppendEntries {
-                entries: _, term, ..
-            } => {
+            Command::AppendEntries { term, .. } => {
                 // While waiting for votes, a candidate may receive an
                 // AppendEntries RPC from another server claiming to be
                 // leader. If the leader’s term (included in its RPC) is at least
@@ -140,8 +202,7 @@ impl Apply for Raft<Candidate> {
                         "Received higher term, transitioning to follower"
                     );
                     let raft: Raft<Follower> = Raft::from(self);
-                    //                    raft.io.append(entries)?;
-                    return Ok(RaftHandle::Follower(raft));
+                    return raft.apply(cmd);
                 }
 
                 // TODO: If the term in the RPC is smaller than the candidate’s
@@ -149,21 +210,41 @@ impl Apply for Raft<Candidate> {
 
                 Ok(RaftHandle::Candidate(self))
             }
-            Command::Heartbeat {
-                term, leader_id: _, ..
-            } => {
+            Command::Heartbeat { term, .. } => {
                 if term >= self.state.current_term {
                     info!(
                         self.role.logger,
                         "Received higher term, transitioning to follower"
                     );
                     let raft: Raft<Follower> = Raft::from(self);
-                    //                    raft.io.heartbeat(leader_id);
-                    return Ok(RaftHandle::Follower(raft));
+                    return raft.apply(cmd);
                 }
 
                 Ok(RaftHandle::Candidate(self))
             }
+            Command::InstallSnapshot { term, .. } if term >= self.state.current_term => {
+                info!(
+                    self.role.logger,
+                    "Received snapshot from leader, transitioning to follower"
+                );
+                let raft: Raft<Follower> = Raft::from(self);
+                raft.apply(cmd)
+            }
+            Command::Compact { index, term } => {
+                self.compact(index, term)?;
+                Ok(RaftHandle::Candidate(self))
+            }
+            Command::ClientRequest { id, .. } => {
+                // there's no leader to forward to until the election is over
+                self.send(
+                    Address::Client,
+                    Command::ClientResponse {
+                        id,
+                        res: Err(JosefineError::NotLeader { leader_id: None }),
+                    },
+                )?;
+                Ok(RaftHandle::Candidate(self))
+            }
             _ => Ok(RaftHandle::Candidate(self)),
         }
     }
@@ -176,13 +257,19 @@ impl From<Raft<Candidate>> for Raft<Follower> {
             state: val.state,
             role: Follower {
                 leader_id: None,
+                matched: 0,
                 logger: val.logger.new(o!("role" => "follower")),
+                snapshot: None,
+                installing: None,
             },
             logger: val.logger,
             config: val.config,
             log: val.log,
+            hard_state: val.hard_state,
+            membership: val.membership,
             rpc_tx: val.rpc_tx,
             fsm_tx: val.fsm_tx,
+            metrics: val.metrics,
         }
     }
 }
@@ -191,26 +278,120 @@ impl From<Raft<Candidate>> for Raft<Leader> {
     fn from(val: Raft<Candidate>) -> Raft<Leader> {
         info!(val.role.logger, "Becoming the leader");
 
-        let mut nodes: Vec<NodeId> = val.config.nodes.iter().map(|x| x.id).collect();
-        nodes.push(val.id);
-        let progress = ReplicationProgress::new(nodes);
+        let mut nodes: Vec<NodeId> = val.membership.config.node_ids();
+        if !nodes.contains(&val.id) {
+            nodes.push(val.id);
+        }
+        let progress = ReplicationProgress::new(nodes, val.log.next_index(), val.config.max_inflight);
         Raft {
             id: val.id,
             state: val.state,
             role: Leader {
                 logger: val.logger.new(o!("role" => "leader")),
                 progress,
-                heartbeat_time: Instant::now(),
+                heartbeat_time: clock::now(),
                 heartbeat_timeout: val.config.heartbeat_timeout,
+                membership_request: None,
+                transfer: None,
+                heartbeat_seq: 0,
+                reads: Vec::new(),
+                heartbeats: VecDeque::new(),
+                acked: HashMap::new(),
+                lease_floor: 0,
+                proposals: Vec::new(),
+                proposal_bytes: 0,
+                appends: HashMap::new(),
+                snapshot: None,
             },
             logger: val.logger,
             config: val.config,
             log: val.log,
+            hard_state: val.hard_state,
+            membership: val.membership,
             rpc_tx: val.rpc_tx,
             fsm_tx: val.fsm_tx,
+            metrics: val.metrics,
         }
     }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use crate::raft::{Apply, Command, RaftHandle};
+    use crate::test::new_follower_with_peer;
+
+    #[test]
+    fn pre_vote_keeps_term_until_won() {
+        let ((_rpc_rx, _fsm_rx), mut node) = new_follower_with_peer();
+        node.config.pre_vote = true;
+
+        let node = match node.apply(Command::Timeout).unwrap() {
+            RaftHandle::Candidate(candidate) => {
+                assert!(candidate.role.pre_vote);
+                assert_eq!(candidate.state.current_term, 0);
+                RaftHandle::Candidate(candidate)
+            }
+            _ => panic!(),
+        };
+
+        match node.apply(Command::PreVoteResponse { term: 1, from: 2, granted: true }).unwrap() {
+            RaftHandle::Candidate(candidate) => {
+                assert!(!candidate.role.pre_vote);
+                assert_eq!(candidate.state.current_term, 1);
+            }
+            _ => panic!(),
+        }
+    }
+    #[test]
+    fn counts_only_votes_from_this_term() {
+        let ((_rpc_rx, _fsm_rx), node) = new_follower_with_peer();
+        let node = node.apply(Command::Timeout).unwrap();
+        let node = node.apply(Command::VoteResponse { term: 0, from: 2, granted: true }).unwrap();
+        assert!(matches!(node, RaftHandle::Candidate(_)));
+
+        match node.apply(Command::VoteResponse { term: 3, from: 2, granted: false }).unwrap() {
+            RaftHandle::Follower(follower) => {
+                assert_eq!(follower.state.current_term, 3);
+                assert_eq!(follower.state.voted_for, None);
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn keeps_vote_after_defeat() {
+        let ((_rpc_rx, _fsm_rx), node) = new_follower_with_peer();
+        let id = node.id;
+        let node = node
+            .apply(Command::Timeout)
+            .unwrap()
+            .apply(Command::VoteResponse { term: 1, from: 2, granted: false })
+            .unwrap();
+
+        // we voted for ourselves in this term, so can't vote for anyone else in it
+        match node {
+            RaftHandle::Follower(follower) => {
+                assert_eq!(follower.state.current_term, 1);
+                assert_eq!(follower.state.voted_for, Some(id));
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn follows_leader_of_same_term() {
+        let ((_rpc_rx, _fsm_rx), node) = new_follower_with_peer();
+        let node = node
+            .apply(Command::Timeout)
+            .unwrap()
+            .apply(Command::Heartbeat { term: 1, leader_id: 2, seq: 1, leader_commit: 0 })
+            .unwrap();
+        match node {
+            RaftHandle::Follower(follower) => {
+                assert_eq!(follower.state.current_term, 1);
+                assert_eq!(follower.role.leader_id, Some(2));
+            }
+            _ => panic!(),
+        }
+    }
+}
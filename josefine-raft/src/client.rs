@@ -1,4 +1,5 @@
 use tokio::sync::mpsc::UnboundedSender;
+use crate::raft::{Node, NodeId};
 use crate::rpc::{Request, Response};
 use tokio::sync::oneshot;
 use josefine_core::error::{Result, JosefineError};
@@ -37,4 +38,45 @@ impl RaftClient {
             resp => Err(JosefineError::Internal { error_msg: format!("Unexpected Raft query response {:?}", resp) }),
         }
     }
+
+    /// Adds a voting member to the cluster, returning once the change has been committed.
+    pub async fn add_node(&self, node: Node) -> Result<Vec<Node>> {
+        match self.request(Request::AddNode(node)).await? {
+            Response::Membership(voters) => Ok(voters),
+            resp => Err(JosefineError::Internal { error_msg: format!("Unexpected Raft membership response {:?}", resp) }),
+        }
+    }
+
+    /// Adds a non-voting member to the cluster, returning once the change has been committed.
+    pub async fn add_learner(&self, node: Node) -> Result<Vec<Node>> {
+        match self.request(Request::AddLearner(node)).await? {
+            Response::Membership(voters) => Ok(voters),
+            resp => Err(JosefineError::Internal { error_msg: format!("Unexpected Raft membership response {:?}", resp) }),
+        }
+    }
+
+    /// Promotes a learner to a voting member, returning once the change has been committed.
+    pub async fn promote_learner(&self, node_id: NodeId) -> Result<Vec<Node>> {
+        match self.request(Request::PromoteLearner(node_id)).await? {
+            Response::Membership(voters) => Ok(voters),
+            resp => Err(JosefineError::Internal { error_msg: format!("Unexpected Raft membership response {:?}", resp) }),
+        }
+    }
+
+    /// Removes a member from the cluster, returning once the change has been committed.
+    pub async fn remove_node(&self, node_id: NodeId) -> Result<Vec<Node>> {
+        match self.request(Request::RemoveNode(node_id)).await? {
+            Response::Membership(voters) => Ok(voters),
+            resp => Err(JosefineError::Internal { error_msg: format!("Unexpected Raft membership response {:?}", resp) }),
+        }
+    }
+
+    /// Hands leadership over to another voter, e.g. before taking the current leader down for
+    /// maintenance.
+    pub async fn transfer_leadership(&self, node_id: NodeId) -> Result<NodeId> {
+        match self.request(Request::TransferLeadership(node_id)).await? {
+            Response::LeadershipTransferred(node_id) => Ok(node_id),
+            resp => Err(JosefineError::Internal { error_msg: format!("Unexpected Raft transfer response {:?}", resp) }),
+        }
+    }
 }
\ No newline at end of file
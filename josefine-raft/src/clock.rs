@@ -0,0 +1,87 @@
+//! The time and randomness seen by the raft state machine. These normally come from the system,
+//! but a simulation can take control of both for the current thread, so that a run can be
+//! reproduced exactly from its seed.
+use std::cell::RefCell;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::Rng;
+
+struct Simulated {
+    /// The real instant the simulation started at, which virtual time is measured from.
+    start: Instant,
+    /// How far virtual time has advanced.
+    now: Duration,
+    rng: StdRng,
+}
+
+thread_local! {
+    static SIMULATED: RefCell<Option<Simulated>> = const { RefCell::new(None) };
+}
+
+/// The current time.
+pub fn now() -> Instant {
+    SIMULATED.with(|sim| match &*sim.borrow() {
+        Some(sim) => sim.start + sim.now,
+        None => Instant::now(),
+    })
+}
+
+/// The time that has passed since `since`.
+pub fn elapsed(since: Instant) -> Duration {
+    now().saturating_duration_since(since)
+}
+
+/// A random number in `range`.
+pub fn gen_range(range: Range<u64>) -> u64 {
+    SIMULATED.with(|sim| match &mut *sim.borrow_mut() {
+        Some(sim) => sim.rng.gen_range(range),
+        None => rand::thread_rng().gen_range(range),
+    })
+}
+
+/// Replaces the system clock and random number generator on this thread with a virtual clock,
+/// which only moves when advanced, and a generator seeded with `seed`.
+#[cfg(test)]
+pub(crate) fn simulate(seed: u64) {
+    SIMULATED.with(|sim| {
+        *sim.borrow_mut() = Some(Simulated {
+            start: Instant::now(),
+            now: Duration::from_secs(0),
+            rng: <StdRng as rand::SeedableRng>::seed_from_u64(seed),
+        })
+    });
+}
+
+/// Moves the virtual clock forward.
+#[cfg(test)]
+pub(crate) fn advance(by: Duration) {
+    SIMULATED.with(|sim| {
+        if let Some(sim) = &mut *sim.borrow_mut() {
+            sim.now += by;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{advance, elapsed, gen_range, now, simulate};
+
+    #[test]
+    fn virtual_time_only_moves_when_advanced() {
+        simulate(7);
+        let start = now();
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(elapsed(start), Duration::from_secs(0));
+        advance(Duration::from_millis(250));
+        assert_eq!(elapsed(start), Duration::from_millis(250));
+
+        let first: Vec<u64> = (0..8).map(|_| gen_range(0..1000)).collect();
+        simulate(7);
+        let second: Vec<u64> = (0..8).map(|_| gen_range(0..1000)).collect();
+        assert_eq!(first, second);
+    }
+}
@@ -0,0 +1,161 @@
+use bincode::Options;
+use bytes::{Bytes, BytesMut};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec};
+
+use josefine_core::error::{JosefineError, Result};
+
+use crate::config::{MAX_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION};
+use crate::rpc::Message;
+
+/// The version of the handshake's framing, sent as a single byte when connecting to a peer. The
+/// messages that follow are encoded in the protocol version the handshake settles on.
+pub const WIRE_VERSION: u8 = 1;
+/// The oldest handshake framing we still read.
+pub const MIN_WIRE_VERSION: u8 = 1;
+
+/// Frames messages between peers as length-delimited bincode, in the encoding of a negotiated
+/// protocol version.
+pub struct MessageCodec {
+    frames: LengthDelimitedCodec,
+    version: u32,
+}
+
+impl MessageCodec {
+    pub fn new(version: u32) -> MessageCodec {
+        MessageCodec {
+            frames: LengthDelimitedCodec::new(),
+            version,
+        }
+    }
+
+    /// Fails for protocol versions we can't encode messages in, which a successful handshake
+    /// never settles on.
+    fn check_version(&self) -> Result<()> {
+        if !(MIN_PROTOCOL_VERSION..=MAX_PROTOCOL_VERSION).contains(&self.version) {
+            return Err(JosefineError::MessageError {
+                error_msg: format!("unsupported protocol version {}", self.version),
+            });
+        }
+        Ok(())
+    }
+}
+
+fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+}
+
+fn message_error(err: bincode::Error) -> JosefineError {
+    JosefineError::MessageError {
+        error_msg: err.to_string(),
+    }
+}
+
+/// Encodes a value in the wire format.
+pub fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    options().serialize(value).map_err(message_error)
+}
+
+/// Decodes a value from the wire format.
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    options().deserialize(bytes).map_err(message_error)
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = JosefineError;
+
+    fn encode(&mut self, message: Message, dst: &mut BytesMut) -> Result<()> {
+        self.check_version()?;
+        let bytes = encode(&message)?;
+        self.frames.encode(Bytes::from(bytes), dst)?;
+        Ok(())
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = JosefineError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>> {
+        self.check_version()?;
+        match self.frames.decode(src)? {
+            Some(frame) => decode(&frame).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Sends our wire version, which must precede any messages on a new connection.
+pub async fn write_version<S: AsyncWrite + Unpin>(stream: &mut S) -> Result<()> {
+    stream.write_u8(WIRE_VERSION).await?;
+    Ok(())
+}
+
+/// Reads the wire version sent by a peer, failing if it isn't one we can read.
+pub async fn read_version<S: AsyncRead + Unpin>(stream: &mut S) -> Result<u8> {
+    let version = stream.read_u8().await?;
+    if !(MIN_WIRE_VERSION..=WIRE_VERSION).contains(&version) {
+        return Err(JosefineError::MessageError {
+            error_msg: format!(
+                "unsupported wire version {}, expected {} to {}",
+                version, MIN_WIRE_VERSION, WIRE_VERSION
+            ),
+        });
+    }
+    Ok(version)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use super::{read_version, MessageCodec, WIRE_VERSION};
+    use crate::config::MAX_PROTOCOL_VERSION;
+    use crate::raft::{Command, Entry, EntryType};
+    use crate::rpc::{Address, Message};
+
+    #[test]
+    fn round_trip() {
+        let message = Message::new(
+            Address::Peer(1),
+            Address::Peer(2),
+            Command::AppendEntries {
+                term: 3,
+                leader_id: 1,
+                entries: vec![Entry {
+                    entry_type: EntryType::Entry { data: vec![255; 1024] },
+                    term: 3,
+                    index: 7,
+                }],
+                prev_log_index: 6,
+                prev_log_term: 2,
+                leader_commit: 5,
+            },
+        );
+
+        let mut codec = MessageCodec::new(MAX_PROTOCOL_VERSION);
+        let mut buf = BytesMut::new();
+        codec.encode(message.clone(), &mut buf).unwrap();
+        // payloads are sent as raw bytes rather than arrays of numbers
+        assert!(buf.len() < 1100);
+
+        let mut partial = buf.split_to(buf.len() - 1);
+        assert!(codec.decode(&mut partial).unwrap().is_none());
+        partial.unsplit(buf);
+        assert_eq!(codec.decode(&mut partial).unwrap(), Some(message));
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_version() {
+        let mut stream: &[u8] = &[0];
+        assert!(read_version(&mut stream).await.is_err());
+        let mut stream: &[u8] = &[WIRE_VERSION];
+        assert_eq!(read_version(&mut stream).await.unwrap(), WIRE_VERSION);
+
+        let mut codec = MessageCodec::new(MAX_PROTOCOL_VERSION + 1);
+        assert!(codec.decode(&mut BytesMut::new()).is_err());
+    }
+}
@@ -1,5 +1,5 @@
 use std::net::ToSocketAddrs;
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
 use std::time::Duration;
 
@@ -8,11 +8,11 @@ use crate::raft::NodeId;
 
 use josefine_core::error::{Result, JosefineError};
 
-#[serde(default)]
-#[derive(Clone, Debug, Serialize, Deserialize)]
 /// The configuration for this Raft instance.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct RaftConfig {
-    ///
+    /// Where the log, snapshots and the node's persistent state are kept.
     pub data_directory: PathBuf,
     /// Limit run-time for testing
     pub run_for: Option<Duration>,
@@ -24,29 +24,108 @@ pub struct RaftConfig {
     pub port: u16,
     /// A list of addresses to query for cluster membership.
     pub nodes: Vec<Node>,
-    /// The version of the protocol spoken by this instance.
+    /// The name of the cluster this instance belongs to. Peers from other clusters are refused.
+    pub cluster_id: String,
+    /// The newest version of the protocol spoken by this instance. Peers speak the newest version
+    /// they have in common, so this is raised only once every node has been upgraded.
     pub protocol_version: u32,
     /// The default timeout for a heartbeat.
     pub heartbeat_timeout: Duration,
-    /// The default timeout for an election.
+    /// The longest a follower waits to hear from a leader before starting an election. Each wait
+    /// is picked at random from between half of this and all of it.
     pub election_timeout: Duration,
-    ///
+    /// Not currently used.
     pub commit_timeout: Duration,
     /// Maximum number of entries that can be sent in an append message.
     pub max_append_entries: u64,
-    ///
+    /// Maximum number of append messages the leader sends a follower without waiting for them to
+    /// be acknowledged.
+    pub max_inflight: usize,
+    /// How long the leader waits for more client proposals before appending them together.
+    pub batch_timeout: Duration,
+    /// The number of bytes of client proposals that are appended together without waiting for
+    /// the batch timeout. Zero disables batching.
+    pub max_batch_bytes: usize,
+    /// How long a client request may wait for a response before it fails, e.g. because the leader
+    /// it was forwarded to went away.
+    pub request_timeout: Duration,
+    /// The minimum time between snapshots.
     pub snapshot_interval: Duration,
-    ///
+    /// The number of entries applied since the last snapshot that triggers a new one.
     pub snapshot_threshold: u64,
+    /// The size in bytes at which the on-disk log rolls over to a new segment.
+    pub segment_size: u64,
+    /// When the on-disk log is flushed to stable storage.
+    pub sync_policy: SyncPolicy,
+    /// Whether a candidate first checks that it could win an election before incrementing its
+    /// term, so a node rejoining after a partition can't force a healthy leader to step down.
+    pub pre_vote: bool,
+    /// How the leader makes sure reads aren't stale.
+    pub read_mode: ReadMode,
+    /// The most the clocks of two nodes may drift apart over an election timeout. Leases are
+    /// shortened by this much.
+    pub max_clock_drift: Duration,
+    /// How long to wait before the first attempt to reconnect to a peer. The wait doubles with
+    /// every failed attempt, up to `max_reconnect_backoff`.
+    pub reconnect_backoff: Duration,
+    /// The longest wait between attempts to reconnect to a peer. A peer is considered down once
+    /// the wait reaches it.
+    pub max_reconnect_backoff: Duration,
+    /// The number of messages queued for each peer, beyond which messages to it are dropped.
+    pub peer_queue_size: usize,
+    /// Encrypts traffic between peers and requires them to authenticate, when set.
+    pub tls: Option<TlsConfig>,
+    /// Where Prometheus metrics are served over HTTP, at `/metrics`, when set.
+    pub metrics_addr: Option<SocketAddr>,
+    /// Where the node's status is served over HTTP as JSON, at `/status`, when set. The endpoint
+    /// isn't authenticated, so this should be a loopback address.
+    pub admin_addr: Option<SocketAddr>,
 }
 
-const MAX_PROTOCOL_VERSION: u32 = 0;
+/// The PEM files used for mutual TLS between peers. Each node's certificate must be valid for the
+/// name `node-<id>`, which is how a connecting peer is matched to a node in the cluster.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// This node's certificate chain.
+    pub cert: PathBuf,
+    /// The private key for `cert`, in PKCS#8 or RSA format.
+    pub key: PathBuf,
+    /// The certificate authorities that peer certificates must be issued by.
+    pub ca: PathBuf,
+}
+
+/// Controls how often the on-disk log is fsynced.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SyncPolicy {
+    /// Sync after every append. Required for Raft's durability guarantees.
+    Always,
+    /// Sync only when the commit index advances.
+    Commit,
+    /// Leave flushing to the operating system.
+    Never,
+}
+
+/// Controls how the leader confirms it is still the leader before serving a read.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ReadMode {
+    /// Confirm leadership with a round of heartbeats for every read.
+    ReadIndex,
+    /// Serve reads locally while a quorum has acknowledged a recent heartbeat, relying on
+    /// followers not electing a new leader until their election timeout has passed. Only safe
+    /// if clocks drift by no more than `max_clock_drift`.
+    Lease,
+}
+
+/// The oldest protocol version this implementation speaks.
+pub const MIN_PROTOCOL_VERSION: u32 = 0;
+/// The newest protocol version this implementation speaks.
+pub const MAX_PROTOCOL_VERSION: u32 = 0;
 
 impl RaftConfig {
     pub fn config(config_path: &std::path::Path) -> RaftConfig {
         let mut settings = config::Config::default();
         settings
-            .merge(config::File::from(config_path.as_ref()))
+            .merge(config::File::from(config_path))
             .expect("Could not read configuration file")
             .merge(config::Environment::with_prefix("JOSEFINE"))
             .expect("Could not read environment variables");
@@ -54,9 +133,14 @@ impl RaftConfig {
         settings.try_into().expect("Could not create configuration")
     }
 
+    /// The shortest a follower waits to hear from a leader before starting an election.
+    pub fn min_election_timeout(&self) -> Duration {
+        self.election_timeout / 2
+    }
+
     /// Validates the configuration, ensuring all values make sense.
     pub fn validate(&self) -> Result<()> {
-        if self.protocol_version > MAX_PROTOCOL_VERSION {
+        if !(MIN_PROTOCOL_VERSION..=MAX_PROTOCOL_VERSION).contains(&self.protocol_version) {
             return Err(JosefineError::ConfigError {
                 file_path: "".to_string(),
                 error_msg: "Invalid protocol version.".to_string(),
@@ -92,6 +176,25 @@ impl RaftConfig {
                 error_msg: "Commit timeout is too low.".to_string(),
             });
         }
+        if self.max_append_entries == 0 || self.max_inflight == 0 {
+            return Err(JosefineError::ConfigError {
+                file_path: "".to_string(),
+                error_msg: "Append limits must allow at least one message.".to_string(),
+            });
+        }
+        if self.batch_timeout < Duration::from_millis(1) {
+            return Err(JosefineError::ConfigError {
+                file_path: "".to_string(),
+                error_msg: "Batch timeout is too low.".to_string(),
+            });
+        }
+
+        if self.request_timeout < Duration::from_millis(1) {
+            return Err(JosefineError::ConfigError {
+                file_path: "".to_string(),
+                error_msg: "Request timeout is too low.".to_string(),
+            });
+        }
         if self.snapshot_interval < Duration::from_millis(5) {
             return Err(JosefineError::ConfigError {
                 file_path: "".to_string(),
@@ -99,6 +202,51 @@ impl RaftConfig {
             });
         }
 
+        if self.read_mode == ReadMode::Lease && self.max_clock_drift >= self.min_election_timeout() {
+            return Err(JosefineError::ConfigError {
+                file_path: "".to_string(),
+                error_msg: "Clock drift bound must be below half the election timeout.".to_string(),
+            });
+        }
+
+        if self.segment_size < 1024 {
+            return Err(JosefineError::ConfigError {
+                file_path: "".to_string(),
+                error_msg: "Segment size is too low.".to_string(),
+            });
+        }
+
+        if self.reconnect_backoff < Duration::from_millis(1) || self.max_reconnect_backoff < self.reconnect_backoff {
+            return Err(JosefineError::ConfigError {
+                file_path: "".to_string(),
+                error_msg: "Reconnect backoff must be positive and no more than its maximum.".to_string(),
+            });
+        }
+        if self.peer_queue_size == 0 {
+            return Err(JosefineError::ConfigError {
+                file_path: "".to_string(),
+                error_msg: "Peer queue size must allow at least one message.".to_string(),
+            });
+        }
+
+        if self.admin_addr.is_some() && self.admin_addr == self.metrics_addr {
+            return Err(JosefineError::ConfigError {
+                file_path: "".to_string(),
+                error_msg: "The admin and metrics endpoints must use different addresses.".to_string(),
+            });
+        }
+
+        if let Some(tls) = &self.tls {
+            for path in &[&tls.cert, &tls.key, &tls.ca] {
+                if !path.is_file() {
+                    return Err(JosefineError::ConfigError {
+                        file_path: path.display().to_string(),
+                        error_msg: "TLS file does not exist.".to_string(),
+                    });
+                }
+            }
+        }
+
         Ok(())
     }
 }
@@ -119,13 +267,29 @@ impl Default for RaftConfig {
             ip,
             port: 6669,
             nodes: vec![],
+            cluster_id: "josefine".to_string(),
             protocol_version: 0,
             heartbeat_timeout: Duration::from_millis(100),
             election_timeout: Duration::from_millis(1000),
             commit_timeout: Duration::from_millis(50),
             max_append_entries: 64,
+            max_inflight: 8,
+            batch_timeout: Duration::from_millis(2),
+            max_batch_bytes: 1024 * 1024,
+            request_timeout: Duration::from_secs(10),
             snapshot_interval: Duration::from_secs(120),
             snapshot_threshold: 8192,
+            segment_size: 64 * 1024 * 1024,
+            sync_policy: SyncPolicy::Always,
+            pre_vote: false,
+            read_mode: ReadMode::ReadIndex,
+            max_clock_drift: Duration::from_millis(50),
+            reconnect_backoff: Duration::from_millis(100),
+            max_reconnect_backoff: Duration::from_secs(5),
+            peer_queue_size: 1000,
+            tls: None,
+            metrics_addr: None,
+            admin_addr: None,
         }
     }
 }
@@ -133,7 +297,7 @@ impl Default for RaftConfig {
 fn resolve(host: &str) -> Option<IpAddr> {
     (host, 0)
         .to_socket_addrs()
-        .map(|iter| iter.map(|socket_address| socket_address.ip()).nth(0))
+        .map(|iter| iter.map(|socket_address| socket_address.ip()).next())
         .unwrap()
 }
 
@@ -142,7 +306,7 @@ mod tests {
     use std::net::IpAddr;
     use std::time::Duration;
 
-    use super::RaftConfig;
+    use super::{RaftConfig, ReadMode};
 
     #[test]
     fn default() {
@@ -168,6 +332,20 @@ mod tests {
         };
 
         let res = config.validate();
-        assert_eq!(true, res.is_err());
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn lease_needs_drift_below_shortest_election_timeout() {
+        let config = RaftConfig {
+            id: 1,
+            port: 6669,
+            read_mode: ReadMode::Lease,
+            election_timeout: Duration::from_millis(1000),
+            max_clock_drift: Duration::from_millis(600),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        assert!(RaftConfig { max_clock_drift: Duration::from_millis(400), ..config }.validate().is_ok());
     }
 }
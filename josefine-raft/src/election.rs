@@ -1,10 +1,11 @@
 use std::collections::HashMap;
 
+use crate::membership::ClusterConfig;
 use crate::raft::NodeId;
 
 #[derive(Debug)]
 pub struct Election {
-    voter_ids: Vec<NodeId>,
+    config: ClusterConfig,
     votes: HashMap<NodeId, bool>,
 }
 
@@ -16,9 +17,9 @@ pub enum ElectionStatus {
 }
 
 impl Election {
-    pub fn new(voter_ids: Vec<NodeId>) -> Election {
+    pub fn new(config: ClusterConfig) -> Election {
         let mut election = Election {
-            voter_ids,
+            config,
             votes: HashMap::new(),
         };
 
@@ -35,40 +36,16 @@ impl Election {
     }
 
     pub fn election_status(&self) -> ElectionStatus {
-        let (votes, total) =
-            self.votes
-                .iter()
-                .fold((0, 0), |(mut votes, mut total), (_id, vote)| {
-                    if *vote {
-                        votes += 1;
-                    }
+        let granted = |id| self.votes.get(&id) == Some(&true);
+        let not_denied = |id| self.votes.get(&id) != Some(&false);
 
-                    total += 1;
-                    (votes, total)
-                });
-
-        if votes >= self.quorum_size() {
+        if self.config.has_quorum(granted) {
             ElectionStatus::Elected
-        } else if total - votes == self.quorum_size() {
+        } else if !self.config.has_quorum(not_denied) {
+            // even if every remaining voter grants us their vote, we can't win
             ElectionStatus::Defeated
         } else {
             ElectionStatus::Voting
         }
     }
-
-    #[inline]
-    #[allow(dead_code)]
-    fn voters_size(&self) -> usize {
-        self.voter_ids.len()
-    }
-
-    #[inline]
-    fn quorum_size(&self) -> usize {
-        // If we are a single node cluster, we always win the election
-        if self.voter_ids.len() == 1 {
-            return 0;
-        }
-
-        (self.voter_ids.len() / 2) + 1
-    }
 }
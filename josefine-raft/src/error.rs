@@ -8,7 +8,9 @@ pub enum RaftError {
 
 impl From<RaftError> for JosefineError {
     fn from(err: RaftError) -> JosefineError {
-        unimplemented!()
+        match err {
+            RaftError::MessageError { error_msg } => JosefineError::MessageError { error_msg },
+        }
     }
 }
 
@@ -30,7 +32,9 @@ impl From<tokio::sync::mpsc::error::SendError<fsm::Instruction>> for RaftError {
 }
 
 impl From<tokio::sync::mpsc::error::TrySendError<Message>> for RaftError {
-    fn from(_: tokio::sync::mpsc::error::TrySendError<Message>) -> Self {
-        unimplemented!()
+    fn from(err: tokio::sync::mpsc::error::TrySendError<Message>) -> Self {
+        RaftError::MessageError {
+            error_msg: err.to_string(),
+        }
     }
 }
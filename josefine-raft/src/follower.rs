@@ -1,31 +1,41 @@
 use std::time::Duration;
-use std::time::Instant;
 
-use rand::Rng;
-use slog;
 use slog::Logger;
 
-use crate::{candidate::Candidate, fsm};
-use crate::config::RaftConfig;
+use crate::{candidate::Candidate, clock, fsm};
+use crate::config::{RaftConfig, ReadMode};
 use crate::election::Election;
 use crate::error::RaftError;
 use crate::log::Log;
+use crate::membership::{ClusterConfig, Membership};
+use crate::metrics::Metrics;
+use crate::store::{FileStore, HardStateFile, Snapshot, SnapshotFile, Store};
 use crate::raft::Command::VoteResponse;
-use crate::raft::{Apply, LogIndex, RaftHandle, RaftRole, Term};
+use crate::raft::{Apply, Conflict, EntryType, LogIndex, Node, RaftHandle, RaftRole, Term};
 use crate::raft::{Command, NodeId, Raft, Role, State};
 use crate::rpc::{Address, Message};
-use josefine_core::error::Result;
+use josefine_core::error::{JosefineError, Result};
 use tokio::sync::mpsc::UnboundedSender;
 
 #[derive(Debug)]
 pub struct Follower {
     pub leader_id: Option<NodeId>,
+    /// The last index our log is known to share with the leader's, which is as far as we can
+    /// commit.
+    pub matched: LogIndex,
     pub logger: Logger,
+    /// A snapshot that is partway through being received from the leader.
+    pub snapshot: Option<Snapshot>,
+    /// The index of a snapshot received from the leader that the state machine is installing.
+    /// The leader hears about it once the snapshot has been saved.
+    pub installing: Option<LogIndex>,
 }
 
 impl Role for Follower {
     fn term(&mut self, _term: u64) {
         self.leader_id = None;
+        self.matched = 0;
+        self.installing = None;
     }
 
     fn role(&self) -> RaftRole {
@@ -54,81 +64,145 @@ impl Apply for Raft<Follower> {
                 term,
                 prev_log_index,
                 prev_log_term,
+                leader_commit,
             } => {
-                // If we haven't voted and the rpc term is greater, set term to that term.
-                if self.state.voted_for.is_none() && term >= self.state.current_term {
-                    self.term(term);
-
-                    // Vote for leader and reset election timeout
-                    self.state.election_time = Some(Instant::now());
-                    self.role.leader_id = Some(leader_id);
-                    self.state.voted_for = Some(leader_id);
-                }
-
-                // If we voted for someone...
-                if let Some(voted_for) = self.state.voted_for {
-                    // And the entries rpc is from another "leader" with a lower term
-                    if voted_for != leader_id && term < self.state.current_term {
-                        // Something is wrong, fail fast.
-                        panic!();
-                    }
+                // Entries from a leader of an earlier term were delayed on the way, or their
+                // leader was deposed without noticing. Either way they can't be trusted, and our
+                // term tells the old leader to step down.
+                if term < self.state.current_term {
+                    self.send(
+                        Address::Peer(leader_id),
+                        Command::AppendResponse {
+                            node_id: self.id,
+                            term: self.state.current_term,
+                            index: prev_log_index,
+                            success: false,
+                            conflict: None,
+                        },
+                    )?;
+                    return self.apply_self();
                 }
+                self.follow(term, leader_id)?;
 
-                // If we don't have a log at prev index and term, respond false
+                // If we don't have a log at prev index and term, tell the leader where we diverge
                 if !self.log.check_term(prev_log_index, prev_log_term) {
-                    // self.nodes[&leader_id];
-                    // let _ = Message::new(self.state.current_term, self.id, false);
+                    let conflict = self.conflict(prev_log_index)?;
+                    self.send(
+                        Address::Peer(leader_id),
+                        Command::AppendResponse {
+                            node_id: self.id,
+                            term: self.state.current_term,
+                            index: prev_log_index,
+                            success: false,
+                            conflict: Some(conflict),
+                        },
+                    )?;
                     return self.apply_self();
                 }
 
-                // If there are entries...
+                // Append any new entries, then confirm how far our log matches the leader's,
+                // which answers an empty probe too
+                let index = prev_log_index + entries.len() as LogIndex;
                 if !entries.is_empty() {
+                    let mut new_entries = Vec::new();
                     for entry in entries {
-                        let index = entry.index;
-                        self.log.append(entry)?; // append the entry
-                        self.state.last_applied = index; // update our last applied
+                        // once one entry is new, so are all the ones that follow it
+                        if new_entries.is_empty() {
+                            match self.log.term(entry.index)? {
+                                // already covered by our snapshot, or already in our log
+                                _ if entry.index <= self.log.snapshot_index() => continue,
+                                Some(term) if term == entry.term => continue,
+                                // an existing entry conflicts with the leader's, so discard it and
+                                // everything that follows it
+                                Some(_) => {
+                                    self.log.truncate(entry.index - 1)?;
+                                    self.membership.truncate(entry.index - 1);
+                                    self.save_state()?;
+                                }
+                                None => {}
+                            }
+                        }
+                        new_entries.push(entry);
+                    }
+                    if !new_entries.is_empty() {
+                        self.append_entries(new_entries)?;
                     }
+                    self.state.last_applied = self.log.next_index() - 1; // update our last applied
+                }
+                self.role.matched = std::cmp::max(self.role.matched, index);
+                self.commit(leader_commit)?;
 
-                    // confirm append
-                    self.rpc_tx.send(Message::new(Address::Peer(self.id), Address::Peer(leader_id), Command::AppendResponse {
+                self.send(
+                    Address::Peer(leader_id),
+                    Command::AppendResponse {
                         node_id: self.id,
                         term: self.state.current_term,
-                        index: self.state.last_applied,
+                        index,
                         success: true,
-                    })).map_err(|err| RaftError::from(err))?;
-                }
+                        conflict: None,
+                    },
+                )?;
 
                 self.apply_self()
             }
-            Command::Heartbeat { leader_id, .. } => {
-                self.set_election_timeout();
-                self.role.leader_id = Some(leader_id);
-                self.state.voted_for = Some(leader_id);
+            Command::Heartbeat { term, leader_id, seq, leader_commit } => {
+                // a deposed leader learns of the new term from our response, which doesn't count
+                // as an acknowledgement of its leadership
+                if term >= self.state.current_term {
+                    self.follow(term, leader_id)?;
+                    self.commit(leader_commit)?;
+                }
                 self.send(
                     Address::Peer(leader_id),
-                    Command::Heartbeat {
+                    Command::HeartbeatResponse {
                         term: self.state.current_term,
-                        leader_id,
+                        node_id: self.id,
+                        seq,
                     },
                 )?;
                 self.apply_self()
             }
             Command::VoteRequest {
+                term,
                 candidate_id,
                 last_index,
                 last_term,
-                ..
+                transfer,
             } => {
-                if self.can_vote(last_term, last_index) {
+                // The leader serves lease reads on the strength of our acknowledgements, so we
+                // can't help elect anyone else while we're still hearing from it.
+                let leader_alive = self.role.leader_id.is_some() && !self.needs_election();
+                if self.config.read_mode == ReadMode::Lease && leader_alive && !transfer {
                     self.send(
                         Address::Peer(candidate_id),
                         VoteResponse {
                             term: self.state.current_term,
                             from: self.id,
-                            granted: true,
+                            granted: false,
                         },
                     )?;
+                    return self.apply_self();
+                }
+
+                // A vote from an earlier term doesn't stop us voting in a new one.
+                if term > self.state.current_term {
+                    self.term(term);
+                    self.save_state()?;
+                }
+
+                if self.can_vote(term, candidate_id, last_term, last_index)? {
+                    // The vote must be durable before the candidate learns about it.
                     self.state.voted_for = Some(candidate_id);
+                    self.save_state()?;
+                    self.set_election_timeout();
+                    self.send(
+                        Address::Peer(candidate_id),
+                        VoteResponse {
+                            term: self.state.current_term,
+                            from: self.id,
+                            granted: true,
+                        },
+                    )?;
                 } else {
                     self.send(
                         Address::Peer(candidate_id),
@@ -141,15 +215,142 @@ impl Apply for Raft<Follower> {
                 }
                 self.apply_self()
             }
-            Command::Timeout => {
-                if self.state.voted_for.is_none() {
-                    self.set_election_timeout(); // start a new election
-                    let raft: Raft<Candidate> = Raft::from(self);
-                    return raft.seek_election();
+            Command::InstallSnapshot {
+                term,
+                leader_id,
+                last_index,
+                last_term,
+                config,
+                offset,
+                data,
+                done,
+            } => {
+                if term < self.state.current_term {
+                    self.send(
+                        Address::Peer(leader_id),
+                        Command::InstallSnapshotResponse {
+                            node_id: self.id,
+                            term: self.state.current_term,
+                            index: last_index,
+                            offset: 0,
+                            done: false,
+                            success: false,
+                        },
+                    )?;
+                    return self.apply_self();
                 }
 
+                self.follow(term, leader_id)?;
+
+                let mut snapshot = match self.role.snapshot.take() {
+                    Some(snapshot) if snapshot.last_index == last_index && snapshot.last_term == last_term => snapshot,
+                    _ => Snapshot {
+                        last_index,
+                        last_term,
+                        config,
+                        data: Vec::new(),
+                    },
+                };
+
+                if snapshot.data.len() as u64 == offset {
+                    snapshot.data.extend(data);
+                    if done {
+                        self.install_snapshot(leader_id, snapshot)?;
+                        return self.apply_self();
+                    }
+                } else {
+                    // a chunk went missing or was resent, so have the leader carry on from what we have
+                    warn!(self.role.logger, "Discarding out of order snapshot chunk"; "offset" => offset);
+                }
+
+                let received = snapshot.data.len() as u64;
+                self.role.snapshot = Some(snapshot);
+                self.send(
+                    Address::Peer(leader_id),
+                    Command::InstallSnapshotResponse {
+                        node_id: self.id,
+                        term: self.state.current_term,
+                        index: last_index,
+                        offset: received,
+                        done: false,
+                        success: true,
+                    },
+                )?;
+                self.apply_self()
+            }
+            Command::Compact { index, term } => {
+                self.compact(index, term)?;
+                match (self.role.installing, self.role.leader_id) {
+                    (Some(installing), Some(leader_id)) if index >= installing => {
+                        // the log now starts from the snapshot, which holds only committed entries
+                        self.role.installing = None;
+                        self.state.last_applied = std::cmp::max(self.state.last_applied, index);
+                        self.state.commit_index = std::cmp::max(self.state.commit_index, index);
+                        self.role.matched = std::cmp::max(self.role.matched, index);
+                        self.save_state()?;
+                        self.send(
+                            Address::Peer(leader_id),
+                            Command::InstallSnapshotResponse {
+                                node_id: self.id,
+                                term: self.state.current_term,
+                                index,
+                                offset: 0,
+                                done: true,
+                                success: true,
+                            },
+                        )?;
+                    }
+                    _ => {}
+                }
+                self.apply_self()
+            }
+            Command::ClientRequest { id, req } => {
+                // Pass the request on to the leader, whose response makes its way back to the
+                // client through the peer it was forwarded from.
+                match self.role.leader_id {
+                    Some(leader_id) => self.send(Address::Peer(leader_id), Command::ClientRequest { id, req })?,
+                    None => self.send(
+                        Address::Client,
+                        Command::ClientResponse {
+                            id,
+                            res: Err(JosefineError::NotLeader { leader_id: None }),
+                        },
+                    )?,
+                }
+                self.apply_self()
+            }
+            Command::PreVoteRequest { .. } => {
+                // Refuse while we're still hearing from a leader, so a node that rejoins after a
+                // partition can't depose it.
+                let allow = self.role.leader_id.is_none() || self.needs_election();
+                self.respond_pre_vote(&cmd, allow)?;
                 self.apply_self()
             }
+            Command::TimeoutNow { term, leader_id } => {
+                if term < self.state.current_term || !self.membership.config.is_voter(self.id) {
+                    return self.apply_self();
+                }
+
+                info!(self.role.logger, "Leadership handed over, starting election"; "leader_id" => leader_id);
+                self.set_election_timeout();
+                let raft: Raft<Candidate> = Raft::from(self);
+                raft.seek_election(true)
+            }
+            Command::Timeout => {
+                // Learners and nodes that have been removed from the cluster must not disrupt it
+                // with elections.
+                if !self.membership.config.is_voter(self.id) {
+                    self.set_election_timeout();
+                    return self.apply_self();
+                }
+
+                self.set_election_timeout(); // start a new election
+                let raft: Raft<Candidate> = Raft::from(self);
+                if raft.config.pre_vote {
+                    return raft.seek_pre_vote();
+                }
+                raft.seek_election(false)
+            }
             _ => self.apply_self(),
         }
     }
@@ -162,7 +363,7 @@ impl Raft<Follower> {
     ///
     /// * `config` - The configuration to use for creating the state machine.
     /// * `io` - The implementation used to persist the non-volatile state of the state machine and
-    /// entries for the commit log.
+    ///   entries for the commit log.
     /// * `logger` - An optional logger implementation.
     /// * `nodes` - An optional map of nodes present in the cluster.
     ///
@@ -174,46 +375,195 @@ impl Raft<Follower> {
     ) -> Result<Raft<Follower>> {
         config.validate()?;
         let logger = logger.new(o!("id" => config.id));
+        let hard_state = HardStateFile::open(&config.data_directory)?;
+        let mut store = FileStore::open(
+            config.data_directory.join("log"),
+            config.segment_size,
+            config.sync_policy,
+        )?;
+
+        let saved = hard_state.get();
+        let mut membership = saved.membership.unwrap_or_else(|| {
+            // Until a config entry is seen the cluster is the set of nodes we were started with.
+            let mut voters = config.nodes.clone();
+            voters.push(Node {
+                id: config.id,
+                addr: std::net::SocketAddr::new(config.ip, config.port),
+            });
+            Membership::new(ClusterConfig::new(voters))
+        });
+        let snapshot = SnapshotFile::open(&config.data_directory)?.load()?;
+        let snapshot_index = snapshot.as_ref().map_or(0, |snapshot| snapshot.last_index);
+        let commit_index = std::cmp::max(saved.commit_index, snapshot_index);
+        if commit_index > 0 {
+            store.commit(commit_index)?;
+        }
+        let mut log = Log::with_store(store);
+        if let Some(snapshot) = snapshot {
+            log.compact(snapshot.last_index, snapshot.last_term)?;
+            if let Some(config) = snapshot.config {
+                membership.set(config, snapshot.last_index);
+            }
+        }
+
+        // The membership is saved after the log is written, so a crash in between can leave it
+        // pointing at a config entry that was truncated away, or behind one that was appended.
+        membership.truncate(log.next_index() - 1);
+        for entry in log.get_range(log.snapshot_index(), log.next_index())? {
+            if let EntryType::Config { config } = entry.entry_type {
+                membership.set(config, entry.index);
+            }
+        }
+
+        let state = State {
+            current_term: saved.current_term,
+            voted_for: saved.voted_for,
+            commit_index,
+            last_applied: snapshot_index,
+            ..State::default()
+        };
 
         let mut raft = Raft {
             id: config.id,
             config,
-            state: State::default(),
+            state,
             role: Follower {
                 leader_id: None,
+                matched: 0,
                 logger: logger.new(o!("role" => "follower")),
+                snapshot: None,
+                installing: None,
             },
             logger,
-            log: Log::new(),
+            log,
+            hard_state,
+            membership,
             rpc_tx,
             fsm_tx,
+            metrics: Metrics::new(),
         };
 
-        raft.init();
+        raft.init()?;
         Ok(raft)
     }
 
-    fn init(&mut self) {
+    fn init(&mut self) -> Result<()> {
         self.set_election_timeout();
+
+        // Entries committed before a restart that aren't covered by the snapshot need to be
+        // re-applied to the state machine.
+        for entry in self.log.get_range(self.log.snapshot_index(), self.state.commit_index)? {
+            self.state.last_applied = entry.index;
+            self.fsm_tx.send(fsm::Instruction::Drive { entry }).map_err(RaftError::from)?;
+        }
+        Ok(())
+    }
+
+    /// Replaces our log and state machine with a complete snapshot from the leader. The state
+    /// machine saves the snapshot before we discard our log, and we let the leader know once it
+    /// has.
+    fn install_snapshot(&mut self, leader_id: NodeId, snapshot: Snapshot) -> Result<()> {
+        let (last_index, last_term) = (snapshot.last_index, snapshot.last_term);
+        if last_index > self.state.commit_index {
+            info!(self.role.logger, "Installing snapshot"; "index" => last_index, "term" => last_term);
+
+            // Entries following the snapshot are only kept if our log agrees with it.
+            if !self.log.check_term(last_index, last_term) {
+                self.log.truncate(last_index)?;
+                self.membership.truncate(last_index);
+                self.state.last_applied = last_index;
+            }
+            if let Some(config) = snapshot.config.clone() {
+                self.membership.set(config, last_index);
+            }
+            self.role.installing = Some(last_index);
+            self.save_state()?;
+
+            self.fsm_tx.send(fsm::Instruction::Restore { snapshot }).map_err(RaftError::from)?;
+            return Ok(());
+        }
+
+        // we already have everything the snapshot covers
+        self.send(
+            Address::Peer(leader_id),
+            Command::InstallSnapshotResponse {
+                node_id: self.id,
+                term: self.state.current_term,
+                index: last_index,
+                offset: 0,
+                done: true,
+                success: true,
+            },
+        )
+    }
+
+    /// Commits the entries the leader has committed, as far as our log is known to match its,
+    /// and has the state machine apply them.
+    fn commit(&mut self, leader_commit: LogIndex) -> Result<()> {
+        let index = std::cmp::min(leader_commit, self.role.matched);
+        if index <= self.state.commit_index {
+            return Ok(());
+        }
+
+        self.log.commit(index)?;
+        let prev = self.state.commit_index;
+        self.state.commit_index = index;
+        self.save_state()?;
+        for entry in self.log.get_range(prev, index)? {
+            self.fsm_tx.send(fsm::Instruction::Drive { entry }).map_err(RaftError::from)?;
+        }
+        Ok(())
+    }
+
+    /// Describes where our log diverges from the leader's, given that we don't have the entry the
+    /// leader expected at `prev_log_index`.
+    fn conflict(&self, prev_log_index: LogIndex) -> Result<Conflict> {
+        let term = match self.log.term(prev_log_index)? {
+            Some(term) => term,
+            None if prev_log_index < self.log.snapshot_index() => {
+                // everything up to the snapshot is committed, so it must match the leader
+                return Ok(Conflict { term: 0, index: self.log.snapshot_index() + 1 });
+            }
+            None => return Ok(Conflict { term: 0, index: self.log.next_index() }),
+        };
+
+        // find the first entry we have for the conflicting term, so the leader can skip it
+        let mut index = prev_log_index;
+        while index - 1 > self.log.snapshot_index() && self.log.term(index - 1)? == Some(term) {
+            index -= 1;
+        }
+        Ok(Conflict { term, index })
     }
 
-    fn can_vote(&self, last_term: Term, last_index: LogIndex) -> bool {
-        !(self.state.voted_for.is_some()
-            || self.state.current_term > last_term
-            || self.state.commit_index > last_index)
+    /// Whether we can vote for the candidate in `term`: we haven't voted for anyone else in it, and
+    /// the candidate's log holds everything ours does, so it can't lose a committed entry.
+    fn can_vote(&self, term: Term, candidate_id: NodeId, last_term: Term, last_index: LogIndex) -> Result<bool> {
+        Ok(term == self.state.current_term
+            && self.state.voted_for.is_none_or(|voted_for| voted_for == candidate_id)
+            && self.log_is_current(last_term, last_index)?)
+    }
+
+    /// Recognises `leader_id` as the leader of `term`, which is at least our current term, moving
+    /// to it if it's newer.
+    fn follow(&mut self, term: Term, leader_id: NodeId) -> Result<()> {
+        if term > self.state.current_term {
+            self.term(term);
+            self.save_state()?;
+        }
+        self.set_election_timeout();
+        self.role.leader_id = Some(leader_id);
+        Ok(())
     }
 
     fn get_randomized_timeout(&self) -> Duration {
-        let _prev_timeout = self.state.election_timeout;
-        let timeout = rand::thread_rng().gen_range(
-            self.state.min_election_timeout..self.state.max_election_timeout
-        );
-        Duration::from_millis(timeout as u64)
+        let min = self.config.min_election_timeout().as_millis() as u64;
+        let max = self.config.election_timeout.as_millis() as u64;
+        Duration::from_millis(clock::gen_range(min..max))
     }
 
     fn set_election_timeout(&mut self) {
         self.state.election_timeout = Some(self.get_randomized_timeout());
-        self.state.election_time = Some(Instant::now());
+        self.state.election_time = Some(clock::now());
     }
 
     fn apply_self(self) -> Result<RaftHandle> {
@@ -223,9 +573,7 @@ impl Raft<Follower> {
 
 impl From<Raft<Follower>> for Raft<Candidate> {
     fn from(val: Raft<Follower>) -> Raft<Candidate> {
-        let mut node_ids: Vec<NodeId> = val.config.nodes.iter().map(|n| n.id).collect();
-        node_ids.push(val.id);
-        let election = Election::new(node_ids);
+        let election = Election::new(val.membership.config.clone());
 
         Raft {
             id: val.id,
@@ -233,12 +581,16 @@ impl From<Raft<Follower>> for Raft<Candidate> {
             role: Candidate {
                 election,
                 logger: val.logger.new(o!("role" => "candidate")),
+                pre_vote: false,
             },
             logger: val.logger,
             config: val.config,
             log: val.log,
+            hard_state: val.hard_state,
+            membership: val.membership,
             rpc_tx: val.rpc_tx,
             fsm_tx: val.fsm_tx,
+            metrics: val.metrics,
         }
     }
 }
@@ -246,6 +598,17 @@ impl From<Raft<Follower>> for Raft<Candidate> {
 #[cfg(test)]
 mod tests {
 
+    use futures::FutureExt;
+    use tokio::sync::mpsc;
+
+    use josefine_core::error::JosefineError;
+
+    use crate::fsm::Instruction;
+    use crate::logger::get_root_logger;
+    use crate::membership::ClusterConfig;
+    use crate::raft::{Conflict, Entry, EntryType, Node, Raft};
+    use crate::rpc::{Address, Request};
+    use crate::store::SnapshotFile;
     use crate::test::new_follower;
 
     use super::Apply;
@@ -263,6 +626,226 @@ mod tests {
         }
     }
 
+    #[test]
+    fn vote_survives_restart() {
+        let ((_rpc_rx, _fsm_rx), follower) = new_follower();
+        let config = follower.config.clone();
+        let node = follower
+            .apply(Command::VoteRequest {
+                term: 1,
+                candidate_id: 2,
+                last_term: 1,
+                last_index: 0,
+                transfer: false,
+            })
+            .unwrap();
+        assert!(node.is_follower());
+
+        let (rpc_tx, _rpc_rx) = mpsc::unbounded_channel();
+        let (fsm_tx, _fsm_rx) = mpsc::unbounded_channel();
+        let restarted = Raft::new(config, get_root_logger().new(o!()), rpc_tx, fsm_tx).unwrap();
+        assert_eq!(restarted.state.voted_for, Some(2));
+    }
+
+    #[test]
+    fn config_entries_survive_restart_before_membership_is_saved() {
+        let ((_rpc_rx, _fsm_rx), mut follower) = new_follower();
+        let mut voters = follower.membership.config.voters.clone();
+        voters.push(Node { id: 2, addr: "127.0.0.1:6670".parse().unwrap() });
+        // stop between appending the entry and saving the membership it switches to
+        follower
+            .log
+            .append(Entry { entry_type: EntryType::Config { config: ClusterConfig::new(voters) }, term: 1, index: 1 })
+            .unwrap();
+
+        let (rpc_tx, _rpc_rx) = mpsc::unbounded_channel();
+        let (fsm_tx, _fsm_rx) = mpsc::unbounded_channel();
+        let restarted = Raft::new(follower.config.clone(), get_root_logger().new(o!()), rpc_tx, fsm_tx).unwrap();
+        assert_eq!(restarted.membership.index, 1);
+        assert!(restarted.membership.config.is_voter(2));
+    }
+
+    #[test]
+    fn install_snapshot_in_chunks() {
+        let ((mut rpc_rx, mut fsm_rx), follower) = new_follower();
+        let (id, data_directory) = (follower.id, follower.config.data_directory.clone());
+        let chunk = |offset, data, done| Command::InstallSnapshot {
+            term: 1,
+            leader_id: 2,
+            last_index: 10,
+            last_term: 1,
+            config: None,
+            offset,
+            data,
+            done,
+        };
+
+        let ack = |offset| Command::InstallSnapshotResponse {
+            node_id: id,
+            term: 1,
+            index: 10,
+            offset,
+            done: false,
+            success: true,
+        };
+
+        let node = follower.apply(chunk(0, vec![1, 2], false)).unwrap();
+        assert_eq!(rpc_rx.blocking_recv().unwrap().command, ack(2));
+        // a chunk past what we have is dropped, and the leader told where to carry on from
+        let node = node.apply(chunk(3, vec![4], false)).unwrap();
+        assert_eq!(rpc_rx.blocking_recv().unwrap().command, ack(2));
+        let node = node.apply(chunk(2, vec![3], true)).unwrap();
+        let snapshot = match fsm_rx.blocking_recv().unwrap() {
+            Instruction::Restore { snapshot } => snapshot,
+            _ => panic!(),
+        };
+        assert_eq!(snapshot.data, vec![1, 2, 3]);
+
+        // the log is kept until the state machine has saved the snapshot
+        let node = match node {
+            RaftHandle::Follower(follower) => {
+                assert_eq!(follower.log.snapshot_index(), 0);
+                assert_eq!(follower.state.commit_index, 0);
+                RaftHandle::Follower(follower)
+            }
+            _ => panic!(),
+        };
+        assert!(rpc_rx.recv().now_or_never().is_none());
+
+        SnapshotFile::open(&data_directory).unwrap().save(&snapshot).unwrap();
+        match node.apply(Command::Compact { index: 10, term: 1 }).unwrap() {
+            RaftHandle::Follower(follower) => {
+                assert_eq!(follower.log.snapshot_index(), 10);
+                assert_eq!(follower.log.next_index(), 11);
+                assert_eq!(follower.state.commit_index, 10);
+            }
+            _ => panic!(),
+        }
+        assert_eq!(
+            rpc_rx.blocking_recv().unwrap().command,
+            Command::InstallSnapshotResponse {
+                node_id: id,
+                term: 1,
+                index: 10,
+                offset: 0,
+                done: true,
+                success: true,
+            }
+        );
+    }
+
+    #[test]
+    fn compacts_only_what_a_saved_snapshot_covers() {
+        let ((_rpc_rx, _fsm_rx), follower) = new_follower();
+        match follower.apply(Command::Compact { index: 5, term: 1 }).unwrap() {
+            RaftHandle::Follower(follower) => assert_eq!(follower.log.snapshot_index(), 0),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn truncates_conflicting_entries() {
+        let ((mut rpc_rx, _fsm_rx), follower) = new_follower();
+        let entry = |term, index| Entry { entry_type: EntryType::Entry { data: vec![] }, term, index };
+        let append = |prev_log_index, prev_log_term, entries| Command::AppendEntries {
+            term: 1,
+            leader_id: 2,
+            entries,
+            prev_log_index,
+            prev_log_term,
+            leader_commit: 0,
+        };
+
+        let node = follower
+            .apply(append(0, 0, vec![entry(1, 1), entry(1, 2), entry(1, 3)]))
+            .unwrap()
+            // the leader's entry at 3 is from term 2, so we disagree from the start of term 1
+            .apply(append(3, 2, vec![]))
+            .unwrap();
+        let rejection = std::iter::from_fn(|| rpc_rx.blocking_recv())
+            .find(|msg| matches!(msg.command, Command::AppendResponse { success: false, .. }))
+            .unwrap();
+        match rejection.command {
+            Command::AppendResponse { conflict, .. } => {
+                assert_eq!(conflict, Some(Conflict { term: 1, index: 1 }))
+            }
+            _ => panic!(),
+        }
+
+        match node.apply(append(1, 1, vec![entry(2, 2)])).unwrap() {
+            RaftHandle::Follower(follower) => {
+                assert_eq!(follower.log.next_index(), 3);
+                assert_eq!(follower.log.term(2).unwrap(), Some(2));
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn follows_newer_term_and_rejects_older() {
+        let ((mut rpc_rx, _fsm_rx), follower) = new_follower();
+        let append = |term, leader_id| Command::AppendEntries {
+            term,
+            leader_id,
+            entries: vec![],
+            prev_log_index: 0,
+            prev_log_term: 0,
+            leader_commit: 0,
+        };
+
+        // having voted in an earlier term doesn't stop us following the leader of a newer one
+        let node = follower
+            .apply(Command::VoteRequest { term: 1, candidate_id: 3, last_term: 0, last_index: 0, transfer: false })
+            .unwrap()
+            .apply(append(2, 2))
+            .unwrap()
+            .apply(append(1, 3))
+            .unwrap();
+        match node {
+            RaftHandle::Follower(follower) => {
+                assert_eq!(follower.state.current_term, 2);
+                assert_eq!(follower.state.voted_for, None);
+                assert_eq!(follower.role.leader_id, Some(2));
+            }
+            _ => panic!(),
+        }
+
+        let msg = std::iter::from_fn(|| rpc_rx.blocking_recv())
+            .find(|msg| msg.to == Address::Peer(3) && matches!(msg.command, Command::AppendResponse { .. }))
+            .unwrap();
+        assert!(matches!(msg.command, Command::AppendResponse { term: 2, success: false, .. }));
+    }
+
+    #[test]
+    fn commits_what_the_leader_has_committed() {
+        let ((_rpc_rx, mut fsm_rx), follower) = new_follower();
+        let entry = |index| Entry { entry_type: EntryType::Entry { data: vec![] }, term: 1, index };
+        let node = follower
+            .apply(Command::AppendEntries {
+                term: 1,
+                leader_id: 2,
+                entries: vec![entry(1), entry(2), entry(3)],
+                prev_log_index: 0,
+                prev_log_term: 0,
+                leader_commit: 1,
+            })
+            .unwrap()
+            // we can't commit past the entries we know we share with the leader
+            .apply(Command::Heartbeat { term: 1, leader_id: 2, seq: 0, leader_commit: 5 })
+            .unwrap();
+        match node {
+            RaftHandle::Follower(follower) => assert_eq!(follower.state.commit_index, 3),
+            _ => panic!(),
+        }
+
+        for index in 1..=3 {
+            match fsm_rx.blocking_recv().unwrap() {
+                Instruction::Drive { entry } => assert_eq!(entry.index, index),
+                _ => panic!(),
+            }
+        }
+    }
+
     #[test]
     fn follower_noop() {
         let (_, follower) = new_follower();
@@ -273,4 +856,84 @@ mod tests {
             RaftHandle::Leader(_) => panic!(),
         }
     }
+
+    #[test]
+    fn timeout_now_starts_election() {
+        let ((_rpc_rx, _fsm_rx), follower) = new_follower();
+        let node = follower
+            .apply(Command::TimeoutNow { term: 0, leader_id: 2 })
+            .unwrap();
+        assert!(node.is_leader());
+    }
+
+    #[test]
+    fn refuses_pre_vote_with_live_leader() {
+        let ((mut rpc_rx, _fsm_rx), follower) = new_follower();
+        let pre_vote = Command::PreVoteRequest { term: 2, candidate_id: 3, last_term: 0, last_index: 0 };
+        let node = follower
+            .apply(Command::Heartbeat { term: 1, leader_id: 2, seq: 0, leader_commit: 0 })
+            .unwrap()
+            .apply(pre_vote)
+            .unwrap();
+        assert!(node.is_follower());
+
+        let granted = loop {
+            if let Command::PreVoteResponse { granted, .. } = rpc_rx.blocking_recv().unwrap().command {
+                break granted;
+            }
+        };
+        assert!(!granted);
+    }
+
+    #[test]
+    fn ignores_heartbeat_from_earlier_term() {
+        let ((mut rpc_rx, _fsm_rx), follower) = new_follower();
+        let node = follower
+            .apply(Command::Heartbeat { term: 2, leader_id: 2, seq: 0, leader_commit: 0 })
+            .unwrap()
+            .apply(Command::Heartbeat { term: 1, leader_id: 3, seq: 0, leader_commit: 0 })
+            .unwrap();
+        match node {
+            RaftHandle::Follower(follower) => {
+                assert_eq!(follower.state.current_term, 2);
+                assert_eq!(follower.role.leader_id, Some(2));
+                // following a leader isn't the same as voting for it
+                assert_eq!(follower.state.voted_for, None);
+            }
+            _ => panic!(),
+        }
+
+        rpc_rx.blocking_recv().unwrap();
+        let msg = rpc_rx.blocking_recv().unwrap();
+        assert_eq!(msg.to, Address::Peer(3));
+        assert!(matches!(msg.command, Command::HeartbeatResponse { term: 2, .. }));
+    }
+
+    #[test]
+    fn forwards_client_requests_to_leader() {
+        let ((mut rpc_rx, _fsm_rx), follower) = new_follower();
+        let propose = |id| Command::ClientRequest { id, req: Request::Propose(vec![1]) };
+        let node = follower.apply(propose(vec![1])).unwrap();
+        let msg = rpc_rx.blocking_recv().unwrap();
+        assert_eq!(msg.to, Address::Client);
+        assert!(matches!(
+            msg.command,
+            Command::ClientResponse { res: Err(JosefineError::NotLeader { leader_id: None }), .. }
+        ));
+
+        let node = node
+            .apply(Command::Heartbeat { term: 1, leader_id: 2, seq: 0, leader_commit: 0 })
+            .unwrap()
+            .apply(propose(vec![2]))
+            .unwrap();
+        assert!(node.is_follower());
+        let msg = loop {
+            let msg = rpc_rx.blocking_recv().unwrap();
+            if let Command::ClientRequest { .. } = msg.command {
+                break msg;
+            }
+        };
+        assert_eq!(msg.to, Address::Peer(2));
+        assert_eq!(msg.command, propose(vec![2]));
+    }
 }
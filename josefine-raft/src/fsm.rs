@@ -1,25 +1,43 @@
+use std::collections::HashMap;
 use std::fmt;
+use std::time::{Duration, Instant};
 
 use slog::Logger;
 use tokio::sync::mpsc;
 
-use josefine_core::error::Result;
+use josefine_core::error::{JosefineError, Result};
 use crate::{
-    raft::{Entry, EntryType, LogIndex},
+    clock,
+    config::RaftConfig,
+    membership::ClusterConfig,
+    raft::{Entry, EntryType, LogIndex, Term},
     rpc,
+    store::{Snapshot, SnapshotFile},
 };
 use crate::rpc::{Message, Address, Response};
 use crate::raft::Command;
+use crate::metrics::Metrics;
 
 pub trait Fsm: Send + Sync + fmt::Debug {
     fn transition(&mut self, data: Vec<u8>) -> Result<Vec<u8>>;
     fn query(&mut self, data: Vec<u8>) -> Result<Vec<u8>>;
+    /// Serializes the entire state of the state machine, so the log up to the current point can
+    /// be discarded.
+    fn snapshot(&self) -> Result<Vec<u8>>;
+    /// Replaces the state of the state machine with one previously produced by `snapshot`.
+    fn restore(&mut self, snapshot: Vec<u8>) -> Result<()>;
 }
 
 #[derive(Debug)]
 pub enum Instruction {
     Drive { entry: Entry },
-    Query(Vec<u8>),
+    /// Run a query for a client once every entry up to `index` has been applied.
+    Query { id: Vec<u8>, data: Vec<u8>, index: LogIndex },
+    /// Answer the client that proposed the entry at `index` once it has been applied, unless a
+    /// new leader replaced it with an entry from another term.
+    Notify { id: Vec<u8>, index: LogIndex, term: Term },
+    /// Save a snapshot received from the leader and replace the state machine with it.
+    Restore { snapshot: Snapshot },
 }
 
 pub struct Driver<T: Fsm> {
@@ -27,22 +45,63 @@ pub struct Driver<T: Fsm> {
     fsm_rx: mpsc::UnboundedReceiver<Instruction>,
     rpc_tx: mpsc::UnboundedSender<rpc::Message>,
     applied_idx: LogIndex,
+    applied_term: Term,
+    /// The latest cluster configuration applied, which is included in snapshots.
+    applied_config: Option<ClusterConfig>,
     fsm: T,
+    /// Queries waiting for the entries they must observe to be applied.
+    queries: Vec<(LogIndex, Vec<u8>, Vec<u8>)>,
+    /// The clients waiting for the entry they proposed to be applied, by index.
+    proposals: HashMap<LogIndex, (Term, Vec<u8>)>,
+    snapshots: SnapshotFile,
+    /// The last index included in the most recent snapshot.
+    snapshot_idx: LogIndex,
+    /// When the most recent snapshot was taken.
+    snapshot_time: Instant,
+    /// The minimum time between snapshots.
+    snapshot_interval: Duration,
+    /// The number of entries applied since the last snapshot that will trigger a new one.
+    snapshot_threshold: u64,
+    metrics: Metrics,
 }
 impl<T: Fsm> Driver<T> {
+    /// Creates a new driver, restoring the state machine from the latest snapshot if there is one.
     pub fn new(
         logger: Logger,
+        config: &RaftConfig,
         fsm_rx: mpsc::UnboundedReceiver<Instruction>,
         rpc_tx: mpsc::UnboundedSender<rpc::Message>,
-        fsm: T,
-    ) -> Self {
-        Self {
+        mut fsm: T,
+        metrics: Metrics,
+    ) -> Result<Self> {
+        let snapshots = SnapshotFile::open(&config.data_directory)?;
+        let (applied_idx, applied_term, applied_config) = match snapshots.load()? {
+            Some(snapshot) => {
+                info!(logger, "restoring snapshot"; "index" => snapshot.last_index, "term" => snapshot.last_term);
+                fsm.restore(snapshot.data)?;
+                (snapshot.last_index, snapshot.last_term, snapshot.config)
+            }
+            None => (0, 0, None),
+        };
+        metrics.applied_index.set(applied_idx as i64);
+
+        Ok(Self {
             logger,
             fsm_rx,
             rpc_tx,
             fsm,
-            applied_idx: 0,
-        }
+            queries: Vec::new(),
+            proposals: HashMap::new(),
+            applied_idx,
+            applied_term,
+            applied_config,
+            snapshots,
+            snapshot_idx: applied_idx,
+            snapshot_time: clock::now(),
+            snapshot_interval: config.snapshot_interval,
+            snapshot_threshold: config.snapshot_threshold,
+            metrics,
+        })
     }
 
     pub async fn run(mut self, mut shutdown: tokio::sync::broadcast::Receiver<()>) -> Result<T> {
@@ -65,23 +124,124 @@ impl<T: Fsm> Driver<T> {
 
         match instruction {
             Instruction::Drive { entry } => {
-                if let EntryType::Entry { data } = entry.entry_type {
-                    self.fsm.transition(data)?;
+                // entries covered by the snapshot we restored from may be replayed on startup
+                if entry.index <= self.applied_idx {
+                    return Ok(());
                 }
-            },
-            Instruction::Query(data) => {
-                let res = self.fsm.query(data)?;
-                self.rpc_tx.send(Message {
-                    to: Address::Local,
-                    from: Address::Local,
-                    command: Command::ClientResponse {
-                        id: vec![],
-                        res: Ok(Response::State(res)),
+
+                self.applied_idx = entry.index;
+                self.applied_term = entry.term;
+                let res = match entry.entry_type {
+                    EntryType::Entry { data } => Some(self.fsm.transition(data)),
+                    EntryType::Config { config } => {
+                        self.applied_config = Some(config);
+                        None
+                    }
+                    _ => None,
+                };
+                match self.proposals.remove(&entry.index) {
+                    Some((term, id)) => self.respond(id, term, entry.term, res)?,
+                    None => {
+                        res.transpose()?;
                     }
-                })?;
+                }
+
+                if self.needs_snapshot() {
+                    self.snapshot()?;
+                }
+            },
+            Instruction::Restore { snapshot } => {
+                if snapshot.last_index <= self.applied_idx {
+                    return Ok(());
+                }
+
+                info!(self.logger, "restoring snapshot"; "index" => snapshot.last_index, "term" => snapshot.last_term);
+                self.snapshots.save(&snapshot)?;
+                self.fsm.restore(snapshot.data)?;
+                self.applied_idx = snapshot.last_index;
+                self.applied_term = snapshot.last_term;
+                self.applied_config = snapshot.config;
+                self.snapshot_idx = snapshot.last_index;
+                self.snapshot_time = clock::now();
+                self.compact()?;
+            },
+            Instruction::Query { id, data, index } => {
+                self.queries.push((index, id, data));
             },
+            Instruction::Notify { id, index, term } => {
+                self.proposals.insert(index, (term, id));
+            },
+        };
+
+        self.metrics.applied_index.set(self.applied_idx as i64);
+        self.run_queries()
+    }
+
+    /// Runs the queries whose index has been applied, sending each result back to its client.
+    fn run_queries(&mut self) -> Result<()> {
+        let applied_idx = self.applied_idx;
+        let (ready, waiting) = self.queries.drain(..).partition(|(index, ..)| *index <= applied_idx);
+        self.queries = waiting;
+
+        for (_, id, data) in ready {
+            let res = self.fsm.query(data).map(Response::State);
+            self.rpc_tx.send(Message::new(
+                Address::Local,
+                Address::Client,
+                Command::ClientResponse { id, res },
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Answers the client that proposed an entry in `proposed_term`, now that the entry at its
+    /// index has been applied.
+    fn respond(&self, id: Vec<u8>, proposed_term: Term, term: Term, res: Option<Result<Vec<u8>>>) -> Result<()> {
+        let res = match res {
+            Some(res) if proposed_term == term => res.map(Response::State),
+            _ => Err(JosefineError::Internal {
+                error_msg: "the proposal was discarded by a new leader".to_string(),
+            }),
+        };
+        self.rpc_tx.send(Message::new(
+            Address::Local,
+            Address::Client,
+            Command::ClientResponse { id, res },
+        ))?;
+        Ok(())
+    }
+
+    fn needs_snapshot(&self) -> bool {
+        self.applied_idx > self.snapshot_idx
+            && self.applied_idx - self.snapshot_idx >= self.snapshot_threshold
+            && clock::elapsed(self.snapshot_time) >= self.snapshot_interval
+    }
+
+    /// Snapshots the state machine and lets raft know it can discard the log up to this point.
+    fn snapshot(&mut self) -> Result<()> {
+        let snapshot = Snapshot {
+            last_index: self.applied_idx,
+            last_term: self.applied_term,
+            config: self.applied_config.clone(),
+            data: self.fsm.snapshot()?,
         };
+        self.snapshots.save(&snapshot)?;
+        self.snapshot_idx = snapshot.last_index;
+        self.snapshot_time = clock::now();
+        info!(self.logger, "took snapshot"; "index" => snapshot.last_index, "term" => snapshot.last_term);
+        self.compact()
+    }
 
+    /// Lets raft know it can discard the log up to the snapshot we just saved.
+    fn compact(&self) -> Result<()> {
+        self.rpc_tx.send(Message::new(
+            Address::Local,
+            Address::Local,
+            Command::Compact {
+                index: self.snapshot_idx,
+                term: self.applied_term,
+            },
+        ))?;
         Ok(())
     }
 }
@@ -91,6 +251,7 @@ mod test {
     use tokio::sync::mpsc::unbounded_channel;
 
     use crate::error::RaftError;
+    use crate::test::new_config;
 
     use super::*;
     #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -130,6 +291,19 @@ mod test {
             };
             Ok(String::into_bytes(state.to_string()))
         }
+
+        fn snapshot(&self) -> Result<Vec<u8>> {
+            let state = match self.state {
+                TestState::A => "A",
+                TestState::B => "B",
+            };
+            Ok(String::into_bytes(state.to_string()))
+        }
+
+        fn restore(&mut self, snapshot: Vec<u8>) -> Result<()> {
+            self.transition(snapshot)?;
+            Ok(())
+        }
     }
 
     #[tokio::test]
@@ -137,8 +311,8 @@ mod test {
         let fsm = TestFsm::new();
 
         let (tx, rx) = unbounded_channel();
-        let (rpc_tx, rpc_rx) = unbounded_channel();
-        let driver = Driver::new(crate::logger::get_root_logger().new(o!()), rx, rpc_tx, fsm);
+        let (rpc_tx, _rpc_rx) = unbounded_channel();
+        let driver = Driver::new(crate::logger::get_root_logger().new(o!()), &new_config(), rx, rpc_tx, fsm, Metrics::new())?;
 
         let (shutdown_tx, shutdown_rx) = tokio::sync::broadcast::channel(1);
         tx.send(Instruction::Drive {
@@ -147,9 +321,9 @@ mod test {
                     data: "B".as_bytes().to_owned(),
                 },
                 term: 0,
-                index: 0,
+                index: 1,
             },
-        }).map_err(|err| RaftError::from(err))?;
+        }).map_err(RaftError::from)?;
 
         let (join, _) = tokio::join!(
             tokio::spawn(driver.run(shutdown_rx)),
@@ -162,16 +336,99 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn snapshot() -> Result<()> {
+        let config = RaftConfig {
+            snapshot_interval: Duration::from_secs(0),
+            snapshot_threshold: 2,
+            ..new_config()
+        };
+        let (_tx, rx) = unbounded_channel();
+        let (rpc_tx, mut rpc_rx) = unbounded_channel();
+        let mut driver = Driver::new(crate::logger::get_root_logger().new(o!()), &config, rx, rpc_tx, TestFsm::new(), Metrics::new())?;
+
+        for (index, data) in [(1, "B"), (2, "A"), (3, "B")] {
+            driver.exec(Instruction::Drive {
+                entry: Entry {
+                    entry_type: EntryType::Entry { data: data.as_bytes().to_owned() },
+                    term: 1,
+                    index,
+                },
+            }).await?;
+        }
+
+        // the snapshot was taken after the second entry
+        let msg = rpc_rx.recv().await.unwrap();
+        assert_eq!(msg.command, Command::Compact { index: 2, term: 1 });
+
+        let (_tx, rx) = unbounded_channel();
+        let (rpc_tx, _rpc_rx) = unbounded_channel();
+        let restored = Driver::new(crate::logger::get_root_logger().new(o!()), &config, rx, rpc_tx, TestFsm::new(), Metrics::new())?;
+        assert_eq!(restored.fsm.state, TestState::A);
+        assert_eq!(restored.applied_idx, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn saves_restored_snapshot() -> Result<()> {
+        let config = new_config();
+        let (_tx, rx) = unbounded_channel();
+        let (rpc_tx, mut rpc_rx) = unbounded_channel();
+        let mut driver = Driver::new(crate::logger::get_root_logger().new(o!()), &config, rx, rpc_tx, TestFsm::new(), Metrics::new())?;
+
+        let snapshot = Snapshot { last_index: 5, last_term: 2, config: None, data: "B".as_bytes().to_owned() };
+        driver.exec(Instruction::Restore { snapshot: snapshot.clone() }).await?;
+        assert_eq!(driver.fsm.state, TestState::B);
+        assert_eq!(SnapshotFile::open(&config.data_directory)?.load()?, Some(snapshot));
+
+        // raft only discards its log once the snapshot is saved
+        let msg = rpc_rx.recv().await.unwrap();
+        assert_eq!(msg.command, Command::Compact { index: 5, term: 2 });
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn answers_proposals() -> Result<()> {
+        let (_tx, rx) = unbounded_channel();
+        let (rpc_tx, mut rpc_rx) = unbounded_channel();
+        let mut driver = Driver::new(crate::logger::get_root_logger().new(o!()), &new_config(), rx, rpc_tx, TestFsm::new(), Metrics::new())?;
+
+        driver.exec(Instruction::Notify { id: vec![1], index: 1, term: 1 }).await?;
+        driver.exec(Instruction::Notify { id: vec![2], index: 2, term: 1 }).await?;
+        for (index, term) in [(1, 1), (2, 2)] {
+            driver.exec(Instruction::Drive {
+                entry: Entry { entry_type: EntryType::Entry { data: "B".as_bytes().to_owned() }, term, index },
+            }).await?;
+        }
+
+        match rpc_rx.recv().await.unwrap().command {
+            Command::ClientResponse { id, res } => {
+                assert_eq!(id, vec![1]);
+                assert!(res.is_ok());
+            }
+            _ => panic!(),
+        }
+        // the second proposal was replaced by an entry from a later term
+        match rpc_rx.recv().await.unwrap().command {
+            Command::ClientResponse { id, res } => {
+                assert_eq!(id, vec![2]);
+                assert!(res.is_err());
+            }
+            _ => panic!(),
+        }
+        Ok(())
+    }
+
     #[tokio::test]
     async fn query() -> Result<()> {
         let fsm = TestFsm::new();
 
         let (tx, rx) = unbounded_channel();
         let (rpc_tx, mut rpc_rx) = unbounded_channel();
-        let driver = Driver::new(crate::logger::get_root_logger().new(o!()), rx, rpc_tx, fsm);
+        let driver = Driver::new(crate::logger::get_root_logger().new(o!()), &new_config(), rx, rpc_tx, fsm, Metrics::new())?;
 
         let (shutdown_tx, shutdown_rx) = tokio::sync::broadcast::channel(1);
-        tx.send(Instruction::Query(vec![])).map_err(|err| RaftError::from(err))?;
+        tx.send(Instruction::Query { id: vec![1], data: vec![], index: 0 }).map_err(RaftError::from)?;
 
         let (_, join, _) = tokio::join!(
             tokio::spawn(driver.run(shutdown_rx)),
@@ -0,0 +1,210 @@
+//! The exchange that opens every connection between peers, before any messages are sent. The
+//! connecting node introduces itself, and the accepting node checks that it is a member of the
+//! same cluster and picks the newest protocol version both of them speak, so nodes can be upgraded
+//! one at a time.
+use std::net::IpAddr;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use josefine_core::error::{JosefineError, Result};
+
+use crate::codec;
+use crate::config::{RaftConfig, MIN_PROTOCOL_VERSION};
+use crate::raft::{Node, NodeId};
+
+/// The largest handshake frame we'll read, so a bogus length can't make us allocate.
+const MAX_FRAME: u32 = 64 * 1024;
+
+/// What a node tells a peer about itself when it connects.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Hello {
+    pub cluster_id: String,
+    pub id: NodeId,
+    /// The oldest protocol version the node speaks.
+    pub min_version: u32,
+    /// The newest protocol version the node speaks.
+    pub max_version: u32,
+}
+
+/// The accepting node's answer to a [`Hello`].
+#[derive(Debug, Serialize, Deserialize)]
+enum Reply {
+    Accept { id: NodeId, version: u32 },
+    Reject { reason: String },
+}
+
+/// How a peer that connected to this node shows which node it is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Identity {
+    /// The node the peer proved it is with its TLS certificate.
+    Certificate(NodeId),
+    /// The address the peer connected from, which must be its node's configured address since
+    /// nothing else backs up its claim.
+    Address(IpAddr),
+}
+
+impl Hello {
+    pub fn new(config: &RaftConfig) -> Hello {
+        Hello {
+            cluster_id: config.cluster_id.clone(),
+            id: config.id,
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: config.protocol_version,
+        }
+    }
+
+    /// The newest protocol version spoken by both nodes, if there is one.
+    fn negotiate(&self, other: &Hello) -> Option<u32> {
+        let version = self.max_version.min(other.max_version);
+        if version >= self.min_version.max(other.min_version) {
+            Some(version)
+        } else {
+            None
+        }
+    }
+}
+
+/// Introduces this node to peer `to` after connecting to it, returning the protocol version to
+/// speak.
+pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, hello: &Hello, to: NodeId) -> Result<u32> {
+    codec::write_version(stream).await?;
+    write_frame(stream, hello).await?;
+    match read_frame(stream).await? {
+        Reply::Accept { id, .. } if id != to => Err(rejected(format!("expected node {} but reached node {}", to, id))),
+        Reply::Accept { version, .. } if version < hello.min_version || version > hello.max_version => {
+            Err(rejected(format!("peer chose unsupported protocol version {}", version)))
+        }
+        Reply::Accept { version, .. } => Ok(version),
+        Reply::Reject { reason } => Err(rejected(reason)),
+    }
+}
+
+/// Checks the introduction of a peer that has connected to this node, returning its id and the
+/// protocol version to speak.
+///
+/// * `nodes` - The nodes allowed to connect.
+/// * `identity` - What shows which node the peer is.
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    hello: &Hello,
+    nodes: &[Node],
+    identity: Identity,
+) -> Result<(NodeId, u32)> {
+    codec::read_version(stream).await?;
+    let peer: Hello = read_frame(stream).await?;
+    let node = nodes.iter().find(|node| node.id == peer.id);
+    let version = match (identity, node) {
+        _ if peer.cluster_id != hello.cluster_id => Err(format!(
+            "node {} belongs to cluster {:?}, not {:?}",
+            peer.id, peer.cluster_id, hello.cluster_id
+        )),
+        (Identity::Certificate(id), _) if id != peer.id => Err(format!("node {} claimed to be node {}", id, peer.id)),
+        (_, None) => Err(format!("node {} is not in the cluster", peer.id)),
+        (Identity::Address(ip), Some(node)) if node.addr.ip() != ip => Err(format!(
+            "node {} connected from {}, not its address {}",
+            peer.id,
+            ip,
+            node.addr.ip()
+        )),
+        _ => hello
+            .negotiate(&peer)
+            .ok_or_else(|| format!("no protocol version in common with node {}", peer.id)),
+    };
+
+    match version {
+        Ok(version) => {
+            write_frame(stream, &Reply::Accept { id: hello.id, version }).await?;
+            Ok((peer.id, version))
+        }
+        Err(reason) => {
+            write_frame(stream, &Reply::Reject { reason: reason.clone() }).await?;
+            Err(rejected(reason))
+        }
+    }
+}
+
+fn rejected(reason: String) -> JosefineError {
+    JosefineError::MessageError {
+        error_msg: format!("handshake rejected: {}", reason),
+    }
+}
+
+async fn write_frame<S: AsyncWrite + Unpin, T: Serialize>(stream: &mut S, value: &T) -> Result<()> {
+    let bytes = codec::encode(value)?;
+    stream.write_u32(bytes.len() as u32).await?;
+    stream.write_all(&bytes).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+async fn read_frame<S: AsyncRead + Unpin, T: DeserializeOwned>(stream: &mut S) -> Result<T> {
+    let len = stream.read_u32().await?;
+    if len > MAX_FRAME {
+        return Err(rejected(format!("frame of {} bytes is too large", len)));
+    }
+    let mut bytes = vec![0; len as usize];
+    stream.read_exact(&mut bytes).await?;
+    codec::decode(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{accept, connect, Hello, Identity};
+    use crate::raft::Node;
+
+    fn hello(cluster_id: &str, id: u32, min_version: u32, max_version: u32) -> Hello {
+        Hello {
+            cluster_id: cluster_id.to_string(),
+            id,
+            min_version,
+            max_version,
+        }
+    }
+
+    #[tokio::test]
+    async fn negotiates_and_rejects() {
+        let nodes: Vec<Node> = (1..=2)
+            .map(|id| Node {
+                id,
+                addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            })
+            .collect();
+        let local = hello("a", 1, 0, 3);
+        let loopback = Identity::Address(([127, 0, 0, 1]).into());
+
+        // the lower of the two newest versions is chosen
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let peer = hello("a", 2, 1, 2);
+        let (connected, accepted) = tokio::join!(
+            connect(&mut client, &peer, 1),
+            accept(&mut server, &local, &nodes, loopback)
+        );
+        assert_eq!(connected.unwrap(), 2);
+        assert_eq!(accepted.unwrap(), (2, 2));
+
+        let rejected = vec![
+            // another cluster
+            (hello("b", 2, 0, 3), loopback),
+            // not a member
+            (hello("a", 3, 0, 3), loopback),
+            // its certificate says it's someone else
+            (hello("a", 2, 0, 3), Identity::Certificate(1)),
+            // connected from somewhere other than its address
+            (hello("a", 2, 0, 3), Identity::Address(([10, 0, 0, 2]).into())),
+            // too new
+            (hello("a", 2, 4, 5), loopback),
+        ];
+        for (peer, identity) in rejected {
+            let (mut client, mut server) = tokio::io::duplex(1024);
+            let (connected, accepted) = tokio::join!(
+                connect(&mut client, &peer, 1),
+                accept(&mut server, &local, &nodes, identity)
+            );
+            assert!(connected.is_err() && accepted.is_err(), "{:?} was accepted", peer);
+        }
+    }
+}
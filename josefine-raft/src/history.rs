@@ -0,0 +1,379 @@
+//! Records the operations clients perform against a cluster, and checks that the results they saw
+//! are linearizable: that every operation appears to take effect at a single instant between its
+//! invocation and its completion, in an order that a single copy of the state machine agrees with.
+//!
+//! The checker follows Wing & Gong's search, with the memoization of states already explored that
+//! Lowe added and Porcupine uses: operations are linearized one at a time in the order they were
+//! invoked, backtracking whenever an operation completes without having been linearized.
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use josefine_core::error::Result;
+
+use crate::client::RaftClient;
+use crate::fsm::Fsm;
+
+/// Identifies the client that performed an operation, to make violations easier to follow.
+pub type ClientId = u64;
+
+/// Identifies an operation within a history.
+pub type OpId = usize;
+
+/// A request made of the state machine.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Input {
+    Mutate(Vec<u8>),
+    Query(Vec<u8>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// A client sent a request.
+    Invoke { op: OpId, client: ClientId, input: Input },
+    /// The client received the response to the request `op`.
+    Complete { op: OpId, output: Result<Vec<u8>> },
+}
+
+/// Records a history as it happens. Clones share the same history, so each client can be handed
+/// its own.
+#[derive(Clone, Debug, Default)]
+pub struct Recorder {
+    events: Arc<Mutex<Vec<Event>>>,
+}
+
+impl Recorder {
+    pub fn new() -> Recorder {
+        Recorder::default()
+    }
+
+    /// Records that a client sent a request, returning the id to complete it with.
+    pub fn invoke(&self, client: ClientId, input: Input) -> OpId {
+        let mut events = self.events.lock().unwrap();
+        let op = events.len();
+        events.push(Event::Invoke { op, client, input });
+        op
+    }
+
+    /// Records the response a client received.
+    pub fn complete(&self, op: OpId, output: Result<Vec<u8>>) {
+        self.events.lock().unwrap().push(Event::Complete { op, output });
+    }
+
+    /// The events recorded so far, in the order they happened.
+    pub fn events(&self) -> Vec<Event> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+/// A client whose requests are recorded.
+pub struct RecordingClient {
+    id: ClientId,
+    client: RaftClient,
+    recorder: Recorder,
+}
+
+impl RecordingClient {
+    pub fn new(id: ClientId, client: RaftClient, recorder: Recorder) -> Self {
+        Self { id, client, recorder }
+    }
+
+    /// Proposes a state transition, see [`RaftClient::mutate`].
+    pub async fn mutate(&self, command: Vec<u8>) -> Result<Vec<u8>> {
+        let op = self.recorder.invoke(self.id, Input::Mutate(command.clone()));
+        let res = self.client.mutate(command).await;
+        self.recorder.complete(op, res.clone());
+        res
+    }
+
+    /// Queries the state machine, see [`RaftClient::query`].
+    pub async fn query(&self, command: Vec<u8>) -> Result<Vec<u8>> {
+        let op = self.recorder.invoke(self.id, Input::Query(command.clone()));
+        let res = self.client.query(command).await;
+        self.recorder.complete(op, res.clone());
+        res
+    }
+}
+
+/// An invocation paired with its completion.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Operation {
+    pub client: ClientId,
+    pub input: Input,
+    /// What the client was told, or `None` if it never learned whether the operation took effect.
+    pub output: Option<Vec<u8>>,
+    /// The position of the invocation in the history.
+    pub call: usize,
+    /// The position of the completion in the history, if the outcome is known. An operation with
+    /// an unknown outcome may take effect at any point after it was invoked, or not at all.
+    pub ret: Option<usize>,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ret = self.ret.map_or("?".to_string(), |ret| ret.to_string());
+        write!(f, "[{}, {}] client {}: {:?} -> ", self.call, ret, self.client, self.input)?;
+        match &self.output {
+            Some(output) => write!(f, "{:?}", output),
+            None => write!(f, "unknown"),
+        }
+    }
+}
+
+/// Pairs up the events of a history. A mutation that failed or never completed may still have
+/// taken effect, so its outcome is unknown, but a query that failed can be left out entirely.
+pub fn operations(events: &[Event]) -> Vec<Operation> {
+    let mut ops: Vec<Option<Operation>> = Vec::new();
+    let mut index = vec![None; events.len()];
+    for (position, event) in events.iter().enumerate() {
+        match event {
+            Event::Invoke { op, client, input } => {
+                index[*op] = Some(ops.len());
+                ops.push(Some(Operation {
+                    client: *client,
+                    input: input.clone(),
+                    output: None,
+                    call: position,
+                    ret: None,
+                }));
+            }
+            Event::Complete { op, output } => {
+                let slot = match index.get(*op).cloned().flatten() {
+                    Some(slot) => &mut ops[slot],
+                    None => continue,
+                };
+                match (slot.as_mut(), output) {
+                    (Some(operation), Ok(output)) => {
+                        operation.output = Some(output.clone());
+                        operation.ret = Some(position);
+                    }
+                    (Some(Operation { input: Input::Query(_), .. }), Err(_)) => *slot = None,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    ops.into_iter()
+        .flatten()
+        .filter(|op| op.ret.is_some() || matches!(op.input, Input::Mutate(_)))
+        .collect()
+}
+
+/// A history that isn't linearizable.
+#[derive(Debug, PartialEq)]
+pub struct Violation {
+    /// A sub-history that can't be linearized either, with every operation the violation doesn't
+    /// depend on left out. It breaks at the same completion as the full history.
+    pub operations: Vec<Operation>,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "the history is not linearizable, as these operations show:")?;
+        for op in &self.operations {
+            writeln!(f, "  {}", op)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks a history against a sequential model of the state machine, which `model` creates in its
+/// initial state. The model is moved between states with its snapshots, so they must capture
+/// everything that affects its output.
+pub fn check<T: Fsm, F: Fn() -> T>(events: &[Event], model: F) -> std::result::Result<(), Violation> {
+    if linearizable(&operations(events), &model) {
+        return Ok(());
+    }
+
+    // Every prefix of a linearizable history is linearizable too, so find the shortest that isn't:
+    // it ends with the completion that can't be explained, and whatever happened after is left out.
+    let (mut linear, mut violated) = (0, events.len());
+    while violated - linear > 1 {
+        let mid = (linear + violated) / 2;
+        if linearizable(&operations(&events[..mid]), &model) {
+            linear = mid;
+        } else {
+            violated = mid;
+        }
+    }
+    let broken = violated - 1;
+    let mut ops = operations(&events[..violated]);
+
+    // Leaving out a query only removes a constraint, so any query the violation survives without
+    // can go. Leaving out a mutation could instead leave the reads that saw its effect unexplained,
+    // so a mutation only goes if the violation survives not knowing whether it took effect, and
+    // the history still breaks at the same completion rather than earlier.
+    for queries in &[true, false] {
+        let mut i = 0;
+        while i < ops.len() {
+            if matches!(ops[i].input, Input::Query(_)) != *queries {
+                i += 1;
+                continue;
+            }
+
+            let mut smaller = ops.clone();
+            smaller.remove(i);
+            let removable = if *queries {
+                !linearizable(&smaller, &model)
+            } else {
+                let mut relaxed = ops.clone();
+                relaxed[i] = relax(relaxed[i].clone());
+                !linearizable(&relaxed, &model) && linearizable(&before(&smaller, broken), &model)
+            };
+            if removable {
+                ops = smaller;
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    Err(Violation { operations: ops })
+}
+
+/// Forgets whether a mutation took effect.
+fn relax(op: Operation) -> Operation {
+    Operation {
+        output: None,
+        ret: None,
+        ..op
+    }
+}
+
+/// The operations as they stood just before the completion at `position`.
+fn before(ops: &[Operation], position: usize) -> Vec<Operation> {
+    ops.iter()
+        .filter(|op| op.ret != Some(position) || matches!(op.input, Input::Mutate(_)))
+        .map(|op| if op.ret == Some(position) { relax(op.clone()) } else { op.clone() })
+        .collect()
+}
+
+#[derive(Clone, Copy)]
+enum Point {
+    Call(usize),
+    Return(usize),
+}
+
+fn linearizable<T: Fsm, F: Fn() -> T>(ops: &[Operation], model: &F) -> bool {
+    let mut points: Vec<(usize, Point)> = Vec::with_capacity(ops.len() * 2);
+    for (i, op) in ops.iter().enumerate() {
+        points.push((op.call, Point::Call(i)));
+        if let Some(ret) = op.ret {
+            points.push((ret, Point::Return(i)));
+        }
+    }
+    points.sort_by_key(|(position, _)| *position);
+    let mut calls = vec![0; ops.len()];
+    for (i, (_, point)) in points.iter().enumerate() {
+        if let Point::Call(op) = point {
+            calls[*op] = i;
+        }
+    }
+
+    let mut fsm = model();
+    let mut state = fsm.snapshot().expect("could not snapshot the model");
+    let mut linearized = vec![false; ops.len()];
+    // the operations linearized so far, with the state before each
+    let mut stack: Vec<(usize, Vec<u8>)> = Vec::new();
+    // the combinations of linearized operations and resulting states already explored
+    let mut explored = HashSet::new();
+
+    let mut i = 0;
+    while i < points.len() {
+        match points[i].1 {
+            Point::Call(op) | Point::Return(op) if linearized[op] => i += 1,
+            Point::Call(op) => {
+                if let Some(next) = step(&mut fsm, &state, &ops[op]) {
+                    let mut key = linearized.clone();
+                    key[op] = true;
+                    if explored.insert((key, next.clone())) {
+                        linearized[op] = true;
+                        stack.push((op, std::mem::replace(&mut state, next)));
+                        i = 0;
+                        continue;
+                    }
+                }
+                i += 1;
+            }
+            Point::Return(_) => {
+                // the operation must have taken effect by now, but can't have after the ones we
+                // picked, so take back the last of them and try the operations invoked after it
+                match stack.pop() {
+                    Some((op, prev)) => {
+                        linearized[op] = false;
+                        state = prev;
+                        i = calls[op] + 1;
+                    }
+                    None => return false,
+                }
+            }
+        }
+    }
+    true
+}
+
+/// Applies an operation to the model in `state`, returning the state that follows if the model
+/// agrees with what the client was told.
+fn step<T: Fsm>(fsm: &mut T, state: &[u8], op: &Operation) -> Option<Vec<u8>> {
+    fsm.restore(state.to_vec()).expect("could not restore the model");
+    let output = match &op.input {
+        Input::Mutate(data) => fsm.transition(data.clone()),
+        Input::Query(data) => fsm.query(data.clone()),
+    };
+    match (output, &op.output) {
+        (Ok(output), Some(expected)) if output != *expected => None,
+        (Err(_), Some(_)) => None,
+        _ => Some(fsm.snapshot().expect("could not snapshot the model")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use josefine_core::error::JosefineError;
+
+    use super::{check, Input, Recorder};
+    use crate::test::TestFsm;
+
+    #[test]
+    fn accepts_concurrent_operations() {
+        let history = Recorder::new();
+        let write = history.invoke(1, Input::Mutate(vec![1]));
+        // concurrent with the write, so it may see either value
+        let read = history.invoke(2, Input::Query(vec![]));
+        history.complete(read, Ok(vec![1]));
+        history.complete(write, Ok(vec![1]));
+        // the outcome is unknown, but a later read shows it took effect
+        let lost = history.invoke(1, Input::Mutate(vec![2]));
+        history.complete(lost, Err(JosefineError::Internal { error_msg: "timed out".to_string() }));
+        let read = history.invoke(2, Input::Query(vec![]));
+        history.complete(read, Ok(vec![2]));
+
+        assert_eq!(check(&history.events(), TestFsm::default), Ok(()));
+    }
+
+    #[test]
+    fn reports_minimal_violation() {
+        let history = Recorder::new();
+        for (client, value) in &[(1, 1), (2, 2), (1, 3)] {
+            let op = history.invoke(*client, Input::Mutate(vec![*value]));
+            history.complete(op, Ok(vec![*value]));
+        }
+        let read = history.invoke(3, Input::Query(vec![]));
+        history.complete(read, Ok(vec![3]));
+        // a stale read, after the write that followed had completed
+        let stale = history.invoke(2, Input::Query(vec![]));
+        history.complete(stale, Ok(vec![2]));
+
+        let violation = check(&history.events(), TestFsm::default).unwrap_err();
+        let ops: Vec<_> = violation.operations.iter().map(|op| (op.input.clone(), op.output.clone())).collect();
+        assert_eq!(
+            ops,
+            vec![
+                (Input::Mutate(vec![2]), Some(vec![2])),
+                (Input::Mutate(vec![3]), Some(vec![3])),
+                (Input::Query(vec![]), Some(vec![2])),
+            ]
+        );
+    }
+}
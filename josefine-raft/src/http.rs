@@ -0,0 +1,115 @@
+//! A minimal HTTP server for the endpoints operators poll, such as `/metrics`. Only `GET` is
+//! supported, and each connection serves a single request.
+use std::sync::Arc;
+
+use slog::Logger;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+
+use josefine_core::error::Result;
+
+/// The largest request we'll read, so a client can't make us buffer without limit.
+const MAX_REQUEST: usize = 8 * 1024;
+
+/// The content type and body of a response.
+pub type Body = (&'static str, Vec<u8>);
+
+/// Answers requests on `listener` until shutdown. `handler` is given the path of each request,
+/// without its query, and returns nothing for paths that aren't found.
+pub async fn serve<H>(log: Logger, mut shutdown: broadcast::Receiver<()>, listener: TcpListener, handler: H) -> Result<()>
+where
+    H: Fn(&str) -> Option<Body> + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    loop {
+        let (stream, addr) = tokio::select! {
+            _ = shutdown.recv() => break,
+            accepted = listener.accept() => accepted?,
+        };
+
+        let handler = handler.clone();
+        let log = log.clone();
+        tokio::spawn(async move {
+            if let Err(err) = respond(stream, handler.as_ref()).await {
+                debug!(log, "failed to answer http request"; "addr" => addr.to_string(), "error" => format!("{:?}", err));
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn respond<H: Fn(&str) -> Option<Body>>(mut stream: TcpStream, handler: &H) -> Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|end| end == b"\r\n\r\n") {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 || request.len() + read > MAX_REQUEST {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut line = request.lines().next().unwrap_or_default().split_whitespace();
+    let (status, (content_type, body)) = match (line.next(), line.next()) {
+        (Some("GET"), Some(target)) => {
+            let path = target.split('?').next().unwrap_or_default();
+            match handler(path) {
+                Some(body) => ("200 OK", body),
+                None => ("404 Not Found", ("text/plain", b"not found\n".to_vec())),
+            }
+        }
+        _ => ("405 Method Not Allowed", ("text/plain", b"method not allowed\n".to_vec())),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&body).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::logger::get_root_logger;
+
+    async fn get(addr: std::net::SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn serves_paths() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::broadcast::channel(1);
+        let server = tokio::spawn(super::serve(get_root_logger().new(o!()), shutdown_rx, listener, |path| {
+            match path {
+                "/hello" => Some(("text/plain", b"hi".to_vec())),
+                _ => None,
+            }
+        }));
+
+        let found = get(addr, "GET /hello?verbose HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(found.starts_with("HTTP/1.1 200 OK\r\n"), "{}", found);
+        assert!(found.ends_with("\r\n\r\nhi"), "{}", found);
+        assert!(get(addr, "GET /nope HTTP/1.1\r\n\r\n").await.starts_with("HTTP/1.1 404"));
+        assert!(get(addr, "POST /hello HTTP/1.1\r\n\r\n").await.starts_with("HTTP/1.1 405"));
+
+        shutdown_tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+}
@@ -1,10 +1,12 @@
-use std::io::Write;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::time::Duration;
 use std::time::Instant;
 
 use slog::Logger;
 use josefine_core::error::Result;
 
+use crate::clock;
+use crate::config::ReadMode;
 use crate::error::RaftError;
 use crate::follower::Follower;
 use crate::progress::NodeProgress;
@@ -14,17 +16,22 @@ use crate::raft::Entry;
 use crate::raft::EntryType;
 use crate::raft::Raft;
 use crate::raft::Role;
-use crate::raft::Term;
-use crate::raft::{Apply, NodeId, RaftHandle, RaftRole};
+use crate::raft::{Apply, Conflict, Node, NodeId, RaftHandle, RaftRole};
 use crate::rpc::Address;
-use crate::rpc::Message;
 use crate::rpc::Request;
+use crate::rpc::Response;
+use crate::store::{Snapshot, SnapshotFile};
+use crate::transport::ConnectionState;
+use josefine_core::error::JosefineError;
 use crate::{
     fsm,
     raft::LogIndex,
 };
 
-///
+/// The maximum number of bytes of snapshot data sent in a single message.
+const SNAPSHOT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The state of a node while it leads the cluster.
 #[derive(Debug)]
 pub struct Leader {
     pub logger: Logger,
@@ -33,11 +40,71 @@ pub struct Leader {
     pub heartbeat_time: Instant,
     /// The timeout since the last heartbeat.
     pub heartbeat_timeout: Duration,
+    /// The client waiting on the membership change in progress, if any.
+    pub membership_request: Option<Vec<u8>>,
+    /// The leadership transfer in progress, if any.
+    pub transfer: Option<Transfer>,
+    /// The sequence number of the latest heartbeat round.
+    pub heartbeat_seq: u64,
+    /// Reads waiting for our leadership to be confirmed.
+    pub reads: Vec<Read>,
+    /// When recent heartbeat rounds were sent, by sequence number.
+    pub heartbeats: VecDeque<(u64, Instant)>,
+    /// The latest heartbeat round each node has acknowledged.
+    pub acked: HashMap<NodeId, u64>,
+    /// Heartbeat rounds before this one can't be used for a lease.
+    pub lease_floor: u64,
+    /// Client proposals waiting to be appended together.
+    pub proposals: Vec<Proposal>,
+    /// The total size of the data in `proposals`.
+    pub proposal_bytes: usize,
+    /// When the appends each node hasn't acknowledged were sent, by the index of their last entry.
+    pub appends: HashMap<NodeId, VecDeque<(LogIndex, Instant)>>,
+    /// The latest snapshot, kept while it's being sent to nodes so it isn't loaded for every chunk.
+    pub snapshot: Option<Snapshot>,
+}
+
+/// A client proposal waiting to be appended to the log with the rest of its batch.
+#[derive(Debug)]
+pub struct Proposal {
+    pub client_id: Vec<u8>,
+    pub data: Vec<u8>,
+    /// When the proposal was received.
+    pub received: Instant,
+}
+
+/// A read that can be served once a quorum has confirmed we're still the leader and the state
+/// machine has caught up with `index`.
+#[derive(Debug)]
+pub struct Read {
+    /// The client that requested the read.
+    pub client_id: Vec<u8>,
+    pub data: Vec<u8>,
+    /// The commit index when the read was requested.
+    pub index: LogIndex,
+    /// The heartbeat round that confirms the read.
+    pub seq: u64,
+    /// The nodes that have acknowledged that round.
+    pub acks: HashSet<NodeId>,
+}
+
+/// Leadership being handed over to another node. No new proposals are accepted while this is in
+/// progress, so the target can catch up with the log.
+#[derive(Debug)]
+pub struct Transfer {
+    /// The node that will become the leader.
+    pub target: NodeId,
+    /// The client that requested the transfer.
+    pub client_id: Vec<u8>,
+    /// When the transfer began.
+    pub started: Instant,
+    /// Whether the target has been told to start an election.
+    pub timeout_sent: bool,
 }
 
 impl Role for Leader {
     fn term(&mut self, _term: u64) {
-        unimplemented!()
+        // a leader steps down whenever it sees a new term, so there's nothing to reset
     }
 
     fn role(&self) -> RaftRole {
@@ -50,45 +117,130 @@ impl Role for Leader {
 }
 
 impl Raft<Leader> {
-    pub(crate) fn heartbeat(&self) -> Result<()> {
+    pub(crate) fn heartbeat(&mut self) -> Result<()> {
+        self.role.heartbeat_seq += 1;
+        let lease = self.lease_duration();
+        while let Some((_, sent)) = self.role.heartbeats.front() {
+            if clock::elapsed(*sent) < lease {
+                break;
+            }
+            self.role.heartbeats.pop_front();
+        }
+        self.role.heartbeats.push_back((self.role.heartbeat_seq, clock::now()));
+
         self.send_all(Command::Heartbeat {
             term: self.state.current_term,
             leader_id: self.id,
+            seq: self.role.heartbeat_seq,
+            leader_commit: self.state.commit_index,
         })?;
         Ok(())
     }
 
+    /// How long after sending a heartbeat its acknowledgements hold off a new election. Followers
+    /// wait at least the minimum election timeout, less whatever our clocks may have drifted.
+    fn lease_duration(&self) -> Duration {
+        self.config
+            .min_election_timeout()
+            .checked_sub(self.config.max_clock_drift)
+            .unwrap_or_default()
+    }
+
+    /// Whether a quorum acknowledged a heartbeat recently enough that no other leader can have
+    /// been elected since.
+    fn has_lease(&self) -> bool {
+        if self.config.read_mode != ReadMode::Lease || self.role.transfer.is_some() {
+            return false;
+        }
+
+        let config = &self.membership.config;
+        let acked = |id: NodeId, seq: u64| {
+            id == self.id || self.role.acked.get(&id).is_some_and(|acked| *acked >= seq)
+        };
+        self.role
+            .heartbeats
+            .iter()
+            .rev()
+            .filter(|(seq, _)| *seq >= self.role.lease_floor)
+            .find(|(seq, _)| config.has_quorum(|id| acked(id, *seq)))
+            .is_some_and(|(_, sent)| clock::elapsed(*sent) < self.lease_duration())
+    }
+
     fn needs_heartbeat(&self) -> bool {
-        self.role.heartbeat_time.elapsed() > self.role.heartbeat_timeout
+        clock::elapsed(self.role.heartbeat_time) > self.role.heartbeat_timeout
     }
 
     fn reset_heartbeat_timer(&mut self) {
-        self.role.heartbeat_time = Instant::now();
+        self.role.heartbeat_time = clock::now();
     }
 
-    fn append(mut self, data: Vec<u8>) -> Result<RaftHandle> {
+    fn append(mut self, entry_type: EntryType) -> Result<RaftHandle> {
         let term = self.state.current_term;
-        let next_index = self.log.next_index();
-        let entry = Entry {
-            entry_type: EntryType::Entry { data },
+        let index = self.append_local(entry_type)?;
+
+        let node_id = self.id;
+        self.apply(Command::AppendResponse {
+            node_id,
             term,
-            index: next_index,
-        };
-        let index = self.log.append(entry)?;
-        assert_eq!(next_index, index);
+            index,
+            success: true,
+            conflict: None,
+        })
+    }
 
-        self.state.last_applied = index;
+    /// Queues a client proposal, appending the batch right away if it has grown large enough.
+    fn propose(mut self, id: Vec<u8>, data: Vec<u8>) -> Result<RaftHandle> {
+        self.role.proposal_bytes += data.len();
+        self.role.proposals.push(Proposal {
+            client_id: id,
+            data,
+            received: clock::now(),
+        });
+
+        if self.role.proposal_bytes >= self.config.max_batch_bytes {
+            return self.flush();
+        }
+        Ok(RaftHandle::Leader(Box::new(self)))
+    }
 
-        // self.rpc_tx.send(Message::new(
-        //     Address::Peer(self.id),
-        //     Address::Local,
-        //     Command::AppendResponse {
-        //         node_id: self.id,
-        //         term: self.state.current_term,
-        //         index: self.state.last_applied,
-        //         success: true,
-        //     },
-        // ))?;
+    /// Whether the oldest queued proposal has waited for the batch timeout.
+    fn batch_ready(&self) -> bool {
+        self.role
+            .proposals
+            .first()
+            .is_some_and(|proposal| clock::elapsed(proposal.received) >= self.config.batch_timeout)
+    }
+
+    /// Appends every queued proposal with a single write and replicates them together. Each
+    /// client is answered once its entry has been applied.
+    fn flush(mut self) -> Result<RaftHandle> {
+        if self.role.proposals.is_empty() {
+            return Ok(RaftHandle::Leader(Box::new(self)));
+        }
+
+        let term = self.state.current_term;
+        let proposals = std::mem::take(&mut self.role.proposals);
+        self.role.proposal_bytes = 0;
+
+        let mut clients = Vec::with_capacity(proposals.len());
+        let mut entries = Vec::with_capacity(proposals.len());
+        for (index, proposal) in (self.log.next_index()..).zip(proposals) {
+            clients.push((index, proposal.client_id));
+            entries.push(Entry {
+                entry_type: EntryType::Entry { data: proposal.data },
+                term,
+                index,
+            });
+        }
+
+        let index = self.log.append_all(entries)?;
+        self.state.last_applied = index;
+        for (index, id) in clients {
+            self.fsm_tx
+                .send(fsm::Instruction::Notify { id, index, term })
+                .map_err(RaftError::from)?;
+        }
+        self.replicate()?;
 
         let node_id = self.id;
         self.apply(Command::AppendResponse {
@@ -96,116 +248,474 @@ impl Raft<Leader> {
             term,
             index,
             success: true,
+            conflict: None,
         })
     }
 
-    fn query(mut self, data: Vec<u8>) -> Result<RaftHandle> {
-        // self.fsm_tx.send(fsm::Instruction::Query {
-        //
-        // });
-        unimplemented!()
+    /// Appends a new entry in the current term to our own log.
+    fn append_local(&mut self, entry_type: EntryType) -> Result<LogIndex> {
+        let next_index = self.log.next_index();
+        let entry = Entry {
+            entry_type,
+            term: self.state.current_term,
+            index: next_index,
+        };
+        let index = self.append_entry(entry)?;
+        assert_eq!(next_index, index);
+
+        self.state.last_applied = index;
+        Ok(index)
+    }
+
+    /// Starts moving the cluster to a new set of voters and learners. Changes to the voters go
+    /// through joint consensus first.
+    fn change_membership(mut self, id: Vec<u8>, voters: Vec<Node>, learners: Vec<Node>) -> Result<RaftHandle> {
+        if self.role.membership_request.is_some()
+            || self.membership.config.is_joint()
+            || self.membership.index > self.state.commit_index
+        {
+            return self.reject(id, "a membership change is already in progress");
+        }
+
+        let config = self.membership.config.change(voters, learners);
+        let next = self.log.next_index();
+        for node in config.nodes() {
+            if self.role.progress.get(node.id).is_none() {
+                self.role.progress.insert(node.id, next);
+            }
+        }
+
+        self.role.membership_request = Some(id);
+        self.append(EntryType::Config { config })
+    }
+
+    /// Makes a learner a voter, once it has caught up with the committed log.
+    fn promote_learner(self, id: Vec<u8>, node_id: NodeId) -> Result<RaftHandle> {
+        let config = &self.membership.config;
+        let learner = match config.learners.iter().find(|node| node.id == node_id) {
+            Some(learner) => *learner,
+            None => return self.reject(id, "the node is not a learner"),
+        };
+
+        let caught_up = match self.role.progress.get(node_id) {
+            Some(progress) => progress.index() >= self.state.commit_index,
+            None => false,
+        };
+        if !caught_up {
+            return self.reject(id, "the learner has not caught up");
+        }
+
+        let mut voters = config.voters.clone();
+        voters.push(learner);
+        let learners = config.learners.iter().filter(|node| node.id != node_id).cloned().collect();
+        self.change_membership(id, voters, learners)
+    }
+
+    /// Fails a client request without changing anything.
+    fn reject(self, id: Vec<u8>, reason: &str) -> Result<RaftHandle> {
+        self.send(
+            Address::Client,
+            Command::ClientResponse {
+                id,
+                res: Err(JosefineError::Internal {
+                    error_msg: reason.to_string(),
+                }),
+            },
+        )?;
+        Ok(RaftHandle::Leader(Box::new(self)))
+    }
+
+    /// Moves membership changes along once their config entries are committed. Once the joint
+    /// configuration is committed the new configuration is appended, and once that is committed
+    /// the change is complete.
+    fn commit_membership(&mut self) -> Result<()> {
+        if self.membership.index > self.state.commit_index {
+            return Ok(());
+        }
+
+        if self.membership.config.is_joint() {
+            let config = self.membership.config.leave_joint();
+            let index = self.append_local(EntryType::Config { config })?;
+            self.role.progress.advance(self.id, index);
+            self.commit()?;
+            return Ok(());
+        }
+
+        if let Some(id) = self.role.membership_request.take() {
+            let config = self.membership.config.clone();
+            for node_id in self.membership.previous.node_ids() {
+                if !config.contains(node_id) && node_id != self.id {
+                    self.role.progress.remove(node_id);
+                }
+            }
+
+            self.send(
+                Address::Client,
+                Command::ClientResponse {
+                    id,
+                    res: Ok(Response::Membership(config.voters)),
+                },
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Starts handing leadership over to another voter.
+    fn transfer_leadership(mut self, id: Vec<u8>, target: NodeId) -> Result<RaftHandle> {
+        if self.role.transfer.is_some() {
+            return self.reject(id, "a leadership transfer is already in progress");
+        }
+        if target == self.id || !self.membership.config.is_voter(target) {
+            return self.reject(id, "the node is not a voter that can take over");
+        }
+
+        info!(self.role.logger, "Transferring leadership"; "target" => target);
+        self.role.transfer = Some(Transfer {
+            target,
+            client_id: id,
+            started: clock::now(),
+            timeout_sent: false,
+        });
+        self.transfer()?;
+        Ok(RaftHandle::Leader(Box::new(self)))
+    }
+
+    /// Tells the transfer target to start an election once it has every entry in our log.
+    fn transfer(&mut self) -> Result<()> {
+        let last_index = self.log.next_index() - 1;
+        let (target, client_id) = match &mut self.role.transfer {
+            Some(transfer) if !transfer.timeout_sent => {
+                let caught_up = match self.role.progress.get(transfer.target) {
+                    Some(progress) => progress.index() >= last_index,
+                    None => false,
+                };
+                if !caught_up {
+                    return Ok(());
+                }
+
+                transfer.timeout_sent = true;
+                (transfer.target, transfer.client_id.clone())
+            }
+            _ => return Ok(()),
+        };
+
+        self.send(
+            Address::Peer(target),
+            Command::TimeoutNow {
+                term: self.state.current_term,
+                leader_id: self.id,
+            },
+        )?;
+        self.send(
+            Address::Client,
+            Command::ClientResponse {
+                id: client_id,
+                res: Ok(Response::LeadershipTransferred(target)),
+            },
+        )
+    }
+
+    /// Gives up on a transfer that hasn't completed within an election timeout, so we can go back
+    /// to accepting proposals.
+    fn expire_transfer(&mut self) -> Result<()> {
+        let expired = match &self.role.transfer {
+            Some(transfer) => clock::elapsed(transfer.started) > self.config.election_timeout,
+            None => false,
+        };
+        if !expired {
+            return Ok(());
+        }
+
+        let transfer = self.role.transfer.take().unwrap();
+        warn!(self.role.logger, "Leadership transfer timed out"; "target" => transfer.target);
+        // The target may have won votes on the strength of acknowledgements we've already
+        // received, so only rounds from here on count toward a lease.
+        self.role.lease_floor = self.role.heartbeat_seq + 1;
+        if !transfer.timeout_sent {
+            self.send(
+                Address::Client,
+                Command::ClientResponse {
+                    id: transfer.client_id,
+                    res: Err(JosefineError::Internal {
+                        error_msg: "the leadership transfer timed out".to_string(),
+                    }),
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The current voters and learners, leaving out the given node.
+    fn members_except(&self, node_id: NodeId) -> (Vec<Node>, Vec<Node>) {
+        let config = &self.membership.config;
+        let except = |nodes: &Vec<Node>| nodes.iter().filter(|node| node.id != node_id).cloned().collect();
+        (except(&config.voters), except(&config.learners))
+    }
+
+    /// A leader that has been removed from the cluster steps down once the change is committed.
+    fn step_down_if_removed(self) -> Result<RaftHandle> {
+        if !self.membership.config.is_voter(self.id)
+            && self.membership.index <= self.state.commit_index
+        {
+            info!(self.role.logger, "Removed from the cluster, stepping down");
+            return Ok(RaftHandle::Follower(Raft::from(self)));
+        }
+
+        Ok(RaftHandle::Leader(Box::new(self)))
+    }
+
+    /// Serves a read using the ReadIndex protocol: the read waits for the commit index at the time
+    /// of the request to be applied, once a round of heartbeats confirms nobody has replaced us.
+    fn query(mut self, id: Vec<u8>, data: Vec<u8>) -> Result<RaftHandle> {
+        let committed_in_term = self.log.term(self.state.commit_index)? == Some(self.state.current_term);
+        if committed_in_term && self.has_lease() {
+            self.fsm_tx
+                .send(fsm::Instruction::Query {
+                    id,
+                    data,
+                    index: self.state.commit_index,
+                })
+                .map_err(RaftError::from)?;
+            return Ok(RaftHandle::Leader(Box::new(self)));
+        }
+
+        // Until an entry from our term is committed we may not know the latest commit index, so
+        // read from the end of our log instead, making sure there's an entry from our term there.
+        let index = if committed_in_term {
+            self.state.commit_index
+        } else {
+            let (last_term, _) = self.last_log()?;
+            if last_term != self.state.current_term {
+                let index = self.append_local(EntryType::Command { command: Command::Noop })?;
+                self.role.progress.advance(self.id, index);
+                self.commit()?;
+            }
+            self.log.next_index() - 1
+        };
+
+        self.heartbeat()?;
+        self.reset_heartbeat_timer();
+
+        let mut acks = HashSet::new();
+        acks.insert(self.id);
+        self.role.reads.push(Read {
+            client_id: id,
+            data,
+            index,
+            seq: self.role.heartbeat_seq,
+            acks,
+        });
+        self.serve_reads()?;
+        Ok(RaftHandle::Leader(Box::new(self)))
+    }
+
+    /// Hands reads that have been confirmed by a quorum to the state machine, which will run them
+    /// once it has applied their index.
+    fn serve_reads(&mut self) -> Result<()> {
+        let config = &self.membership.config;
+        let commit_index = self.state.commit_index;
+        let (ready, waiting) = self
+            .role
+            .reads
+            .drain(..)
+            .partition(|read| read.index <= commit_index && config.has_quorum(|id| read.acks.contains(&id)));
+        self.role.reads = waiting;
+
+        for read in ready {
+            self.fsm_tx
+                .send(fsm::Instruction::Query {
+                    id: read.client_id,
+                    data: read.data,
+                    index: read.index,
+                })
+                .map_err(RaftError::from)?;
+        }
+        Ok(())
     }
 
     fn commit(&mut self) -> Result<LogIndex> {
-        let quorum_idx = self.role.progress.committed_index();
+        let quorum_idx = self.role.progress.committed_index(&self.membership.config);
         if quorum_idx > self.state.commit_index
             && self.log.check_term(quorum_idx, self.state.current_term)
         {
             self.log.commit(quorum_idx)?;
             let prev = self.state.commit_index;
             self.state.commit_index = quorum_idx;
-            self.log
-                .get_range(prev, self.state.commit_index)?
-                .into_iter()
-                .for_each(|entry| self.fsm_tx.send(fsm::Instruction::Drive { entry }).unwrap());
+            self.save_state()?;
+            for entry in self.log.get_range(prev, self.state.commit_index)? {
+                self.fsm_tx.send(fsm::Instruction::Drive { entry }).map_err(RaftError::from)?;
+            }
+            self.commit_membership()?;
         }
 
         Ok(quorum_idx)
     }
 
-    fn write_state(&self) {
-        #[derive(Serialize)]
-        struct RaftDebugState {
-            leader_id: NodeId,
-            term: Term,
-            index: LogIndex,
+    /// Sends the latest snapshot to any follower that needs entries we have already compacted.
+    fn send_snapshots(&mut self) -> Result<()> {
+        let snapshot_index = self.log.snapshot_index();
+        if snapshot_index == 0 {
+            return Ok(());
         }
 
-        let tmp = std::env::temp_dir();
-        let state_file = tmp.join("josefine.json");
-        let mut state_file = std::fs::File::create(state_file).expect("couldn't create state file");
+        for node_id in self.peers().iter().map(|node| node.id) {
+            if self.role.progress.connection(node_id) != ConnectionState::Connected {
+                continue;
+            }
+            let needs_snapshot = match self.role.progress.get(node_id) {
+                Some(NodeProgress::Snapshot(progress)) => {
+                    progress.is_expired(self.config.election_timeout)
+                }
+                Some(progress) => progress.next() <= snapshot_index,
+                None => false,
+            };
 
-        let debug_state = RaftDebugState {
-            leader_id: self.id,
-            index: self.state.commit_index,
-            term: self.state.current_term,
+            if needs_snapshot {
+                self.send_snapshot(node_id)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Starts sending the latest snapshot to the node, or carries on from what it has acknowledged
+    /// if it was already being sent.
+    fn send_snapshot(&mut self, node_id: NodeId) -> Result<()> {
+        let stale = match &self.role.snapshot {
+            Some(snapshot) => snapshot.last_index != self.log.snapshot_index(),
+            None => true,
         };
-        state_file
-            .write_all(&serde_json::to_vec(&debug_state).expect("could not serialize state"))
-            .expect("could not write state");
+        if stale {
+            self.role.snapshot = SnapshotFile::open(&self.config.data_directory)?.load()?;
+        }
+        let last_index = match &self.role.snapshot {
+            Some(snapshot) => snapshot.last_index,
+            None => return Ok(()),
+        };
+        info!(self.role.logger, "Sending snapshot"; "node_id" => node_id, "index" => last_index);
+
+        self.role.progress.snapshot(node_id, last_index);
+        let offset = match self.role.progress.get(node_id) {
+            Some(NodeProgress::Snapshot(progress)) => progress.state.offset,
+            _ => 0,
+        };
+        self.send_snapshot_chunk(node_id, offset)
     }
 
-    fn replicate(&mut self) -> Result<()> {
-        for node in &self.config.nodes {
-            if let Some(mut progress) = self.role.progress.get_mut(node.id) {
-                if !progress.is_active() {
-                    continue;
+    /// Sends the chunk of the snapshot starting at `offset`. The next one is only sent once the
+    /// node acknowledges this one, so a slow node isn't flooded with the whole snapshot at once.
+    fn send_snapshot_chunk(&self, node_id: NodeId, offset: u64) -> Result<()> {
+        let snapshot = match &self.role.snapshot {
+            Some(snapshot) => snapshot,
+            None => return Ok(()),
+        };
+        let start = std::cmp::min(offset as usize, snapshot.data.len());
+        let end = std::cmp::min(start + SNAPSHOT_CHUNK_SIZE, snapshot.data.len());
+        self.send(
+            Address::Peer(node_id),
+            Command::InstallSnapshot {
+                term: self.state.current_term,
+                leader_id: self.id,
+                last_index: snapshot.last_index,
+                last_term: snapshot.last_term,
+                config: snapshot.config.clone(),
+                offset: start as u64,
+                data: snapshot.data[start..end].to_vec(),
+                done: end == snapshot.data.len(),
+            },
+        )
+    }
+
+    /// Finds the index to resume replicating from after a node rejected our entries. If we have
+    /// entries from the conflicting term, the node is missing the ones after our last entry for
+    /// it. Otherwise every entry the node has for that term needs to be replaced.
+    fn backtrack(&self, conflict: Option<Conflict>) -> Result<LogIndex> {
+        let conflict = match conflict {
+            Some(conflict) => conflict,
+            None => return Ok(self.log.snapshot_index() + 1),
+        };
+
+        if conflict.term > 0 {
+            let mut index = self.log.next_index() - 1;
+            while index > self.log.snapshot_index() {
+                match self.log.term(index)? {
+                    Some(term) if term == conflict.term => return Ok(index + 1),
+                    Some(term) if term < conflict.term => break,
+                    _ => index -= 1,
                 }
+            }
+        }
+        Ok(conflict.index)
+    }
 
-                match &mut progress {
-                    NodeProgress::Probe(progress) => {
-                        if !progress.next > progress.index {
-                            continue;
-                        }
-
-                        let prev = self.log.get(progress.index)?;
-                        let (prev_log_index, prev_log_term) = if let Some(prev) = prev {
-                            (prev.index, prev.term)
-                        } else {
-                            (0, 0)
-                        };
-                        let entry = self
-                            .log
-                            .get(progress.next)?
-                            .expect("next entry didn't exist");
-                        self.rpc_tx.send(Message::new(
-                            Address::Peer(self.id),
-                            Address::Peer(node.id),
-                            Command::AppendEntries {
-                                term: self.state.current_term,
-                                leader_id: self.id,
-                                entries: vec![entry],
-                                prev_log_index,
-                                prev_log_term,
-                            },
-                        )).map_err(|err| RaftError::from(err))?;
-                    }
-                    NodeProgress::Replicate(progress) => {
-                        if !progress.next > progress.index {
-                            continue;
-                        }
-
-                        let term = self.state.current_term;
-                        let start = progress.next;
-                        let end = progress.next + crate::progress::MAX_INFLIGHT;
-                        let entries = self.log.get_range(start, end)?;
-                        let prev = self
-                            .log
-                            .get(progress.index)?
-                            .expect("previous entry did not exist!");
-                        self.rpc_tx.send(Message::new(
-                            Address::Peer(self.id),
-                            Address::Peer(node.id),
-                            Command::AppendEntries {
-                                term,
-                                leader_id: self.id,
-                                entries,
-                                prev_log_index: prev.index,
-                                prev_log_term: prev.term,
-                            },
-                        )).map_err(|err| RaftError::from(err))?;
-                    }
-                    _ => {}
+    /// Sends the node the entries following `prev_log_index`, up to `max_append_entries` of them.
+    /// Returns the index of the last entry sent.
+    fn send_entries(&self, node_id: NodeId, prev_log_index: LogIndex, last_index: LogIndex) -> Result<LogIndex> {
+        // a probe of a node that may already have everything carries no entries at all
+        let end = std::cmp::max(prev_log_index, std::cmp::min(prev_log_index + self.config.max_append_entries, last_index));
+        let entries = self.log.get_range(prev_log_index, end)?;
+        let prev_log_term = self.log.term(prev_log_index)?.unwrap_or(0);
+        self.send(
+            Address::Peer(node_id),
+            Command::AppendEntries {
+                term: self.state.current_term,
+                leader_id: self.id,
+                entries,
+                prev_log_index,
+                prev_log_term,
+                leader_commit: self.state.commit_index,
+            },
+        )?;
+        Ok(end)
+    }
+
+    /// Records how long the appends to the node up to `index` took to be acknowledged.
+    fn acknowledged(&mut self, node_id: NodeId, index: LogIndex) {
+        if let Some(appends) = self.role.appends.get_mut(&node_id) {
+            while let Some((_, sent)) = appends.front().filter(|(last, _)| *last <= index).copied() {
+                appends.pop_front();
+                self.metrics.append_latency.observe(clock::elapsed(sent).as_secs_f64());
+            }
+        }
+    }
+
+    /// Forgets the appends to the node that were given up on as lost, so they aren't counted
+    /// towards its append latency.
+    fn expire_appends(&mut self, node_id: NodeId) {
+        let timeout = self.config.election_timeout;
+        if let Some(appends) = self.role.appends.get_mut(&node_id) {
+            appends.retain(|(_, sent)| clock::elapsed(*sent) <= timeout);
+        }
+    }
+
+    fn replicate(&mut self) -> Result<()> {
+        self.send_snapshots()?;
+
+        let last_index = self.log.next_index() - 1;
+        for node in &self.peers() {
+            // anything sent to a node we aren't connected to would be dropped, we'll probe it
+            // again once it reconnects
+            if self.role.progress.connection(node.id) != ConnectionState::Connected {
+                continue;
+            }
+
+            // a probing node gets a single message until we know where its log matches ours, even
+            // if we have nothing new for it, a replicating one gets as many as its inflight window
+            // allows
+            loop {
+                let next = match self.role.progress.get(node.id) {
+                    Some(progress @ NodeProgress::Probe(_)) if progress.is_active() => progress.next(),
+                    Some(progress) if progress.is_active() && progress.next() <= last_index => progress.next(),
+                    _ => break,
+                };
+                let sent = self.send_entries(node.id, next - 1, last_index)?;
+                self.role.progress.sent(node.id, sent);
+                let appends = self.role.appends.entry(node.id).or_default();
+                appends.push_back((sent, clock::now()));
+                // the oldest was lost if more are outstanding than the inflight window allows
+                if appends.len() > self.config.max_inflight {
+                    appends.pop_front();
                 }
             }
         }
@@ -219,68 +729,242 @@ impl Apply for Raft<Leader> {
         self.log_command(&cmd);
         match cmd {
             Command::Tick => {
-                self.write_state();
-
                 if self.needs_heartbeat() {
                     self.heartbeat()?;
                     self.reset_heartbeat_timer();
                 }
 
+                if !self.role.progress.quorum_active(&self.membership.config, self.id, self.config.election_timeout) {
+                    info!(self.role.logger, "Lost contact with a quorum, stepping down");
+                    return Ok(RaftHandle::Follower(Raft::from(self)));
+                }
+
                 self.replicate()?;
+                self.expire_transfer()?;
 
-                Ok(RaftHandle::Leader(self))
+                if self.batch_ready() {
+                    return self.flush();
+                }
+                Ok(RaftHandle::Leader(Box::new(self)))
+            }
+            Command::Flush => {
+                if self.batch_ready() {
+                    return self.flush();
+                }
+                Ok(RaftHandle::Leader(Box::new(self)))
+            }
+            Command::HeartbeatResponse { term, .. }
+            | Command::AppendResponse { term, .. }
+            | Command::InstallSnapshotResponse { term, .. }
+                if term > self.state.current_term =>
+            {
+                info!(self.role.logger, "Peer has a newer term, stepping down"; "term" => term);
+                self.term(term);
+                self.save_state()?;
+                Ok(RaftHandle::Follower(Raft::from(self)))
+            }
+            Command::HeartbeatResponse { term, .. }
+            | Command::AppendResponse { term, .. }
+            | Command::InstallSnapshotResponse { term, .. }
+                if term < self.state.current_term =>
+            {
+                // a response to what we sent while leading an earlier term, which says nothing
+                // about what the node holds now, and whose heartbeat sequence numbers overlap
+                // with ours
+                Ok(RaftHandle::Leader(Box::new(self)))
+            }
+            Command::HeartbeatResponse { node_id, seq, .. } => {
+                self.role.progress.responded(node_id);
+                self.role.progress.resume(node_id, self.config.election_timeout);
+                self.expire_appends(node_id);
+                let acked = self.role.acked.entry(node_id).or_insert(0);
+                *acked = std::cmp::max(*acked, seq);
+                for read in self.role.reads.iter_mut().filter(|read| seq >= read.seq) {
+                    read.acks.insert(node_id);
+                }
+                self.serve_reads()?;
+                Ok(RaftHandle::Leader(Box::new(self)))
+            }
+            Command::AppendResponse { node_id, index, success: false, conflict, .. } => {
+                if self.role.progress.get(node_id).is_some() {
+                    self.role.progress.responded(node_id);
+                    let next = self.backtrack(conflict)?;
+                    debug!(self.role.logger, "Entries rejected"; "node_id" => node_id, "index" => index, "next" => next);
+                    self.role.progress.reject(node_id, index, next);
+                    self.role.appends.remove(&node_id);
+                }
+                Ok(RaftHandle::Leader(Box::new(self)))
             }
             Command::AppendResponse { node_id, index, .. } => {
-                self.role.progress.advance(node_id, index);
-                self.commit()?;
-                Ok(RaftHandle::Leader(self))
+                if self.role.progress.get(node_id).is_some() {
+                    self.role.progress.responded(node_id);
+                    self.role.progress.advance(node_id, index);
+                    self.acknowledged(node_id, index);
+                    self.commit()?;
+                    self.serve_reads()?;
+                    self.transfer()?;
+                }
+                self.step_down_if_removed()
             }
-            Command::AppendEntries { term, .. } => {
-                if term > self.state.current_term {
-                    // TODO(jcm): move term logic into dedicated handler
-                    self.term(term);
-                    return Ok(RaftHandle::Follower(Raft::from(self)));
+            Command::InstallSnapshotResponse { node_id, index, offset, done, success, .. } => {
+                let pending = match self.role.progress.get(node_id) {
+                    Some(NodeProgress::Snapshot(progress)) => progress.state.pending,
+                    Some(_) => None,
+                    None => return Ok(RaftHandle::Leader(Box::new(self))),
+                };
+                self.role.progress.responded(node_id);
+                if pending != Some(index) {
+                    // a response about a snapshot we've since stopped sending
+                    return self.step_down_if_removed();
                 }
 
-                Ok(RaftHandle::Leader(self))
+                if !success {
+                    self.role.progress.snapshot_failed(node_id);
+                } else if done {
+                    self.role.progress.snapshot_done(node_id, index);
+                    self.commit()?;
+                } else {
+                    self.role.progress.snapshot_acked(node_id, offset);
+                    self.send_snapshot_chunk(node_id, offset)?;
+                }
+                self.step_down_if_removed()
+            }
+            Command::AppendEntries { term, .. }
+            | Command::Heartbeat { term, .. }
+            | Command::InstallSnapshot { term, .. }
+                if term > self.state.current_term =>
+            {
+                // a leader of a later term, which the follower we become should hear from
+                self.term(term);
+                self.save_state()?;
+                let raft: Raft<Follower> = Raft::from(self);
+                raft.apply(cmd)
+            }
+            Command::PreVoteRequest { .. } => {
+                // we're the leader, so there's no need for an election
+                self.respond_pre_vote(&cmd, false)?;
+                Ok(RaftHandle::Leader(Box::new(self)))
             }
-            Command::ClientRequest { req, .. } => {
+            Command::VoteRequest { term, .. } if term > self.state.current_term => {
+                // e.g. the target of a leadership transfer has started its election
+                self.term(term);
+                self.save_state()?;
+                let raft: Raft<Follower> = Raft::from(self);
+                raft.apply(cmd)
+            }
+            Command::ClientRequest { id, req } => {
+                // only reads are served while leadership is being handed over
+                if self.role.transfer.is_some() && !matches!(req, Request::Query(_)) {
+                    return self.reject(id, "leadership is being transferred");
+                }
+
                 match req {
-                    Request::Propose(data) => self.append(data),
-                    Request::Query(data) => self.query(data),
+                    Request::Propose(data) => self.propose(id, data),
+                    Request::Query(data) => self.query(id, data),
+                    Request::AddNode(node) => {
+                        let (mut voters, learners) = self.members_except(node.id);
+                        voters.push(node);
+                        self.change_membership(id, voters, learners)
+                    }
+                    Request::AddLearner(node) => {
+                        let (voters, mut learners) = self.members_except(node.id);
+                        learners.push(node);
+                        self.change_membership(id, voters, learners)
+                    }
+                    Request::PromoteLearner(node_id) => self.promote_learner(id, node_id),
+                    Request::TransferLeadership(node_id) => self.transfer_leadership(id, node_id),
+                    Request::RemoveNode(node_id) => {
+                        let (voters, learners) = self.members_except(node_id);
+                        self.change_membership(id, voters, learners)
+                    }
+                }
+            }
+            Command::Compact { index, term } => {
+                self.compact(index, term)?;
+                Ok(RaftHandle::Leader(Box::new(self)))
+            }
+            Command::Connection { node_id, state } => {
+                info!(self.role.logger, "Peer connection changed"; "node_id" => node_id, "state" => format!("{:?}", state));
+                self.role.progress.connection_changed(node_id, state);
+                self.role.appends.remove(&node_id);
+                if state == ConnectionState::Connected {
+                    self.replicate()?;
                 }
+                Ok(RaftHandle::Leader(Box::new(self)))
             }
-            _ => Ok(RaftHandle::Leader(self)),
+            _ => Ok(RaftHandle::Leader(Box::new(self))),
         }
     }
 }
 
 impl From<Raft<Leader>> for Raft<Follower> {
     fn from(val: Raft<Leader>) -> Raft<Follower> {
+        // Clients still waiting on us won't hear back once we're no longer the leader.
+        let waiting = val
+            .role
+            .reads
+            .iter()
+            .map(|read| read.client_id.clone())
+            .chain(val.role.proposals.iter().map(|proposal| proposal.client_id.clone()))
+            .chain(val.role.membership_request.clone())
+            // once TimeoutNow is sent the client has already been told the transfer happened
+            .chain(
+                val.role
+                    .transfer
+                    .as_ref()
+                    .filter(|transfer| !transfer.timeout_sent)
+                    .map(|transfer| transfer.client_id.clone()),
+            );
+        for id in waiting {
+            let _ = val.send(
+                Address::Client,
+                Command::ClientResponse {
+                    id,
+                    res: Err(JosefineError::Internal {
+                        error_msg: "leadership was lost".to_string(),
+                    }),
+                },
+            );
+        }
+
         Raft {
             id: val.id,
             state: val.state,
             role: Follower {
                 leader_id: None,
+                matched: 0,
                 logger: val.logger.new(o!("role" => "follower")),
+                snapshot: None,
+                installing: None,
             },
             logger: val.logger,
             config: val.config,
             log: val.log,
+            hard_state: val.hard_state,
+            membership: val.membership,
             rpc_tx: val.rpc_tx,
             fsm_tx: val.fsm_tx,
+            metrics: val.metrics,
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
+    use futures::FutureExt;
+    use josefine_core::error::JosefineError;
 
+    use super::SNAPSHOT_CHUNK_SIZE;
     use crate::{
+        config::ReadMode,
         fsm::Instruction,
-        raft::{Apply, Command, EntryType, RaftHandle},
-        rpc::Request,
-        test::new_follower,
+        progress::NodeProgress,
+        raft::{Apply, Command, Conflict, Entry, EntryType, Node, RaftHandle},
+        rpc::{Address, Request, Response},
+        store::{Snapshot, SnapshotFile},
+        test::{new_follower, new_leader_with_peer},
     };
 
     #[test]
@@ -313,4 +997,377 @@ mod tests {
             panic!()
         }
     }
+
+    #[test]
+    fn batches_proposals() {
+        let ((_rpc_rx, mut fsm_rx), mut node) = new_follower();
+        node.config.max_batch_bytes = 1024;
+        node.config.batch_timeout = Duration::from_millis(1);
+        let mut node = node.apply(Command::Timeout).unwrap();
+        for id in 1..=3 {
+            node = node
+                .apply(Command::ClientRequest { id: vec![id], req: Request::Propose(vec![id]) })
+                .unwrap();
+        }
+        match &node {
+            RaftHandle::Leader(leader) => assert_eq!(leader.log.next_index(), 1),
+            _ => panic!(),
+        }
+
+        std::thread::sleep(Duration::from_millis(2));
+        match node.apply(Command::Flush).unwrap() {
+            RaftHandle::Leader(leader) => {
+                assert_eq!(leader.log.next_index(), 4);
+                assert_eq!(leader.state.commit_index, 3);
+            }
+            _ => panic!(),
+        }
+
+        // every client is answered once its own entry is applied
+        for index in 1..=3 {
+            match fsm_rx.blocking_recv().unwrap() {
+                Instruction::Notify { id, index: notify_index, term } => {
+                    assert_eq!((id, notify_index, term), (vec![index as u8], index, 1))
+                }
+                _ => panic!(),
+            }
+        }
+    }
+
+    #[test]
+    fn sends_snapshot_to_lagging_follower() {
+        let ((mut rpc_rx, _fsm_rx), mut leader) = new_leader_with_peer();
+        let term = 1;
+        let snapshot = Snapshot {
+            last_index: 5,
+            last_term: term,
+            config: None,
+            data: vec![1; SNAPSHOT_CHUNK_SIZE + 3],
+        };
+        SnapshotFile::open(&leader.config.data_directory).unwrap().save(&snapshot).unwrap();
+        leader.log.compact(5, term).unwrap();
+        let mut next_chunk = || loop {
+            match rpc_rx.recv().now_or_never() {
+                Some(Some(msg)) => {
+                    if let Command::InstallSnapshot { offset, data, done, .. } = msg.command {
+                        assert_eq!(msg.to, Address::Peer(2));
+                        return Some((offset, data.len(), done));
+                    }
+                }
+                _ => return None,
+            }
+        };
+        let ack = |offset, done| Command::InstallSnapshotResponse {
+            node_id: 2,
+            term,
+            index: 5,
+            offset,
+            done,
+            success: true,
+        };
+
+        // the next chunk is only sent once the follower has the previous one
+        let node = leader.apply(Command::Tick).unwrap();
+        assert_eq!(next_chunk(), Some((0, SNAPSHOT_CHUNK_SIZE, false)));
+        assert_eq!(next_chunk(), None);
+
+        let node = node.apply(ack(SNAPSHOT_CHUNK_SIZE as u64, false)).unwrap();
+        assert_eq!(next_chunk(), Some((SNAPSHOT_CHUNK_SIZE as u64, 3, true)));
+
+        let node = node.apply(ack(0, true)).unwrap();
+        match node {
+            RaftHandle::Leader(leader) => match leader.role.progress.get(2) {
+                Some(NodeProgress::Probe(progress)) => assert_eq!(progress.next, 6),
+                _ => panic!(),
+            },
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn remove_node_through_joint_consensus() {
+        let ((mut rpc_rx, _fsm_rx), leader) = new_leader_with_peer();
+        let id = leader.id;
+        let term = 1;
+        let node = RaftHandle::Leader(Box::new(leader))
+            .apply(Command::ClientRequest { id: vec![1], req: Request::RemoveNode(2) })
+            .unwrap();
+
+        // the joint configuration needs the outgoing node to acknowledge it
+        let node = match node {
+            RaftHandle::Leader(leader) => {
+                assert!(leader.membership.config.is_joint());
+                assert_eq!(leader.state.commit_index, 0);
+                RaftHandle::Leader(leader)
+            }
+            _ => panic!(),
+        };
+
+        let node = node
+            .apply(Command::AppendResponse { node_id: 2, term, index: 1, success: true, conflict: None })
+            .unwrap();
+        match node {
+            RaftHandle::Leader(leader) => {
+                assert!(!leader.membership.config.is_joint());
+                assert_eq!(leader.membership.config.node_ids(), vec![id]);
+                assert_eq!(leader.state.commit_index, 2);
+                assert!(leader.role.progress.get(2).is_none());
+            }
+            _ => panic!(),
+        }
+
+        let msg = loop {
+            let msg = rpc_rx.blocking_recv().unwrap();
+            if let Command::ClientResponse { .. } = msg.command {
+                break msg;
+            }
+        };
+        assert_eq!(msg.to, Address::Client);
+        match msg.command {
+            Command::ClientResponse { id, res } => {
+                assert_eq!(id, vec![1]);
+                assert!(matches!(res, Ok(Response::Membership(voters)) if voters.len() == 1));
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn learner_does_not_block_commits() {
+        let ((_rpc_rx, _fsm_rx), node) = new_follower();
+        let learner = Node {
+            id: 2,
+            addr: "127.0.0.1:6670".parse().unwrap(),
+        };
+        let node = node
+            .apply(Command::Timeout)
+            .unwrap()
+            .apply(Command::ClientRequest { id: vec![1], req: Request::AddLearner(learner) })
+            .unwrap()
+            .apply(Command::ClientRequest { id: vec![2], req: Request::PromoteLearner(2) })
+            .unwrap();
+
+        // the learner was added without waiting on it, but can't vote until it catches up
+        let node = match node {
+            RaftHandle::Leader(leader) => {
+                assert_eq!(leader.state.commit_index, 1);
+                assert!(leader.membership.config.is_learner(2));
+                assert!(leader.role.progress.get(2).is_some());
+                RaftHandle::Leader(leader)
+            }
+            _ => panic!(),
+        };
+
+        let node = node
+            .apply(Command::AppendResponse { node_id: 2, term: 1, index: 1, success: true, conflict: None })
+            .unwrap()
+            .apply(Command::ClientRequest { id: vec![3], req: Request::PromoteLearner(2) })
+            .unwrap();
+        match node {
+            RaftHandle::Leader(leader) => {
+                assert!(leader.membership.config.is_joint());
+                assert!(leader.membership.config.is_voter(2));
+                assert!(!leader.membership.config.is_learner(2));
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn ignores_responses_from_earlier_terms() {
+        let ((_rpc_rx, _fsm_rx), leader) = new_leader_with_peer();
+        let node = RaftHandle::Leader(Box::new(leader))
+            .apply(Command::ClientRequest { id: vec![1], req: Request::Propose(vec![1]) })
+            .unwrap();
+        let index = match &node {
+            RaftHandle::Leader(leader) => leader.log.next_index() - 1,
+            _ => panic!(),
+        };
+
+        let node = node
+            .apply(Command::AppendResponse { node_id: 2, term: 0, index, success: true, conflict: None })
+            .unwrap();
+        match node {
+            RaftHandle::Leader(leader) => {
+                assert_eq!(leader.role.progress.get(2).unwrap().index(), 0);
+                assert_eq!(leader.state.commit_index, 0);
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn transfer_leadership_once_caught_up() {
+        let ((mut rpc_rx, _fsm_rx), leader) = new_leader_with_peer();
+        let term = 1;
+        let node = RaftHandle::Leader(Box::new(leader))
+            .apply(Command::ClientRequest { id: vec![1], req: Request::Propose(vec![1]) })
+            .unwrap()
+            .apply(Command::ClientRequest { id: vec![2], req: Request::TransferLeadership(2) })
+            .unwrap()
+            .apply(Command::ClientRequest { id: vec![3], req: Request::Propose(vec![2]) })
+            .unwrap();
+
+        // proposals are refused while the target catches up
+        let node = match node {
+            RaftHandle::Leader(leader) => {
+                assert_eq!(leader.log.next_index(), 2);
+                assert!(!leader.role.transfer.as_ref().unwrap().timeout_sent);
+                RaftHandle::Leader(leader)
+            }
+            _ => panic!(),
+        };
+
+        node.apply(Command::AppendResponse { node_id: 2, term, index: 1, success: true, conflict: None })
+            .unwrap();
+        let msg = loop {
+            let msg = rpc_rx.blocking_recv().unwrap();
+            if let Command::TimeoutNow { .. } = msg.command {
+                break msg;
+            }
+        };
+        assert_eq!(msg.to, Address::Peer(2));
+    }
+
+    #[test]
+    fn answers_transfer_when_leadership_is_lost() {
+        let ((mut rpc_rx, _fsm_rx), leader) = new_leader_with_peer();
+        // the target has an entry to catch up on, so isn't told to take over yet
+        let node = RaftHandle::Leader(Box::new(leader))
+            .apply(Command::ClientRequest { id: vec![2], req: Request::Propose(vec![1]) })
+            .unwrap()
+            .apply(Command::ClientRequest { id: vec![1], req: Request::TransferLeadership(2) })
+            .unwrap()
+            .apply(Command::VoteRequest { term: 2, candidate_id: 2, last_term: 1, last_index: 1, transfer: false })
+            .unwrap();
+        assert!(node.is_follower());
+
+        let res = loop {
+            match rpc_rx.blocking_recv().unwrap().command {
+                Command::ClientResponse { id, res } if id == vec![1] => break res,
+                _ => {}
+            }
+        };
+        assert!(matches!(res, Err(JosefineError::Internal { .. })));
+    }
+
+    #[test]
+    fn backtracks_by_term() {
+        let ((_rpc_rx, _fsm_rx), mut leader) = new_leader_with_peer();
+        for (index, term) in [(1, 1), (2, 1), (3, 1), (4, 3), (5, 3)] {
+            let entry_type = EntryType::Entry { data: vec![] };
+            leader.log.append(Entry { entry_type, term, index }).unwrap();
+        }
+
+        // we don't have the follower's term, so skip every entry it has for it
+        assert_eq!(leader.backtrack(Some(Conflict { term: 2, index: 2 })).unwrap(), 2);
+
+        // we share the follower's term, so resume after our last entry for it
+        let node = RaftHandle::Leader(Box::new(leader))
+            .apply(Command::AppendResponse {
+                node_id: 2,
+                term: 1,
+                index: 5,
+                success: false,
+                conflict: Some(Conflict { term: 1, index: 1 }),
+            })
+            .unwrap();
+        match node {
+            RaftHandle::Leader(leader) => assert_eq!(leader.role.progress.get(2).unwrap().next(), 4),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn steps_down_without_quorum() {
+        let ((_rpc_rx, _fsm_rx), mut leader) = new_leader_with_peer();
+        leader.config.election_timeout = Duration::from_millis(10);
+        let node = RaftHandle::Leader(Box::new(leader));
+
+        // the follower's response keeps us in charge
+        std::thread::sleep(Duration::from_millis(20));
+        let node = node
+            .apply(Command::HeartbeatResponse { term: 1, node_id: 2, seq: 0 })
+            .unwrap()
+            .apply(Command::Tick)
+            .unwrap();
+        assert!(node.is_leader());
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(node.apply(Command::Tick).unwrap().is_follower());
+    }
+
+    #[test]
+    fn follows_leader_of_later_term() {
+        let ((_rpc_rx, _fsm_rx), leader) = new_leader_with_peer();
+        let node = RaftHandle::Leader(Box::new(leader))
+            .apply(Command::Heartbeat { term: 2, leader_id: 2, seq: 1, leader_commit: 0 })
+            .unwrap();
+        match node {
+            RaftHandle::Follower(follower) => {
+                assert_eq!(follower.state.current_term, 2);
+                assert_eq!(follower.role.leader_id, Some(2));
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn read_waits_for_heartbeat_quorum() {
+        let ((_rpc_rx, mut fsm_rx), leader) = new_leader_with_peer();
+        let node = RaftHandle::Leader(Box::new(leader))
+            .apply(Command::ClientRequest { id: vec![1], req: Request::Query(vec![]) })
+            .unwrap()
+            // a response to an earlier heartbeat doesn't confirm the read
+            .apply(Command::AppendResponse { node_id: 2, term: 1, index: 1, success: true, conflict: None })
+            .unwrap()
+            .apply(Command::HeartbeatResponse { term: 1, node_id: 2, seq: 1 })
+            .unwrap();
+        match &node {
+            RaftHandle::Leader(leader) => assert_eq!(leader.role.reads.len(), 1),
+            _ => panic!(),
+        }
+
+        // nor does one from when an earlier term's leader used the same sequence number
+        let node = node.apply(Command::HeartbeatResponse { term: 0, node_id: 2, seq: 2 }).unwrap();
+        match &node {
+            RaftHandle::Leader(leader) => assert_eq!(leader.role.reads.len(), 1),
+            _ => panic!(),
+        }
+
+        node.apply(Command::HeartbeatResponse { term: 1, node_id: 2, seq: 2 }).unwrap();
+        let query = loop {
+            if let Instruction::Query { id, index, .. } = fsm_rx.blocking_recv().unwrap() {
+                break (id, index);
+            }
+        };
+        // the read waits on the no-op entry that confirms our commit index
+        assert_eq!(query, (vec![1], 1));
+    }
+
+    #[test]
+    fn lease_read_skips_heartbeat_round() {
+        let ((_rpc_rx, mut fsm_rx), mut leader) = new_leader_with_peer();
+        leader.config.read_mode = ReadMode::Lease;
+        let node = RaftHandle::Leader(Box::new(leader))
+            .apply(Command::ClientRequest { id: vec![1], req: Request::Propose(vec![1]) })
+            .unwrap()
+            .apply(Command::AppendResponse { node_id: 2, term: 1, index: 1, success: true, conflict: None })
+            .unwrap()
+            .apply(Command::HeartbeatResponse { term: 1, node_id: 2, seq: 1 })
+            .unwrap()
+            .apply(Command::ClientRequest { id: vec![2], req: Request::Query(vec![]) })
+            .unwrap();
+
+        match node {
+            RaftHandle::Leader(leader) => assert!(leader.role.reads.is_empty()),
+            _ => panic!(),
+        }
+        let query = loop {
+            if let Instruction::Query { id, index, .. } = fsm_rx.blocking_recv().unwrap() {
+                break (id, index);
+            }
+        };
+        assert_eq!(query, (vec![2], 1));
+    }
 }
@@ -22,6 +22,8 @@ extern crate slog_async;
 extern crate slog_term;
 
 use crate::raft::RaftHandle;
+use crate::status::StatusHandle;
+use crate::transport::{TcpTransport, Transport};
 
 use josefine_core::error::Result;
 use std::time::Duration;
@@ -30,12 +32,20 @@ use tokio::sync::oneshot;
 use tokio::sync::mpsc::UnboundedReceiver;
 
 mod candidate;
+mod clock;
+mod codec;
 mod election;
 pub mod error;
 mod follower;
+mod handshake;
+pub mod history;
+mod http;
 mod leader;
 mod log;
+pub mod metrics;
+pub mod membership;
 pub mod rpc;
+pub mod status;
 mod store;
 
 /// [Raft](raft.github.io) is a state machine for replicated consensus.
@@ -51,12 +61,15 @@ mod logger;
 mod progress;
 mod server;
 mod tcp;
+mod tls;
+pub mod transport;
 pub mod fsm;
+#[cfg(test)]
 mod test;
 pub mod client;
 
-pub struct JosefineRaft {
-    server: server::Server,
+pub struct JosefineRaft<T: Transport = TcpTransport> {
+    server: server::Server<T>,
 }
 
 impl JosefineRaft {
@@ -70,12 +83,26 @@ impl JosefineRaft {
         let config = config::RaftConfig::config(path.as_ref());
         Self::new(config)
     }
+}
+
+impl<T: Transport> JosefineRaft<T> {
+    /// Runs raft with peer messages carried by `transport` rather than TCP.
+    pub fn with_transport(config: config::RaftConfig, transport: T) -> Self {
+        JosefineRaft {
+            server: server::Server::with_transport(config, transport),
+        }
+    }
+
+    /// A live view of the node's status, which is available once it is running.
+    pub fn status(&self) -> StatusHandle {
+        self.server.status()
+    }
 
-    pub async fn run<T: 'static + fsm::Fsm>(self, fsm: T, client_rx: UnboundedReceiver<(Request, oneshot::Sender<Result<Response>>)>) -> Result<RaftHandle> {
+    pub async fn run<F: 'static + fsm::Fsm>(self, fsm: F, client_rx: UnboundedReceiver<(Request, oneshot::Sender<Result<Response>>)>) -> Result<RaftHandle> {
         self.server.run(None, fsm, client_rx).await
     }
 
-    pub async fn run_for<T: 'static + fsm::Fsm>(self, duration: Duration, fsm: T, client_rx: UnboundedReceiver<(Request, oneshot::Sender<Result<Response>>)>) -> Result<RaftHandle> {
+    pub async fn run_for<F: 'static + fsm::Fsm>(self, duration: Duration, fsm: F, client_rx: UnboundedReceiver<(Request, oneshot::Sender<Result<Response>>)>) -> Result<RaftHandle> {
         self.server.run(Some(duration), fsm, client_rx).await
     }
 }
@@ -1,15 +1,40 @@
+use std::convert::TryInto;
+
 use crate::{raft::Entry, store::Store};
 use crate::raft::{EntryType, LogIndex};
 use crate::raft::Term;
-use josefine_core::error::Result;
+use josefine_core::error::{JosefineError, Result};
+
+/// The version of the entry encoding, stored as the first byte of every record.
+///
+/// Each record is laid out as, with integers big-endian:
+///
+/// | version: u8 | term: u64 | index: u64 | type: u8 | length: u32 | crc: u32 | payload |
+///
+/// The CRC covers the term, index, type, length and payload. Data entries store their data as
+/// the payload, while the rarer config and command entries store JSON.
+const ENTRY_VERSION: u8 = 1;
+const HEADER_SIZE: usize = 26;
+
+const TYPE_ENTRY: u8 = 0;
+const TYPE_CONFIG: u8 = 1;
+const TYPE_COMMAND: u8 = 2;
 
-pub struct Log<T: Store + Default> {
+/// Records written before the binary encoding are JSON objects, so always start with this byte.
+/// They stay readable, and are replaced as the log is truncated and compacted.
+const LEGACY_JSON: u8 = b'{';
+
+pub struct Log<T: Store> {
     store: T,
+    /// The index of the last entry covered by the most recent snapshot.
+    snapshot_index: LogIndex,
+    /// The term of the last entry covered by the most recent snapshot.
+    snapshot_term: Term,
 }
 
 impl <T: Store + Default> Default for Log<T> {
     fn default() -> Self {
-        Log { store: T::default() }
+        Log::with_store(T::default())
     }
 }
 
@@ -17,17 +42,30 @@ impl <T: Store + Default> Log<T> {
     pub fn new() -> Self {
         Default::default()
     }
+}
+
+impl <T: Store> Log<T> {
+    pub fn with_store(store: T) -> Self {
+        Log { store, snapshot_index: 0, snapshot_term: 0 }
+    }
 
     pub fn check_term(&self, index: LogIndex, term: Term) -> bool {
-        if let Ok(Some(entry)) = self.get(index) {
-            if entry.term == term {
-                true
-            } else {
-                false
-            }
-        } else {
-            false
+        match self.term(index) {
+            Ok(Some(entry_term)) => entry_term == term,
+            _ => false,
+        }
+    }
+
+    /// The term of the entry at `index`, including the last entry covered by the snapshot.
+    pub fn term(&self, index: LogIndex) -> Result<Option<Term>> {
+        if index == self.snapshot_index {
+            return Ok(Some(self.snapshot_term));
         }
+        if index < self.snapshot_index {
+            return Ok(None);
+        }
+
+        Ok(self.get(index)?.map(|entry| entry.term))
     }
 
     pub fn get(&self, index: LogIndex) -> Result<Option<Entry>> {
@@ -46,13 +84,28 @@ impl <T: Store + Default> Log<T> {
         Ok(index)
     }
 
+    /// Appends several entries with a single write to the store.
+    pub fn append_all(&mut self, entries: Vec<Entry>) -> Result<LogIndex> {
+        let bytes = entries
+            .into_iter()
+            .map(Self::serialize)
+            .collect::<Result<Vec<_>>>()?;
+        self.store.append_all(bytes)
+    }
+
     pub fn get_range(&self, start: LogIndex, end: LogIndex) -> Result<Vec<Entry>> {
         let bytes = self.store.get_range(start, end)?;
         bytes.iter()
-            .map(|x| Self::deserialize(&x))
+            .map(|x| Self::deserialize(x))
             .collect()
     }
 
+    /// Discards every entry after `index`.
+    pub fn truncate(&mut self, index: LogIndex) -> Result<()> {
+        self.store.truncate(index)?;
+        Ok(())
+    }
+
     pub fn commit(&mut self, index: LogIndex) -> Result<()> {
         let entry = self.get(index)?.expect("Entry should never be null");
         self.store.commit(entry.index)
@@ -62,13 +115,127 @@ impl <T: Store + Default> Log<T> {
         self.store.next_index()
     }
 
+    pub fn snapshot_index(&self) -> LogIndex {
+        self.snapshot_index
+    }
+
+    pub fn snapshot_term(&self) -> Term {
+        self.snapshot_term
+    }
+
+    /// Discards the entries covered by a snapshot that includes everything up to `index`.
+    pub fn compact(&mut self, index: LogIndex, term: Term) -> Result<()> {
+        if index <= self.snapshot_index {
+            return Ok(());
+        }
+
+        self.store.compact(index)?;
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+        Ok(())
+    }
+
     fn serialize(entry: Entry) -> Result<Vec<u8>> {
-        let bytes = serde_json::to_vec(&entry)?;
+        let (entry_type, payload) = match entry.entry_type {
+            EntryType::Entry { data } => (TYPE_ENTRY, data),
+            EntryType::Config { config } => (TYPE_CONFIG, serde_json::to_vec(&config)?),
+            EntryType::Command { command } => (TYPE_COMMAND, serde_json::to_vec(&command)?),
+        };
+
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + payload.len());
+        bytes.push(ENTRY_VERSION);
+        bytes.extend_from_slice(&entry.term.to_be_bytes());
+        bytes.extend_from_slice(&entry.index.to_be_bytes());
+        bytes.push(entry_type);
+        bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        let crc = Self::checksum(&bytes[1..22], &payload);
+        bytes.extend_from_slice(&crc.to_be_bytes());
+        bytes.extend_from_slice(&payload);
         Ok(bytes)
     }
 
-    fn deserialize(bytes: &[u8]) -> Result<Entry> { 
-        let entry = serde_json::from_slice(bytes)?;
-        Ok(entry)
+    fn deserialize(bytes: &[u8]) -> Result<Entry> {
+        match bytes.first() {
+            Some(&ENTRY_VERSION) => {}
+            Some(&LEGACY_JSON) => return Ok(serde_json::from_slice(bytes)?),
+            version => {
+                return Err(JosefineError::Internal {
+                    error_msg: format!("unknown raft log entry version {:?}", version),
+                })
+            }
+        }
+
+        let corrupt = || JosefineError::Internal {
+            error_msg: "corrupt raft log entry".to_string(),
+        };
+        if bytes.len() < HEADER_SIZE {
+            return Err(corrupt());
+        }
+        let u64_at = |pos: usize| u64::from_be_bytes(bytes[pos..pos + 8].try_into().unwrap());
+        let u32_at = |pos: usize| u32::from_be_bytes(bytes[pos..pos + 4].try_into().unwrap());
+        let (term, index, entry_type) = (u64_at(1), u64_at(9), bytes[17]);
+        let (len, crc) = (u32_at(18) as usize, u32_at(22));
+        let payload = &bytes[HEADER_SIZE..];
+        if payload.len() != len || Self::checksum(&bytes[1..22], payload) != crc {
+            return Err(corrupt());
+        }
+
+        let entry_type = match entry_type {
+            TYPE_ENTRY => EntryType::Entry { data: payload.to_vec() },
+            TYPE_CONFIG => EntryType::Config { config: serde_json::from_slice(payload)? },
+            TYPE_COMMAND => EntryType::Command { command: serde_json::from_slice(payload)? },
+            _ => return Err(corrupt()),
+        };
+        Ok(Entry { entry_type, term, index })
+    }
+
+    fn checksum(header: &[u8], payload: &[u8]) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(header);
+        hasher.update(payload);
+        hasher.finalize()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::membership::ClusterConfig;
+    use crate::raft::{Command, Entry, EntryType};
+    use crate::store::{MemoryStore, Store};
+
+    use super::Log;
+
+    #[test]
+    fn encodes_every_entry_type() {
+        let entries = vec![
+            EntryType::Entry { data: vec![1, 2, 3] },
+            EntryType::Config { config: ClusterConfig::new(vec![]) },
+            EntryType::Command { command: Command::Noop },
+        ];
+        for (index, entry_type) in (1..).zip(entries) {
+            let entry = Entry { entry_type, term: 2, index };
+            let bytes = Log::<MemoryStore>::serialize(entry.clone()).unwrap();
+            assert_eq!(Log::<MemoryStore>::deserialize(&bytes).unwrap(), entry);
+        }
+
+        let mut bytes = Log::<MemoryStore>::serialize(Entry {
+            entry_type: EntryType::Entry { data: vec![1, 2, 3] },
+            term: 2,
+            index: 1,
+        })
+        .unwrap();
+        *bytes.last_mut().unwrap() = 4;
+        assert!(Log::<MemoryStore>::deserialize(&bytes).is_err());
+    }
+
+    #[test]
+    fn reads_legacy_json_entries() {
+        let entry = Entry { entry_type: EntryType::Entry { data: vec![1] }, term: 1, index: 1 };
+        let mut log: Log<MemoryStore> = Log::new();
+        log.store.append(serde_json::to_vec(&entry).unwrap()).unwrap();
+        log.append(Entry { index: 2, ..entry.clone() }).unwrap();
+
+        let entries = log.get_range(0, 2).unwrap();
+        assert_eq!(entries, vec![entry.clone(), Entry { index: 2, ..entry }]);
     }
 }
@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+
+use crate::raft::{LogIndex, Node, NodeId};
+
+/// The members of the cluster. While a change to the voters is in progress the cluster is in
+/// joint consensus, and decisions require a majority of both the incoming and outgoing voters.
+/// Learners receive the log but never count toward a quorum.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ClusterConfig {
+    /// The voters of the configuration, or of the configuration being transitioned to.
+    pub voters: Vec<Node>,
+    /// While in joint consensus, the voters of the configuration being transitioned from.
+    pub outgoing: Vec<Node>,
+    /// Non-voting members that are catching up before they can be promoted.
+    #[serde(default)]
+    pub learners: Vec<Node>,
+}
+
+impl ClusterConfig {
+    pub fn new(voters: Vec<Node>) -> ClusterConfig {
+        ClusterConfig {
+            voters,
+            outgoing: Vec::new(),
+            learners: Vec::new(),
+        }
+    }
+
+    pub fn is_joint(&self) -> bool {
+        !self.outgoing.is_empty()
+    }
+
+    /// Every node that is part of either configuration, including learners.
+    pub fn nodes(&self) -> Vec<Node> {
+        let mut nodes = self.voters.clone();
+        for node in self.outgoing.iter().chain(self.learners.iter()) {
+            if !nodes.iter().any(|n| n.id == node.id) {
+                nodes.push(*node);
+            }
+        }
+        nodes
+    }
+
+    pub fn node_ids(&self) -> Vec<NodeId> {
+        self.nodes().iter().map(|node| node.id).collect()
+    }
+
+    /// Whether the node is a voter or a learner.
+    pub fn contains(&self, id: NodeId) -> bool {
+        self.is_voter(id) || self.is_learner(id)
+    }
+
+    /// Whether the node votes in either configuration.
+    pub fn is_voter(&self, id: NodeId) -> bool {
+        self.voters.iter().chain(self.outgoing.iter()).any(|node| node.id == id)
+    }
+
+    pub fn is_learner(&self, id: NodeId) -> bool {
+        self.learners.iter().any(|node| node.id == id)
+    }
+
+    /// Moves to a new set of voters and learners. Learners don't affect quorums, so the change
+    /// only needs joint consensus if the voters change.
+    pub fn change(&self, voters: Vec<Node>, learners: Vec<Node>) -> ClusterConfig {
+        let same_voters = voters.len() == self.voters.len()
+            && voters.iter().all(|node| self.voters.contains(node));
+        ClusterConfig {
+            outgoing: if same_voters { Vec::new() } else { self.voters.clone() },
+            voters,
+            learners,
+        }
+    }
+
+    /// Completes a transition, leaving only the new voters.
+    pub fn leave_joint(&self) -> ClusterConfig {
+        ClusterConfig {
+            voters: self.voters.clone(),
+            outgoing: Vec::new(),
+            learners: self.learners.clone(),
+        }
+    }
+
+    /// Whether the nodes for which `granted` returns true form a quorum.
+    pub fn has_quorum<F: Fn(NodeId) -> bool>(&self, granted: F) -> bool {
+        let majority = |voters: &[Node]| {
+            let count = voters.iter().filter(|node| granted(node.id)).count();
+            count > voters.len() / 2
+        };
+
+        majority(&self.voters) && (self.outgoing.is_empty() || majority(&self.outgoing))
+    }
+
+    /// The highest index that has been replicated to a quorum, given each node's match index.
+    pub fn committed_index(&self, match_indices: &HashMap<NodeId, LogIndex>) -> LogIndex {
+        let committed = |voters: &[Node]| {
+            let mut indices: Vec<LogIndex> = voters
+                .iter()
+                .map(|node| *match_indices.get(&node.id).unwrap_or(&0))
+                .collect();
+            indices.sort_by(|a, b| b.cmp(a));
+            indices.get(indices.len() / 2).cloned().unwrap_or(0)
+        };
+
+        if self.outgoing.is_empty() {
+            committed(&self.voters)
+        } else {
+            std::cmp::min(committed(&self.voters), committed(&self.outgoing))
+        }
+    }
+}
+
+/// Tracks the configuration in effect for this node. A configuration takes effect as soon as its
+/// entry is appended to the log, so the previous configuration is kept in case that entry is
+/// discarded. Only one change can be uncommitted at a time, so one previous configuration is
+/// enough.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Membership {
+    /// The configuration from the most recent config entry in the log.
+    pub config: ClusterConfig,
+    /// The index of the entry `config` came from.
+    pub index: LogIndex,
+    /// The configuration that was in effect before `config`.
+    pub previous: ClusterConfig,
+}
+
+impl Membership {
+    pub fn new(config: ClusterConfig) -> Membership {
+        Membership {
+            previous: config.clone(),
+            config,
+            index: 0,
+        }
+    }
+
+    /// Switches to the configuration from the entry at `index`.
+    pub fn set(&mut self, config: ClusterConfig, index: LogIndex) {
+        if index <= self.index {
+            return;
+        }
+
+        self.previous = std::mem::replace(&mut self.config, config);
+        self.index = index;
+    }
+
+    /// Reverts the latest configuration if its entry has been discarded by truncating the log
+    /// after `index`.
+    pub fn truncate(&mut self, index: LogIndex) {
+        if self.index > index {
+            self.config = self.previous.clone();
+            self.index = 0;
+        }
+    }
+
+    /// Every other node in the cluster.
+    pub fn peers(&self, id: NodeId) -> Vec<Node> {
+        self.config
+            .nodes()
+            .into_iter()
+            .filter(|node| node.id != id)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+
+    use super::ClusterConfig;
+    use crate::raft::{Node, NodeId};
+
+    fn nodes(ids: &[NodeId]) -> Vec<Node> {
+        ids.iter()
+            .map(|id| Node {
+                id: *id,
+                addr: SocketAddr::from(([127, 0, 0, 1], 6000 + *id as u16)),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn joint_quorum_needs_both_majorities() {
+        let config = ClusterConfig::new(nodes(&[1, 2, 3])).change(nodes(&[3, 4, 5]), vec![]);
+        assert!(config.is_joint());
+        assert!(!config.has_quorum(|id| id == 1 || id == 2 || id == 3));
+        assert!(!config.has_quorum(|id| id == 4 || id == 5));
+        assert!(config.has_quorum(|id| id == 1 || id == 3 || id == 4));
+        assert!(!config.leave_joint().is_joint());
+    }
+
+    #[test]
+    fn joint_committed_index() {
+        let config = ClusterConfig::new(nodes(&[1, 2, 3])).change(nodes(&[3, 4, 5]), vec![]);
+        let indices: HashMap<NodeId, u64> = vec![(1, 10), (2, 10), (3, 5), (4, 7), (5, 1)].into_iter().collect();
+        assert_eq!(config.committed_index(&indices), 5);
+        assert_eq!(config.leave_joint().committed_index(&indices), 5);
+        assert_eq!(ClusterConfig::new(nodes(&[1, 2, 3])).committed_index(&indices), 10);
+    }
+
+    #[test]
+    fn learners_are_not_counted() {
+        let config = ClusterConfig::new(nodes(&[1, 2, 3])).change(nodes(&[1, 2, 3]), nodes(&[4, 5]));
+        assert!(!config.is_joint());
+        assert!(config.is_learner(4) && !config.is_voter(4));
+        assert_eq!(config.node_ids(), vec![1, 2, 3, 4, 5]);
+        assert!(!config.has_quorum(|id| id == 1 || id == 4 || id == 5));
+
+        let indices: HashMap<NodeId, u64> = vec![(1, 10), (2, 1), (3, 1), (4, 10), (5, 10)].into_iter().collect();
+        assert_eq!(config.committed_index(&indices), 1);
+    }
+}
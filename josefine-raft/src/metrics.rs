@@ -0,0 +1,184 @@
+//! Prometheus metrics describing the health of a node, served over HTTP at `/metrics` when
+//! `RaftConfig::metrics_addr` is set.
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+use crate::raft::{RaftHandle, RaftRole, Role};
+use crate::transport::DroppedMessages;
+
+/// The metrics of a single node. Each node has its own registry, so several can run in one
+/// process. Clones share the same metrics.
+#[derive(Clone, Debug)]
+pub struct Metrics {
+    registry: Registry,
+    pub(crate) term: IntGauge,
+    pub(crate) role: IntGaugeVec,
+    pub(crate) commit_index: IntGauge,
+    pub(crate) applied_index: IntGauge,
+    /// The highest index the leader knows each peer has, by peer.
+    pub(crate) match_index: IntGaugeVec,
+    /// How many entries each peer is behind the leader's log, by peer.
+    pub(crate) lag: IntGaugeVec,
+    pub(crate) elections: IntCounter,
+    /// The time from the leader sending entries to a peer to the peer acknowledging them.
+    pub(crate) append_latency: Histogram,
+    pub(crate) dropped_messages: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let registry = Registry::new();
+        let term = IntGauge::new("raft_term", "The current term.").unwrap();
+        let role = IntGaugeVec::new(Opts::new("raft_role", "Set to 1 for the node's current role."), &["role"]).unwrap();
+        let commit_index = IntGauge::new("raft_commit_index", "The highest log index known to be committed.").unwrap();
+        let applied_index =
+            IntGauge::new("raft_applied_index", "The highest log index applied to the state machine.").unwrap();
+        let match_index = IntGaugeVec::new(
+            Opts::new("raft_peer_match_index", "The highest log index replicated to each peer, on the leader."),
+            &["peer"],
+        )
+        .unwrap();
+        let lag = IntGaugeVec::new(
+            Opts::new("raft_peer_lag", "The number of entries each peer is behind the leader's log."),
+            &["peer"],
+        )
+        .unwrap();
+        let elections = IntCounter::new("raft_elections_total", "The number of elections this node has started.").unwrap();
+        let append_latency = Histogram::with_opts(
+            HistogramOpts::new(
+                "raft_append_latency_seconds",
+                "The time from sending entries to a peer to it acknowledging them.",
+            )
+            .buckets(exponential_buckets(0.0005, 2.0, 14).unwrap()),
+        )
+        .unwrap();
+        let dropped_messages = IntCounterVec::new(
+            Opts::new("raft_dropped_messages_total", "Messages to each peer dropped by the transport."),
+            &["peer"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(term.clone())).unwrap();
+        registry.register(Box::new(role.clone())).unwrap();
+        registry.register(Box::new(commit_index.clone())).unwrap();
+        registry.register(Box::new(applied_index.clone())).unwrap();
+        registry.register(Box::new(match_index.clone())).unwrap();
+        registry.register(Box::new(lag.clone())).unwrap();
+        registry.register(Box::new(elections.clone())).unwrap();
+        registry.register(Box::new(append_latency.clone())).unwrap();
+        registry.register(Box::new(dropped_messages.clone())).unwrap();
+
+        Metrics {
+            registry,
+            term,
+            role,
+            commit_index,
+            applied_index,
+            match_index,
+            lag,
+            elections,
+            append_latency,
+            dropped_messages,
+        }
+    }
+
+    /// Records the state of the node after a command has been applied to it.
+    pub(crate) fn observe(&self, raft: &RaftHandle) {
+        let (term, commit_index, role) = match raft {
+            RaftHandle::Follower(raft) => (raft.state.current_term, raft.state.commit_index, raft.role.role()),
+            RaftHandle::Candidate(raft) => (raft.state.current_term, raft.state.commit_index, raft.role.role()),
+            RaftHandle::Leader(raft) => (raft.state.current_term, raft.state.commit_index, raft.role.role()),
+        };
+        self.term.set(term as i64);
+        self.commit_index.set(commit_index as i64);
+        for (name, current) in [
+            ("follower", matches!(role, RaftRole::Follower)),
+            ("candidate", matches!(role, RaftRole::Candidate)),
+            ("leader", matches!(role, RaftRole::Leader)),
+        ]
+        .iter()
+        {
+            self.role.with_label_values(&[name]).set(*current as i64);
+        }
+
+    }
+
+    /// Records how far along replication to each peer is. Rebuilding the per-peer gauges is too
+    /// costly to do for every command, so this is only done as time passes.
+    pub(crate) fn observe_peers(&self, raft: &RaftHandle) {
+        // only the leader knows how far along its peers are
+        self.match_index.reset();
+        self.lag.reset();
+        if let RaftHandle::Leader(raft) = raft {
+            let last_index = raft.log.next_index() - 1;
+            for node in raft.peers() {
+                if let Some(progress) = raft.role.progress.get(node.id) {
+                    let peer = node.id.to_string();
+                    self.match_index.with_label_values(&[&peer]).set(progress.index() as i64);
+                    self.lag
+                        .with_label_values(&[&peer])
+                        .set(last_index.saturating_sub(progress.index()) as i64);
+                }
+            }
+        }
+    }
+
+    /// Catches the dropped message counters up with the transport's counts.
+    pub fn record_dropped(&self, dropped: &DroppedMessages) {
+        for (node_id, count) in dropped.counts() {
+            let counter = self.dropped_messages.with_label_values(&[&node_id.to_string()]);
+            counter.inc_by(count.saturating_sub(counter.get()));
+        }
+    }
+
+    /// The metrics in the Prometheus text format.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .expect("metrics can always be encoded");
+        buf
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::raft::{Apply, Command, RaftHandle};
+    use crate::rpc::Request;
+    use crate::test::{new_follower, new_leader_with_peer};
+
+    #[test]
+    fn observes_role_and_term() {
+        let ((_rpc_rx, _fsm_rx), node) = new_follower();
+        let metrics = node.metrics.clone();
+        let node = RaftHandle::Follower(node).apply(Command::Timeout).unwrap();
+        assert!(node.is_leader());
+
+        let text = String::from_utf8(metrics.encode()).unwrap();
+        assert!(text.contains("raft_role{role=\"leader\"} 1"));
+        assert!(text.contains("raft_elections_total 1"));
+        assert!(text.contains("raft_term 1"));
+    }
+
+    #[test]
+    fn observes_peers_on_tick() {
+        let ((_rpc_rx, _fsm_rx), leader) = new_leader_with_peer();
+        let metrics = leader.metrics.clone();
+        let node = RaftHandle::Leader(Box::new(leader))
+            .apply(Command::ClientRequest { id: vec![1], req: Request::Propose(vec![1]) })
+            .unwrap();
+        assert!(!String::from_utf8(metrics.encode()).unwrap().contains("raft_peer_lag{peer=\"2\"}"));
+
+        node.apply(Command::Tick).unwrap();
+        let text = String::from_utf8(metrics.encode()).unwrap();
+        assert!(text.contains("raft_peer_lag{peer=\"2\"}"));
+    }
+}
@@ -1,21 +1,34 @@
-use std::{collections::{HashMap, VecDeque}, convert::TryInto};
+use std::{collections::{HashMap, VecDeque}, time::{Duration, Instant}};
 
+use crate::clock;
+use crate::membership::ClusterConfig;
 use crate::raft::{LogIndex, NodeId};
+use crate::transport::ConnectionState;
 
 #[derive(Debug)]
 pub struct ReplicationProgress {
     progress: HashMap<NodeId, NodeProgress>,
+    /// When each node last responded to us.
+    last_response: HashMap<NodeId, Instant>,
+    /// The transport's connection to each node, which is assumed to be up until we hear otherwise.
+    connections: HashMap<NodeId, ConnectionState>,
+    /// The number of append messages that may be unacknowledged by a node at once.
+    max_inflight: usize,
 }
 
 impl ReplicationProgress {
-    pub fn new(nodes: Vec<NodeId>) -> ReplicationProgress {
+    /// Tracks replication to each of the nodes, starting by probing at `next`.
+    pub fn new(nodes: Vec<NodeId>, next: LogIndex, max_inflight: usize) -> ReplicationProgress {
         assert!(!nodes.is_empty());
-        
+
         let mut progress = HashMap::new();
+        let mut last_response = HashMap::new();
         for node_id in nodes {
-            progress.insert(node_id, NodeProgress::Probe(Progress::new(node_id)));
+            progress.insert(node_id, NodeProgress::new(node_id, next, max_inflight));
+            // every node gets a full timeout to respond to a new leader
+            last_response.insert(node_id, clock::now());
         }
-        ReplicationProgress { progress }
+        ReplicationProgress { progress, last_response, connections: HashMap::new(), max_inflight }
     }
 
     pub fn get(&self, node_id: NodeId) -> Option<&NodeProgress> {
@@ -26,34 +39,136 @@ impl ReplicationProgress {
         self.progress.get_mut(&node_id)
     }
 
-    
     pub fn remove(&mut self, node_id: NodeId) -> Option<NodeProgress> {
+        self.last_response.remove(&node_id);
+        self.connections.remove(&node_id);
         self.progress.remove(&node_id)
     }
-    
-    pub fn insert(&mut self, node_id: NodeId) {
-        self.progress
-            .insert(node_id, NodeProgress::Probe(Progress::new(node_id)));
+    pub fn insert(&mut self, node_id: NodeId, next: LogIndex) {
+        self.progress.insert(node_id, NodeProgress::new(node_id, next, self.max_inflight));
+        self.last_response.insert(node_id, clock::now());
+    }
+
+    /// The state of the transport's connection to the node.
+    pub fn connection(&self, node_id: NodeId) -> ConnectionState {
+        self.connections.get(&node_id).copied().unwrap_or(ConnectionState::Connected)
+    }
+
+    /// Records a change in the transport's connection to the node. Anything sent while it was
+    /// disconnected was lost, so once it reconnects we probe again from its match index.
+    pub fn connection_changed(&mut self, node_id: NodeId, state: ConnectionState) {
+        if !self.progress.contains_key(&node_id) {
+            return;
+        }
+        let previous = self.connections.insert(node_id, state).unwrap_or(ConnectionState::Connected);
+        if state == ConnectionState::Connected && previous != ConnectionState::Connected {
+            let node = self.progress.remove(&node_id).expect("the node does not exist");
+            self.progress.insert(node_id, node.reconnect());
+        }
+    }
+
+    /// Records that the node has responded to us.
+    pub fn responded(&mut self, node_id: NodeId) {
+        if let Some(time) = self.last_response.get_mut(&node_id) {
+            *time = clock::now();
+        }
+    }
+
+    /// Whether the node has responded within the timeout.
+    pub fn responded_within(&self, node_id: NodeId, timeout: Duration) -> bool {
+        self.last_response
+            .get(&node_id)
+            .is_some_and(|time| clock::elapsed(*time) <= timeout)
+    }
+
+    /// Whether a quorum of the configuration, counting `id` itself, has responded within the
+    /// timeout.
+    pub fn quorum_active(&self, config: &ClusterConfig, id: NodeId, timeout: Duration) -> bool {
+        config.has_quorum(|node_id| node_id == id || self.responded_within(node_id, timeout))
     }
 
     pub fn advance(&mut self, node_id: NodeId, index: LogIndex) {
-        let node = self.remove(node_id).expect("the node does not exist");
+        let node = self.progress.remove(&node_id).expect("the node does not exist");
         let node = node.advance(index);
         self.progress.insert(node_id, node);
     }
 
-    pub fn committed_index(&self) -> LogIndex {
-        let mut indices = Vec::new();
-        for progress in self.progress.values() {
-            match progress {
-                NodeProgress::Probe(pr) => indices.push(pr.index),
-                NodeProgress::Replicate(pr) => indices.push(pr.index),
-                _ => panic!()
+    /// The node rejected the entries following `rejected`, so go back to probing from `next`.
+    pub fn reject(&mut self, node_id: NodeId, rejected: LogIndex, next: LogIndex) {
+        let node = self.progress.remove(&node_id).expect("the node does not exist");
+        self.progress.insert(node_id, node.reject(rejected, next));
+    }
+
+    /// Records that entries up to `index` were sent to the node.
+    pub fn sent(&mut self, node_id: NodeId, index: LogIndex) {
+        match self.progress.get_mut(&node_id) {
+            Some(NodeProgress::Probe(prog)) => prog.pause(),
+            Some(NodeProgress::Replicate(prog)) => prog.send(index),
+            _ => {}
+        }
+    }
+
+    /// The node is still responding, so let another message through in case an earlier one was
+    /// lost: a paused probe is resumed and appends that have gone unacknowledged for longer than
+    /// `timeout` are given up on. Once every append that was never acknowledged is given up on, we
+    /// probe again from the match index so the lost entries are resent.
+    pub fn resume(&mut self, node_id: NodeId, timeout: Duration) {
+        let lost = match self.progress.get_mut(&node_id) {
+            Some(NodeProgress::Probe(prog)) => {
+                prog.resume();
+                false
+            }
+            Some(NodeProgress::Replicate(prog)) => {
+                prog.expire(timeout);
+                prog.state.inflight.is_empty() && prog.next > prog.index + 1
             }
+            _ => false,
+        };
+
+        if lost {
+            if let Some(NodeProgress::Replicate(prog)) = self.progress.remove(&node_id) {
+                let mut prog = Progress::<Probe>::from(prog);
+                prog.next = prog.index + 1;
+                self.progress.insert(node_id, NodeProgress::Probe(prog));
+            }
+        }
+    }
+
+    /// Marks that a snapshot up to `index` is being sent to the node, halting replication to it.
+    pub fn snapshot(&mut self, node_id: NodeId, index: LogIndex) {
+        let node = self.progress.remove(&node_id).expect("the node does not exist");
+        self.progress.insert(node_id, node.snapshot(index));
+    }
+
+    /// The node has received the pending snapshot's data up to `offset`, so the next chunk can
+    /// be sent from there.
+    pub fn snapshot_acked(&mut self, node_id: NodeId, offset: u64) {
+        if let Some(NodeProgress::Snapshot(prog)) = self.progress.get_mut(&node_id) {
+            prog.state.offset = offset;
+            prog.state.sent = Some(clock::now());
         }
+    }
+
+    /// The node has installed the snapshot up to `index`, so replication can resume from there.
+    pub fn snapshot_done(&mut self, node_id: NodeId, index: LogIndex) {
+        let node = self.progress.remove(&node_id).expect("the node does not exist");
+        self.progress.insert(node_id, node.snapshot_done(index));
+    }
 
-        indices.sort_by(|a, b| b.cmp(a));
-        indices[indices.len() / 2]
+    /// The node could not install the snapshot, so it will need to be sent again.
+    pub fn snapshot_failed(&mut self, node_id: NodeId) {
+        let node = self.progress.remove(&node_id).expect("the node does not exist");
+        self.progress.insert(node_id, node.snapshot_failed());
+    }
+
+    /// The highest index replicated to a quorum of the given configuration.
+    pub fn committed_index(&self, config: &ClusterConfig) -> LogIndex {
+        let indices = self
+            .progress
+            .iter()
+            .map(|(node_id, progress)| (*node_id, progress.index()))
+            .collect();
+        config.committed_index(&indices)
     }
 }
 
@@ -65,8 +180,8 @@ pub enum NodeProgress {
 }
 
 impl NodeProgress {
-    pub fn new(node_id: NodeId) -> NodeProgress {
-        NodeProgress::Probe(Progress::new(node_id))
+    pub fn new(node_id: NodeId, next: LogIndex, max_inflight: usize) -> NodeProgress {
+        NodeProgress::Probe(Progress::new(node_id, next, max_inflight))
     }
 
     /// Advance the progress to the provided index.
@@ -76,17 +191,94 @@ impl NodeProgress {
                 if prog.increment(idx) {
                      Self::Replicate(Progress::from(prog))
                 } else {
+                    prog.resume();
                     Self::Probe(prog)
                 }
             },
-            NodeProgress::Replicate(mut prog) => { 
-                if prog.increment(idx)  {
-                    Self::Replicate(prog)
-                } else {
-                    Self::Probe(Progress::from(prog))
-                }
+            NodeProgress::Replicate(mut prog) => {
+                // responses can arrive out of order, so an older one doesn't tell us anything
+                prog.increment(idx);
+                prog.acknowledge(idx);
+                Self::Replicate(prog)
             },
-            _ => panic!()
+            // responses to appends sent before the snapshot don't tell us anything new
+            NodeProgress::Snapshot(prog) => Self::Snapshot(prog),
+        }
+    }
+
+    /// The entries following `rejected` didn't match the node's log. Unless the rejection is stale,
+    /// resume probing from `next`.
+    pub fn reject(self, rejected: LogIndex, next: LogIndex) -> Self {
+        // anything up to the match index was already accepted, so the rejection is stale
+        if rejected <= self.index() {
+            return self;
+        }
+
+        let mut prog = match self {
+            NodeProgress::Probe(prog) => prog,
+            NodeProgress::Replicate(prog) => Progress::from(prog),
+            prog => return prog,
+        };
+        prog.next = std::cmp::max(next, prog.index + 1);
+        prog.resume();
+        NodeProgress::Probe(prog)
+    }
+
+    /// The node's connection was re-established, so start probing from after its match index.
+    pub fn reconnect(self) -> Self {
+        let mut prog = match self {
+            NodeProgress::Probe(prog) => prog,
+            NodeProgress::Replicate(prog) => Progress::from(prog),
+            NodeProgress::Snapshot(prog) => Progress::from(prog),
+        };
+        prog.next = prog.index + 1;
+        prog.resume();
+        NodeProgress::Probe(prog)
+    }
+
+    /// Start sending a snapshot up to the provided index. Sending the same snapshot again carries
+    /// on from what the node has already acknowledged.
+    pub fn snapshot(self, idx: LogIndex) -> Self {
+        let offset = match &self {
+            NodeProgress::Snapshot(prog) if prog.state.pending == Some(idx) => prog.state.offset,
+            _ => 0,
+        };
+        let (node_id, active, index, next, max_inflight) = match self {
+            NodeProgress::Probe(prog) => (prog.node_id, prog.active, prog.index, prog.next, prog.max_inflight),
+            NodeProgress::Replicate(prog) => (prog.node_id, prog.active, prog.index, prog.next, prog.max_inflight),
+            NodeProgress::Snapshot(prog) => (prog.node_id, prog.active, prog.index, prog.next, prog.max_inflight),
+        };
+
+        NodeProgress::Snapshot(Progress {
+            node_id,
+            state: Snapshot { pending: Some(idx), offset, sent: Some(clock::now()) },
+            active,
+            index,
+            next,
+            max_inflight,
+        })
+    }
+
+    /// The snapshot up to the provided index was installed, resume probing after it.
+    pub fn snapshot_done(self, idx: LogIndex) -> Self {
+        match self {
+            NodeProgress::Snapshot(prog) => {
+                let mut prog = Progress::<Probe>::from(prog);
+                prog.increment(idx);
+                NodeProgress::Probe(prog)
+            }
+            prog => prog,
+        }
+    }
+
+    /// The snapshot was not installed, fall back to probing.
+    pub fn snapshot_failed(self) -> Self {
+        match self {
+            NodeProgress::Snapshot(mut prog) => {
+                prog.snapshot_fail();
+                NodeProgress::Probe(Progress::from(prog))
+            }
+            prog => prog,
         }
     }
 
@@ -105,14 +297,20 @@ pub fn index(&self) -> LogIndex{
             NodeProgress::Snapshot(prog) => prog.index,
         }
     }
+
+    pub fn next(&self) -> LogIndex {
+        match self {
+            NodeProgress::Probe(prog) => prog.next,
+            NodeProgress::Replicate(prog) => prog.next,
+            NodeProgress::Snapshot(prog) => prog.next,
+        }
+    }
 }
 
 pub trait ProgressState {
     fn reset(&mut self);
 }
 
-pub const MAX_INFLIGHT: u64 = 5;
-
 #[derive(Debug)]
 pub struct Progress<T: ProgressState> {
     pub node_id: NodeId,
@@ -120,6 +318,8 @@ pub struct Progress<T: ProgressState> {
     pub active: bool,
     pub index: LogIndex,
     pub next: LogIndex,
+    /// The number of append messages that may be unacknowledged at once while replicating.
+    pub max_inflight: usize,
 }
 
 impl<T: ProgressState> Progress<T> {
@@ -156,19 +356,29 @@ impl ProgressState for Probe {
 }
 
 impl Progress<Probe> {
-    fn new(node_id: NodeId) -> Progress<Probe> {
+    fn new(node_id: NodeId, next: LogIndex, max_inflight: usize) -> Progress<Probe> {
         Progress {
             node_id,
             state: Probe { paused: false },
             active: false,
             index: 0,
-            next: 0,
+            next,
+            max_inflight,
         }
     }
 
     fn is_active(&self) -> bool {
         !self.state.paused
     }
+
+    /// Stops probing until the node responds, so only one probe is in flight at a time.
+    pub fn pause(&mut self) {
+        self.state.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.state.paused = false;
+    }
 }
 
 impl From<Progress<Replicate>> for Progress<Probe> {
@@ -179,22 +389,28 @@ impl From<Progress<Replicate>> for Progress<Probe> {
             active: progress.active,
             index: progress.index,
             next: progress.next,
+            max_inflight: progress.max_inflight,
         }
     }
 }
 
-#[allow(dead_code)]
 #[derive(Debug, Copy, Clone)]
 pub struct Snapshot {
     /// Current index of the pending snapshot for this progress.
     /// If there is a pending snapshot, replication progress is halted
     /// until the snapshot is complete.
     pub pending: Option<LogIndex>,
+    /// How much of the pending snapshot's data the node has acknowledged.
+    pub offset: u64,
+    /// When the latest chunk of the pending snapshot was sent.
+    pub sent: Option<Instant>,
 }
 
 impl ProgressState for Snapshot {
     fn reset(&mut self) {
         self.pending = None;
+        self.offset = 0;
+        self.sent = None;
     }
 }
 
@@ -203,14 +419,35 @@ impl Progress<Snapshot> {
         self.active
     }
 
-    fn _snapshot_fail(&mut self) {
-        self.state.pending = None;
+    /// Whether the latest chunk of the pending snapshot has gone unacknowledged for longer than
+    /// `timeout`.
+    pub fn is_expired(&self, timeout: Duration) -> bool {
+        self.state.sent.is_none_or(|sent| clock::elapsed(sent) > timeout)
+    }
+
+    fn snapshot_fail(&mut self) {
+        self.state.reset();
+    }
+}
+
+impl From<Progress<Snapshot>> for Progress<Probe> {
+    fn from(progress: Progress<Snapshot>) -> Self {
+        Progress {
+            node_id: progress.node_id,
+            state: Probe { paused: false },
+            active: progress.active,
+            index: progress.index,
+            next: progress.next,
+            max_inflight: progress.max_inflight,
+        }
     }
 }
 
 #[derive(Debug)]
 pub struct Replicate {
-    pub inflight: VecDeque<LogIndex>,
+    /// The last index of each append message that hasn't been acknowledged and when it was sent,
+    /// oldest first.
+    pub inflight: VecDeque<(LogIndex, Instant)>,
 }
 
 impl ProgressState for Replicate {
@@ -220,7 +457,28 @@ impl ProgressState for Replicate {
 impl Progress<Replicate> {
     /// The replication is active as long as there are empty spots in the inflight buffer.
     pub fn is_active(&self) -> bool {
-        self.state.inflight.capacity() > self.state.inflight.len()
+        self.state.inflight.len() < self.max_inflight
+    }
+
+    /// Optimistically continue from after the entries up to `index`, without waiting for them to
+    /// be acknowledged.
+    fn send(&mut self, index: LogIndex) {
+        self.state.inflight.push_back((index, clock::now()));
+        self.next = index + 1;
+    }
+
+    /// Frees the inflight messages that have been acknowledged.
+    fn acknowledge(&mut self, index: LogIndex) {
+        while self.state.inflight.front().is_some_and(|(last, _)| *last <= index) {
+            self.state.inflight.pop_front();
+        }
+    }
+
+    /// Gives up on the inflight messages sent longer than `timeout` ago.
+    fn expire(&mut self, timeout: Duration) {
+        while self.state.inflight.front().is_some_and(|(_, sent)| clock::elapsed(*sent) > timeout) {
+            self.state.inflight.pop_front();
+        }
     }
 }
 
@@ -228,37 +486,32 @@ impl From<Progress<Probe>> for Progress<Replicate> {
     fn from(progress: Progress<Probe>) -> Self {
         Progress {
             node_id: progress.node_id,
-            state: Replicate { inflight: VecDeque::with_capacity(MAX_INFLIGHT.try_into().unwrap()) }, 
+            state: Replicate { inflight: VecDeque::with_capacity(progress.max_inflight) },
             active: progress.active,
             index: progress.index,
             next: progress.next,
+            max_inflight: progress.max_inflight,
         }
     }
 }
 
-#[derive(Debug)]
-pub struct PendingReplication {
-    /// The earliest index in the pending buffer.
-    index: usize,
-    /// The number of items in the pending buffer.
-    count: usize,
-    size: usize,
-    pending: Vec<Option<LogIndex>>,
-}
-
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
+    use crate::clock;
     use crate::progress::{NodeProgress, ReplicationProgress};
+    use crate::transport::ConnectionState;
 
     #[test]
     fn starts_active() {
-        let progress = NodeProgress::new(0);
+        let progress = NodeProgress::new(0, 0, 1);
         assert!(progress.is_active());
     }
 
     #[test]
     fn starts_in_probe() {
-        match NodeProgress::new(0) {
+        match NodeProgress::new(0, 0, 1) {
             NodeProgress::Probe(_) => {}
             _ => panic!(),
         }
@@ -266,15 +519,90 @@ mod tests {
 
     #[test]
     fn increments_to_higher() {
-        let mut progress = NodeProgress::new(0);
+        let progress = NodeProgress::new(0, 0, 1);
         let progress = progress.advance(666);
         assert!(progress.is_active());
         assert_eq!(progress.index(), 666);
     }
 
+    #[test]
+    fn snapshot_returns_to_probe() {
+        let progress = NodeProgress::new(0, 0, 1).snapshot(100);
+        assert_eq!(progress.next(), 0);
+        let progress = progress.advance(50);
+        assert!(matches!(progress, NodeProgress::Snapshot(_)));
+
+        match progress.snapshot_done(100) {
+            NodeProgress::Probe(prog) => {
+                assert_eq!(prog.index, 100);
+                assert_eq!(prog.next, 101);
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn inflight_window() {
+        let mut progress = ReplicationProgress::new(vec![1], 1, 2);
+        progress.advance(1, 1);
+        progress.sent(1, 3);
+        progress.sent(1, 5);
+        assert!(!progress.get(1).unwrap().is_active());
+        assert_eq!(progress.get(1).unwrap().next(), 6);
+
+        // acknowledging the first batch frees its slot
+        progress.advance(1, 3);
+        assert!(progress.get(1).unwrap().is_active());
+        progress.sent(1, 7);
+        assert!(!progress.get(1).unwrap().is_active());
+
+        // a rejection starts probing again after the last acknowledged entry
+        progress.reject(1, 5, 4);
+        assert!(matches!(progress.get(1), Some(NodeProgress::Probe(_))));
+        assert_eq!(progress.get(1).unwrap().next(), 4);
+    }
+
+    #[test]
+    fn expires_unacknowledged_appends() {
+        clock::simulate(1);
+        let timeout = Duration::from_millis(100);
+        let mut progress = ReplicationProgress::new(vec![1], 1, 2);
+        progress.advance(1, 1);
+        progress.sent(1, 3);
+        progress.sent(1, 5);
+
+        // a response from the node doesn't free a slot while the appends may still arrive
+        progress.resume(1, timeout);
+        assert!(!progress.get(1).unwrap().is_active());
+
+        // once they're given up on, the lost entries are probed for again
+        clock::advance(timeout * 2);
+        progress.resume(1, timeout);
+        assert!(matches!(progress.get(1), Some(NodeProgress::Probe(_))));
+        assert_eq!(progress.get(1).unwrap().next(), 2);
+    }
+
+    #[test]
+    fn reconnect_resets_probe() {
+        let mut progress = ReplicationProgress::new(vec![1], 1, 2);
+        progress.advance(1, 3);
+        progress.sent(1, 5);
+        progress.sent(1, 7);
+        assert!(!progress.get(1).unwrap().is_active());
+
+        progress.connection_changed(1, ConnectionState::BackingOff);
+        assert_eq!(progress.connection(1), ConnectionState::BackingOff);
+        assert!(matches!(progress.get(1), Some(NodeProgress::Replicate(_))));
+
+        progress.connection_changed(1, ConnectionState::Connected);
+        assert!(matches!(progress.get(1), Some(NodeProgress::Probe(_))));
+        assert!(progress.get(1).unwrap().is_active());
+        assert_eq!(progress.get(1).unwrap().next(), 4);
+    }
+
     #[test]
     #[should_panic]
     fn cannot_construct_empty() {
-        let _ = ReplicationProgress::new(vec![]);
+        let _ = ReplicationProgress::new(vec![], 1, 1);
     }
 }
@@ -5,16 +5,20 @@ use std::time::Duration;
 use std::time::Instant;
 
 use slog::Logger;
-use uuid::Uuid;
 
+use crate::clock;
 use crate::error::RaftError;
 use crate::follower::Follower;
 use crate::leader::Leader;
 use crate::log::Log;
-use crate::store::MemoryStore;
+use crate::membership::{ClusterConfig, Membership};
+use crate::metrics::Metrics;
+use crate::status::Status;
+use crate::store::{FileStore, HardState, HardStateFile, SnapshotFile};
+use crate::transport::ConnectionState;
 use crate::{
     candidate::Candidate,
-    fsm::{self, Fsm},
+    fsm,
     rpc::Request,
 };
 use crate::{config::RaftConfig, rpc::Response};
@@ -49,6 +53,8 @@ pub enum Command {
         last_term: Term,
         /// Index of the last log entry.
         last_index: LogIndex,
+        /// Whether the election was started because the leader handed over leadership.
+        transfer: bool,
     },
     /// Respond to a vote from another node.
     VoteResponse {
@@ -71,6 +77,8 @@ pub enum Command {
         prev_log_index: LogIndex,
         /// The log term preceeding new entries.
         prev_log_term: Term,
+        /// The leader's commit index.
+        leader_commit: LogIndex,
     },
     AppendResponse {
         /// The id of the responding node.
@@ -81,6 +89,71 @@ pub enum Command {
         index: LogIndex,
         /// Whether the entries were successfully applied.
         success: bool,
+        /// Where our log diverges from the leader's, if the entries were rejected.
+        conflict: Option<Conflict>,
+    },
+    /// Request from the leader to replace our state with a snapshot, sent in chunks.
+    InstallSnapshot {
+        /// The term of the leader.
+        term: Term,
+        /// The id of the leader sending the snapshot.
+        leader_id: NodeId,
+        /// The last index included in the snapshot.
+        last_index: LogIndex,
+        /// The term of the last entry included in the snapshot.
+        last_term: Term,
+        /// The latest cluster configuration included in the snapshot.
+        config: Option<ClusterConfig>,
+        /// The byte offset of this chunk within the snapshot.
+        offset: u64,
+        /// The snapshot data in this chunk.
+        data: Vec<u8>,
+        /// Whether this is the last chunk.
+        done: bool,
+    },
+    /// Respond to a snapshot from the leader.
+    InstallSnapshotResponse {
+        /// The id of the responding node.
+        node_id: NodeId,
+        /// The term of the responding node.
+        term: Term,
+        /// The last index included in the snapshot.
+        index: LogIndex,
+        /// How much of the snapshot's data the node has received, which the leader sends the
+        /// next chunk from. Unused once the snapshot is done.
+        offset: u64,
+        /// Whether the node has installed the whole snapshot.
+        done: bool,
+        /// Whether the node accepted the snapshot, which it doesn't from an earlier term.
+        success: bool,
+    },
+    /// Asks whether the node would vote for the candidate in the next term, without either of
+    /// them changing any state.
+    PreVoteRequest {
+        /// The term the candidate would campaign in.
+        term: Term,
+        /// The id of the candidate.
+        candidate_id: NodeId,
+        /// Term of the candidate's last log entry.
+        last_term: Term,
+        /// Index of the candidate's last log entry.
+        last_index: LogIndex,
+    },
+    /// Response to a pre-vote request.
+    PreVoteResponse {
+        /// The term from the request.
+        term: Term,
+        /// The id of the node responding.
+        from: NodeId,
+        /// Whether the node would vote for the candidate.
+        granted: bool,
+    },
+    /// Request from the leader to start an election immediately, handing leadership over to us.
+    TimeoutNow {
+        /// The term of the leader.
+        term: Term,
+        /// The id of the leader handing over leadership.
+        leader_id: NodeId,
     },
     /// Heartbeat from another node.
     Heartbeat {
@@ -88,7 +161,31 @@ pub enum Command {
         term: Term,
         /// The id of the node sending a heartbeat.
         leader_id: NodeId,
+        /// Increases with every heartbeat round that confirms a read, so the leader can tell
+        /// which round a response belongs to.
+        seq: u64,
+        /// The leader's commit index.
+        leader_commit: LogIndex,
+    },
+    /// A follower's acknowledgement of a heartbeat.
+    HeartbeatResponse {
+        /// The term of the follower.
+        term: Term,
+        /// The id of the follower.
+        node_id: NodeId,
+        /// The sequence number of the heartbeat being acknowledged.
+        seq: u64,
     },
+    /// The state machine has been snapshotted up to and including `index`, so the log up to
+    /// that point can be discarded.
+    Compact {
+        /// The last index included in the snapshot.
+        index: LogIndex,
+        /// The term of the last entry included in the snapshot.
+        term: Term,
+    },
+    /// Append the client proposals that have waited long enough to be batched together.
+    Flush,
     /// Timeout on an event (i.e. election).
     Timeout,
     /// Don't do anything.
@@ -104,6 +201,25 @@ pub enum Command {
         id: Vec<u8>,
         res: Result<Response>,
     },
+    /// The transport's connection to a peer changed. Reported by the local transport rather
+    /// than sent between nodes.
+    Connection {
+        /// The id of the peer.
+        node_id: NodeId,
+        /// The state of the connection to it.
+        state: ConnectionState,
+    },
+}
+
+impl Command {
+    /// Whether the command is only ever issued by the node itself, and must not be accepted from
+    /// a peer.
+    pub fn is_local(&self) -> bool {
+        matches!(
+            self,
+            Command::Tick | Command::Compact { .. } | Command::Flush | Command::Timeout | Command::Connection { .. }
+        )
+    }
 }
 
 /// Shared behavior that all roles of the state machine must implement.
@@ -117,7 +233,8 @@ pub trait Role: Debug {
 #[derive(Serialize, PartialEq, Deserialize, Debug, Clone)]
 pub enum EntryType {
     Entry { data: Vec<u8> },
-    Config {},
+    /// A change to the cluster membership, which takes effect as soon as it is appended.
+    Config { config: ClusterConfig },
     Command { command: Command },
 }
 
@@ -132,8 +249,20 @@ pub struct Entry {
     pub index: LogIndex,
 }
 
+/// Where a follower's log diverges from the leader's, so the leader can skip back past a whole
+/// term of conflicting entries at once.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Conflict {
+    /// The term of the follower's entry at the index the leader expected to match, or 0 if the
+    /// follower's log doesn't reach that far.
+    pub term: Term,
+    /// The first index the follower has for that term, or the follower's next index if its log is
+    /// too short.
+    pub index: LogIndex,
+}
+
 /// Contains information about nodes in raft cluster.
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub struct Node {
     /// The id of the node.
     pub id: NodeId,
@@ -143,7 +272,7 @@ pub struct Node {
 
 /// Volatile and persistent state that is common to all roles.
 // NB: These could just be fields on the common Raft struct, but copying them is annoying.
-#[derive(PartialEq, Clone, Copy)]
+#[derive(PartialEq, Clone, Copy, Default)]
 pub struct State {
     /// The current term of the state machine that is incremented as certain commands are applied
     /// to the state machine. The term of the Raft instance is used in determining leadership. For
@@ -160,18 +289,14 @@ pub struct State {
     pub election_time: Option<Instant>,
     /// The timeout for the current election.
     pub election_timeout: Option<Duration>,
-    /// The max timeout that can be selected randomly.
-    pub min_election_timeout: usize,
-    /// The min timeout that can be selected randomly.
-    pub max_election_timeout: usize,
 }
 
 impl Debug for State {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         let timeout = match (self.election_time, self.election_timeout) {
             (Some(time), Some(timeout)) => {
-                if timeout > time.elapsed() {
-                    timeout - time.elapsed()
+                if timeout > clock::elapsed(time) {
+                    timeout - clock::elapsed(time)
                 } else {
                     Duration::from_secs(0)
                 }
@@ -185,21 +310,6 @@ impl Debug for State {
 
 impl State {}
 
-impl Default for State {
-    fn default() -> Self {
-        State {
-            current_term: 0,
-            voted_for: None,
-            commit_index: 0,
-            last_applied: 0,
-            election_time: None,
-            election_timeout: None,
-            min_election_timeout: 500,
-            max_election_timeout: 1000,
-        }
-    }
-}
-
 /// The primary struct representing the state machine. Contains fields common all roles.
 pub struct Raft<T: Role> {
     /// The identifier for this node.
@@ -214,11 +324,17 @@ pub struct Raft<T: Role> {
     /// An instance containing role specific state and behavior.
     pub role: T,
     /// The persistent state for this raft instance.
-    pub log: Log<MemoryStore>,
+    pub log: Log<FileStore>,
+    /// Durable storage for the term, vote and commit index.
+    pub(crate) hard_state: HardStateFile,
+    /// The cluster configuration currently in effect.
+    pub membership: Membership,
     /// Channel to send messages to other nodes.
     pub rpc_tx: UnboundedSender<Message>,
     /// Channel to send instructions to fsm driver.
     pub fsm_tx: UnboundedSender<fsm::Instruction>,
+    /// Reports the health of this node.
+    pub(crate) metrics: Metrics,
 }
 
 // Base methods for general operations (+ debugging and testing).
@@ -226,7 +342,7 @@ impl<T: Role> Raft<T> {
     /// Checks the status of the election timer.
     pub fn needs_election(&self) -> bool {
         match (self.state.election_time, self.state.election_timeout) {
-            (Some(time), Some(timeout)) => time.elapsed() > timeout,
+            (Some(time), Some(timeout)) => clock::elapsed(time) > timeout,
             _ => false,
         }
     }
@@ -239,10 +355,94 @@ impl<T: Role> Raft<T> {
         self.role.term(term);
     }
 
+    /// Persists the term, vote and commit index if they have changed. Must be called before
+    /// acknowledging anything that depends on them, e.g. granting a vote.
+    pub fn save_state(&mut self) -> Result<()> {
+        self.hard_state.save(HardState::new(&self.state, &self.membership))
+    }
+
+    /// Appends an entry to the log, switching to the configuration it contains if it is a config
+    /// entry.
+    pub fn append_entry(&mut self, entry: Entry) -> Result<LogIndex> {
+        self.append_entries(vec![entry])
+    }
+
+    /// Appends consecutive entries to the log with a single write, switching to each
+    /// configuration among them in turn. Returns the index of the last entry.
+    pub fn append_entries(&mut self, entries: Vec<Entry>) -> Result<LogIndex> {
+        let configs: Vec<(ClusterConfig, LogIndex)> = entries
+            .iter()
+            .filter_map(|entry| match &entry.entry_type {
+                EntryType::Config { config } => Some((config.clone(), entry.index)),
+                _ => None,
+            })
+            .collect();
+
+        let index = self.log.append_all(entries)?;
+        if !configs.is_empty() {
+            for (config, index) in configs {
+                info!(self.logger, "Switching cluster configuration"; "config" => format!("{:?}", config), "index" => index);
+                self.membership.set(config, index);
+            }
+            self.save_state()?;
+        }
+        Ok(index)
+    }
+
+    /// The other nodes in the current cluster configuration.
+    pub fn peers(&self) -> Vec<Node> {
+        self.membership.peers(self.id)
+    }
+
+    /// The term and index of the last entry in our log.
+    pub fn last_log(&self) -> Result<(Term, LogIndex)> {
+        let last_index = self.log.next_index() - 1;
+        let last_term = self.log.term(last_index)?.unwrap_or(0);
+        Ok((last_term, last_index))
+    }
+
+    /// Whether a log ending at the given term and index is at least as up to date as ours.
+    pub fn log_is_current(&self, last_term: Term, last_index: LogIndex) -> Result<bool> {
+        let (our_term, our_index) = self.last_log()?;
+        Ok((last_term, last_index) >= (our_term, our_index))
+    }
+
+    /// Replies to a pre-vote request, granting it if the candidate's log is up to date and `allow`
+    /// is true.
+    pub(crate) fn respond_pre_vote(&self, cmd: &Command, allow: bool) -> Result<()> {
+        if let Command::PreVoteRequest { term, candidate_id, last_term, last_index } = *cmd {
+            let granted = allow
+                && term > self.state.current_term
+                && self.log_is_current(last_term, last_index)?;
+            self.send(
+                Address::Peer(candidate_id),
+                Command::PreVoteResponse {
+                    term,
+                    from: self.id,
+                    granted,
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Discards the log entries covered by a snapshot, which the driver must have saved.
+    pub fn compact(&mut self, index: LogIndex, term: Term) -> Result<()> {
+        let saved = SnapshotFile::open(&self.config.data_directory)?.last_index()?;
+        if index > saved {
+            warn!(self.logger, "refusing to compact past the saved snapshot"; "index" => index, "saved" => saved);
+            return Ok(());
+        }
+
+        info!(self.logger, "compacting log"; "index" => index, "term" => term);
+        self.log.compact(index, term)
+    }
+
     pub fn log_command(&self, cmd: &Command) {
         match cmd {
             Command::Tick => {}
             Command::Heartbeat { .. } => {}
+            Command::HeartbeatResponse { .. } => {}
             _ => {
                 debug!(self.role.log(), ""; "role_state" => format!("{:?}", self.role), "state" => format!("{:?}", self.state), "cmd" => format!("{:?}", cmd))
             }
@@ -251,17 +451,18 @@ impl<T: Role> Raft<T> {
 
     pub fn send(&self, to: Address, cmd: Command) -> Result<()> {
         let msg = Message::new(Address::Peer(self.id), to, cmd);
-        self.rpc_tx.send(msg).map_err(|err| RaftError::from(err))?;
+        self.rpc_tx.send(msg).map_err(RaftError::from)?;
         Ok(())
     }
 
     pub fn send_all(&self, cmd: Command) -> Result<()> {
         let msg = Message::new(Address::Peer(self.id), Address::Peers, cmd);
-        self.rpc_tx.send(msg).map_err(|err| RaftError::from(err))?;
+        self.rpc_tx.send(msg).map_err(RaftError::from)?;
         Ok(())
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum RaftRole {
     Follower,
     Candidate,
@@ -281,7 +482,7 @@ pub enum RaftHandle {
     /// An instance of the state machine in the candidate role.
     Candidate(Raft<Candidate>),
     /// An instance of the state machine in the leader role.
-    Leader(Raft<Leader>),
+    Leader(Box<Raft<Leader>>),
 }
 
 impl RaftHandle {
@@ -297,25 +498,68 @@ impl RaftHandle {
     }
 
     pub fn is_follower(&self) -> bool {
-        if let Self::Follower(_) = self { true } else { false }
+        matches!(self, Self::Follower(_))
     }
 
     pub fn is_candidate(&self) -> bool {
-        if let Self::Candidate(_) = self { true } else { false }
+        matches!(self, Self::Candidate(_))
     }
 
     pub fn is_leader(&self) -> bool {
-        if let Self::Leader(_) = self { true } else { false }
+        matches!(self, Self::Leader(_))
+    }
+
+    /// The leader of the current term, if this node knows who it is.
+    pub fn leader_id(&self) -> Option<NodeId> {
+        match self {
+            RaftHandle::Follower(raft) => raft.role.leader_id,
+            RaftHandle::Candidate(_) => None,
+            RaftHandle::Leader(raft) => Some(raft.id),
+        }
+    }
+
+    /// The other nodes in the current cluster configuration.
+    pub fn peers(&self) -> Vec<Node> {
+        match self {
+            RaftHandle::Follower(raft) => raft.peers(),
+            RaftHandle::Candidate(raft) => raft.peers(),
+            RaftHandle::Leader(raft) => raft.peers(),
+        }
+    }
+
+    /// The current state of this node.
+    pub fn status(&self) -> Status {
+        Status::new(self)
+    }
+
+    /// The metrics describing this node.
+    pub fn metrics(&self) -> Metrics {
+        match self {
+            RaftHandle::Follower(raft) => raft.metrics.clone(),
+            RaftHandle::Candidate(raft) => raft.metrics.clone(),
+            RaftHandle::Leader(raft) => raft.metrics.clone(),
+        }
     }
 }
 
 impl Apply for RaftHandle {
     fn apply(self, cmd: Command) -> Result<RaftHandle> {
-        match self {
+        let tick = matches!(cmd, Command::Tick);
+        let raft = match self {
             RaftHandle::Follower(raft) => raft.apply(cmd),
             RaftHandle::Candidate(raft) => raft.apply(cmd),
-            RaftHandle::Leader(raft) => raft.apply(cmd),
+            RaftHandle::Leader(raft) => (*raft).apply(cmd),
+        }?;
+        let metrics = match &raft {
+            RaftHandle::Follower(raft) => &raft.metrics,
+            RaftHandle::Candidate(raft) => &raft.metrics,
+            RaftHandle::Leader(raft) => &raft.metrics,
+        };
+        metrics.observe(&raft);
+        if tick {
+            metrics.observe_peers(&raft);
         }
+        Ok(raft)
     }
 }
 
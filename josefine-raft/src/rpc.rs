@@ -1,4 +1,4 @@
-use crate::raft::{Command, NodeId, Term};
+use crate::raft::{Command, Node, NodeId};
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub enum Address {
@@ -21,11 +21,11 @@ pub struct Message {
 
 impl Message {
     pub fn new(from: Address, to: Address, command: Command) -> Message {
-        return Message {
+        Message {
             from,
             to,
             command,
-        };
+        }
     }
 }
 
@@ -34,9 +34,24 @@ impl Message {
 pub enum Request {
     Propose(Vec<u8>),
     Query(Vec<u8>),
+    /// Adds a voting member to the cluster.
+    AddNode(Node),
+    /// Adds a non-voting member to the cluster, which receives the log but doesn't count
+    /// toward quorums.
+    AddLearner(Node),
+    /// Makes a learner that has caught up with the log a voting member.
+    PromoteLearner(NodeId),
+    /// Removes a member from the cluster.
+    RemoveNode(NodeId),
+    /// Hands leadership over to another voter.
+    TransferLeadership(NodeId),
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Response {
     State(Vec<u8>),
+    /// The voters of the cluster once a membership change has completed.
+    Membership(Vec<Node>),
+    /// Leadership is being handed over to the node.
+    LeadershipTransferred(NodeId),
 }
\ No newline at end of file
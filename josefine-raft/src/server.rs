@@ -1,100 +1,135 @@
 use josefine_core::error::{JosefineError, Result};
 use crate::error::RaftError;
+use crate::http;
 use crate::logger::get_root_logger;
-use crate::raft::{Apply, Command, RaftHandle};
+use crate::raft::{Apply, Command, Node, NodeId, RaftHandle};
 use crate::rpc::{Address, Message, Request, Response};
-use crate::tcp;
-use crate::{
-    config::RaftConfig,
-    fsm::{self, Fsm},
-};
+use crate::status::{Status, StatusHandle};
+use crate::transport::{TcpTransport, Transport};
+use crate::{config::RaftConfig, fsm};
 use futures::FutureExt;
 use slog::Logger;
 use uuid::Uuid;
-use std::{collections::HashMap, net::SocketAddr};
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
-use tokio::time::Duration;
-use tokio::{
-    net::TcpListener,
-    sync::{mpsc::unbounded_channel, oneshot},
-};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::time::{Duration, Instant};
+use tokio::sync::{mpsc::unbounded_channel, oneshot};
 
 /// step duration
 const TICK: Duration = Duration::from_millis(100);
 
-pub struct Server {
+pub struct Server<T: Transport = TcpTransport> {
     config: RaftConfig,
+    transport: T,
     log: Logger,
+    /// The status of the node, published by the event loop.
+    status_tx: watch::Sender<Option<Status>>,
+    status_rx: watch::Receiver<Option<Status>>,
 }
 
 impl Server {
     pub fn new(config: RaftConfig) -> Self {
+        let transport = TcpTransport::new(&config);
+        Self::with_transport(config, transport)
+    }
+}
+
+impl<T: Transport> Server<T> {
+    /// A server which exchanges messages with its peers over `transport`.
+    pub fn with_transport(config: RaftConfig, transport: T) -> Self {
+        let (status_tx, status_rx) = watch::channel(None);
         Server {
             config,
+            transport,
             log: get_root_logger().new(o!()),
+            status_tx,
+            status_rx,
         }
     }
 
-    pub async fn run<T: 'static + fsm::Fsm>(
+    /// A live view of the node's status.
+    pub fn status(&self) -> StatusHandle {
+        StatusHandle::new(self.status_rx.clone())
+    }
+
+    pub async fn run<F: 'static + fsm::Fsm>(
         self,
         duration: Option<Duration>,
-        fsm: T,
+        fsm: F,
         client_rx: UnboundedReceiver<(Request, oneshot::Sender<Result<Response>>)>,
     ) -> Result<RaftHandle> {
         info!(self.log, "Using config"; "config" => format!("{:?}", self.config));
 
-        // shutdown broadcaster
+        // shutdown broadcasters: the event loop stops first, then the tasks it sends to
         let (shutdown_tx, _shutdown_rx) = tokio::sync::broadcast::channel(1);
+        let (stopped_tx, _stopped_rx) = tokio::sync::broadcast::channel(1);
 
-        // tcp receive
-        let socket_addr = SocketAddr::new(self.config.ip, self.config.port);
-        let listener = TcpListener::bind(socket_addr).await?;
+        // peer communication, with the peers in the current cluster configuration
+        let dropped = self.transport.dropped();
         let (rpc_tx, rpc_rx) = mpsc::unbounded_channel();
-        let (tcp_in_tx, tcp_in_rx) = mpsc::unbounded_channel::<Message>();
-        let (task, tcp_receiver) = tcp::receive_task(
-            self.log.new(o!()),
-            shutdown_tx.subscribe(),
-            listener,
-            tcp_in_tx,
-        )
-        .remote_handle();
+        let (peer_in_tx, peer_in_rx) = mpsc::unbounded_channel::<Message>();
+        let (peer_out_tx, peer_out_rx) = mpsc::unbounded_channel::<Message>();
+        let (peers_tx, peers_rx) = watch::channel(self.config.nodes.clone());
+        let (task, transport) = self
+            .transport
+            .start(
+                self.log.new(o!()),
+                stopped_tx.subscribe(),
+                self.config.id,
+                peers_rx,
+                peer_in_tx,
+                peer_out_rx,
+            )
+            .remote_handle();
         tokio::spawn(task);
 
-        // tcp send
-        let (tcp_out_tx, tcp_out_rx) = mpsc::unbounded_channel::<Message>();
-        let (task, tcp_sender) = tcp::send_task(
-            self.log.new(o!()),
-            shutdown_tx.subscribe(),
-            self.config.id,
-            self.config.clone().nodes,
-            tcp_out_rx,
-        )
-        .remote_handle();
-        tokio::spawn(task);
-
-        // state machine driver
+        // state machine driver, sharing its metrics with raft
         let (fsm_tx, fsm_rx) = unbounded_channel();
-        let driver = fsm::Driver::new(self.log.new(o!()), fsm_rx, rpc_tx.clone(), fsm);
-        let (task, driver) = driver.run(shutdown_tx.subscribe()).remote_handle();
-        tokio::spawn(task);
-
-        // main event loop
         let raft = RaftHandle::new(
             self.log.new(o!()),
-            self.config,
+            self.config.clone(),
             rpc_tx.clone(),
             fsm_tx.clone(),
         );
-        let (task, event_loop) = event_loop(
-            self.log.new(o!()),
-            shutdown_tx.subscribe(),
-            raft,
-            tcp_out_tx,
+        let metrics = raft.metrics();
+        let driver = fsm::Driver::new(self.log.new(o!()), &self.config, fsm_rx, rpc_tx.clone(), fsm, metrics.clone())?;
+        let (task, driver) = driver.run(stopped_tx.subscribe()).remote_handle();
+        tokio::spawn(task);
+
+        if let Some(addr) = self.config.metrics_addr {
+            let handler = move |path: &str| match path {
+                "/metrics" => {
+                    metrics.record_dropped(&dropped);
+                    Some(("text/plain; version=0.0.4", metrics.encode()))
+                }
+                _ => None,
+            };
+            serve_http(self.log.new(o!("endpoint" => "metrics")), stopped_tx.subscribe(), addr, handler).await?;
+        }
+        if let Some(addr) = self.config.admin_addr {
+            let status = StatusHandle::new(self.status_rx.clone());
+            let handler = move |path: &str| match path {
+                "/status" => Some(("application/json", serde_json::to_vec(&status.get()).ok()?)),
+                _ => None,
+            };
+            serve_http(self.log.new(o!("endpoint" => "admin")), stopped_tx.subscribe(), addr, handler).await?;
+        }
+
+        // main event loop
+        let (batch_timeout, request_timeout) = (self.config.batch_timeout, self.config.request_timeout);
+        let channels = Channels {
+            peer_tx: peer_out_tx,
+            peers_tx,
+            status_tx: self.status_tx,
             rpc_rx,
-            tcp_in_rx,
+            peer_rx: peer_in_rx,
             client_rx,
-        )
+        };
+        let (task, event_loop) = event_loop(self.log.new(o!()), shutdown_tx.subscribe(), raft, channels, batch_timeout, request_timeout)
         .remote_handle();
         tokio::spawn(task);
 
@@ -103,44 +138,153 @@ impl Server {
             shutdown_tx.send(())?;
         }
 
-        let (_, _, _, raft) = tokio::try_join!(tcp_receiver, tcp_sender, driver, event_loop)?;
+        // nothing is sent to the transport or the driver once the event loop has stopped
+        let raft = event_loop.await;
+        let _ = stopped_tx.send(());
+        let raft = raft?;
+        tokio::try_join!(transport, driver)?;
         Ok(raft)
     }
 }
 
+/// Serves HTTP requests on `addr` in the background until shutdown.
+async fn serve_http<H>(log: Logger, shutdown: tokio::sync::broadcast::Receiver<()>, addr: SocketAddr, handler: H) -> Result<()>
+where
+    H: Fn(&str) -> Option<http::Body> + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(addr).await?;
+    info!(log, "serving http"; "addr" => addr.to_string());
+    tokio::spawn(async move {
+        if let Err(err) = http::serve(log.clone(), shutdown, listener, handler).await {
+            error!(log, "http server failed"; "error" => format!("{:?}", err));
+        }
+    });
+    Ok(())
+}
+
+/// What the event loop exchanges messages with.
+struct Channels {
+    /// Messages for peers, sent on by the transport.
+    peer_tx: UnboundedSender<Message>,
+    /// Where the nodes the transport connects to are published.
+    peers_tx: watch::Sender<Vec<Node>>,
+    /// Where the status of the node is published.
+    status_tx: watch::Sender<Option<Status>>,
+    /// Messages from raft itself.
+    rpc_rx: UnboundedReceiver<Message>,
+    /// Messages received from peers.
+    peer_rx: UnboundedReceiver<Message>,
+    /// Requests from clients of this node, and where to send their responses.
+    client_rx: UnboundedReceiver<(Request, oneshot::Sender<Result<Response>>)>,
+}
+
+/// A request from a client of this node that hasn't been answered yet.
+struct PendingRequest {
+    tx: oneshot::Sender<Result<Response>>,
+    /// When the client is told the request timed out.
+    deadline: Instant,
+    /// The leader the request was forwarded to, if it was.
+    leader_id: Option<NodeId>,
+}
+
 async fn event_loop(
     log: Logger,
     mut shutdown: tokio::sync::broadcast::Receiver<()>,
     mut raft: RaftHandle,
-    tcp_tx: UnboundedSender<Message>,
-    mut rpc_rx: UnboundedReceiver<Message>,
-    mut tcp_rx: UnboundedReceiver<Message>,
-    mut client_rx: UnboundedReceiver<(Request, oneshot::Sender<Result<Response>>)>,
+    channels: Channels,
+    batch_timeout: Duration,
+    request_timeout: Duration,
 ) -> Result<RaftHandle> {
+    let Channels { peer_tx, peers_tx, status_tx, mut rpc_rx, mut peer_rx, mut client_rx } = channels;
     let mut step_interval = tokio::time::interval(TICK);
-    let mut requests = HashMap::<Vec<u8>, oneshot::Sender<Result<Response>>>::new();
+    let mut batch_interval = tokio::time::interval(batch_timeout);
+    let mut requests = HashMap::<Vec<u8>, PendingRequest>::new();
+    // requests forwarded to us by followers, who to send the response back to, and when the
+    // follower will have given up on it
+    let mut forwarded = HashMap::<Vec<u8>, (Address, Instant)>::new();
+    let mut leader_id = raft.leader_id();
     info!(log, "starting event loop");
 
+    let mut peers = raft.peers();
+    // the transport may have gone away during shutdown, in which case there's no one to tell
+    let _ = peers_tx.send(peers.clone());
+    let mut status = raft.status();
+    let _ = status_tx.send(Some(status.clone()));
+
     loop {
         tokio::select! {
             // shutdown
             _ = shutdown.recv() => break,
             // tick state machine
-            _ = step_interval.tick() => raft = raft.apply(Command::Tick)?,
+            _ = step_interval.tick() => {
+                raft = raft.apply(Command::Tick)?;
+
+                // a response may never come, e.g. when the leader a request was forwarded to
+                // crashed before answering it
+                let now = Instant::now();
+                let expired: Vec<_> = requests.iter().filter(|(_, pending)| pending.deadline <= now).map(|(id, _)| id.clone()).collect();
+                for id in expired {
+                    let pending = requests.remove(&id).unwrap();
+                    let _ = pending.tx.send(Err(JosefineError::Internal { error_msg: "request timed out".to_string() }));
+                }
+                forwarded.retain(|_, (_, deadline)| *deadline > now);
+                // building the status for every message is too costly, so it's only published as
+                // time passes
+                let current = raft.status();
+                if current != status {
+                    status = current;
+                    let _ = status_tx.send(Some(status.clone()));
+                }
+            },
+            // append batched proposals
+            _ = batch_interval.tick() => raft = raft.apply(Command::Flush)?,
             // intra-cluster communication
-            Some(msg) = tcp_rx.recv() => raft = raft.apply(msg.command)?,
+            Some(msg) = peer_rx.recv() => {
+                match msg {
+                    Message { command: Command::ClientResponse { id, res }, .. } => {
+                        match requests.remove(&id) {
+                            Some(pending) => { let _ = pending.tx.send(res); }
+                            None => warn!(log, "dropping response to a request that was already answered"; "id" => format!("{:?}", id)),
+                        }
+                    },
+                    Message { from, command: Command::ClientRequest { id, req }, .. } if raft.is_leader() => {
+                        forwarded.insert(id.clone(), (from, Instant::now() + request_timeout));
+                        raft = raft.apply(Command::ClientRequest { id, req })?;
+                    },
+                    Message { from, command: Command::ClientRequest { id, .. }, .. } => {
+                        // we were sent the request as the leader but no longer are, so rather than
+                        // forwarding it again the node it came from tells its client where to go
+                        let res = Err(JosefineError::NotLeader { leader_id: raft.leader_id() });
+                        peer_tx.send(Message::new(Address::Local, from, Command::ClientResponse { id, res })).map_err(RaftError::from)?;
+                    },
+                    Message { from, command, .. } if command.is_local() && from != Address::Local => {
+                        warn!(log, "dropping local command from a peer"; "from" => format!("{:?}", from), "command" => format!("{:?}", command));
+                    },
+                    msg => raft = raft.apply(msg.command)?,
+                }
+            },
             // outgoing messages from raft
             Some(msg) = rpc_rx.recv() => {
                 match msg {
-                    Message { to: Address::Peer(_), .. } => tcp_tx.send(msg).map_err(|err| RaftError::from(err))?,
-                    Message { to: Address::Peers, ..  } => tcp_tx.send(msg).map_err(|err| RaftError::from(err))?,
+                    Message { to: Address::Peer(to), command: Command::ClientRequest { ref id, .. }, .. } => {
+                        if let Some(pending) = requests.get_mut(id) {
+                            pending.leader_id = Some(to);
+                        }
+                        peer_tx.send(msg).map_err(RaftError::from)?
+                    },
+                    Message { to: Address::Peer(_), .. } => peer_tx.send(msg).map_err(RaftError::from)?,
+                    Message { to: Address::Peers, ..  } => peer_tx.send(msg).map_err(RaftError::from)?,
+                    Message { to: Address::Local, command, .. } => raft = raft.apply(command)?,
+                    Message { to: Address::Client, command: Command::ClientResponse { id, res }, .. } if forwarded.contains_key(&id) => {
+                        let (to, _) = forwarded.remove(&id).unwrap();
+                        peer_tx.send(Message::new(msg.from, to, Command::ClientResponse { id, res })).map_err(RaftError::from)?;
+                    },
                     Message { to: Address::Client, command: Command::ClientResponse { id, res }, .. } => {
                         match requests.remove(&id) {
-                            Some(tx) => tx.send(res).expect("the channel was dropped"),
-                            None => {
-                                error!(log, "cound not find response tx"; "id" => format!("{:?}", id));
-                                panic!("could not find response tx");
-                            }
+                            // the client may have stopped waiting
+                            Some(pending) => { let _ = pending.tx.send(res); }
+                            // or the request has already timed out
+                            None => warn!(log, "dropping response to a request that was already answered"; "id" => format!("{:?}", id)),
                         };
                     },
                     _ => return Err(JosefineError::Internal { error_msg: format!("Unexpected message {:?}", msg) }),
@@ -149,10 +293,26 @@ async fn event_loop(
             // incoming messages from clients
             Some((req, res)) = client_rx.recv() => {
                 let id = Uuid::new_v4().as_bytes().to_vec();
-                requests.insert(id.clone(), res);
+                requests.insert(id.clone(), PendingRequest { tx: res, deadline: Instant::now() + request_timeout, leader_id: None });
                 raft = raft.apply(Command::ClientRequest { id, req, })?;
             },
         }
+
+        // the old leader won't answer what was forwarded to it, so the clients can try the new one
+        if raft.leader_id() != leader_id {
+            leader_id = raft.leader_id();
+            let orphaned: Vec<_> = requests.iter().filter(|(_, pending)| pending.leader_id.is_some() && pending.leader_id != leader_id).map(|(id, _)| id.clone()).collect();
+            for id in orphaned {
+                let pending = requests.remove(&id).unwrap();
+                let _ = pending.tx.send(Err(JosefineError::NotLeader { leader_id }));
+            }
+        }
+
+        if raft.peers() != peers {
+            peers = raft.peers();
+            info!(log, "peers changed"; "peers" => format!("{:?}", peers));
+            let _ = peers_tx.send(peers.clone());
+        }
     }
 
     Ok(raft)
@@ -160,37 +320,50 @@ async fn event_loop(
 
 #[cfg(test)]
 mod tests {
+    use super::Channels;
+    use josefine_core::error::{JosefineError, Result};
     use crate::config::RaftConfig;
-    use josefine_core::error::Result;
     use crate::logger::get_root_logger;
-    use crate::raft::RaftHandle;
+    use crate::raft::{Command, Node, RaftHandle, RaftRole};
+    use crate::rpc::{Address, Message, Request};
+    use crate::test::new_config;
 
     use std::time::Duration;
     use tokio::sync::mpsc::{self, unbounded_channel};
+    use tokio::sync::oneshot;
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
     async fn event_loop() -> Result<()> {
         let (rpc_tx, rpc_rx) = mpsc::unbounded_channel();
-        let (fsm_tx, fsm_rx) = unbounded_channel();
+        let (fsm_tx, _fsm_rx) = unbounded_channel();
         let raft = RaftHandle::new(
             get_root_logger().new(o!()),
-            RaftConfig::default(),
+            new_config(),
             rpc_tx.clone(),
             fsm_tx.clone(),
         );
 
         let (_tcp_in_tx, tcp_in_rx) = mpsc::unbounded_channel();
         let (tcp_out_tx, _tcp_out_rx) = mpsc::unbounded_channel();
-        let (client_tx, client_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (peers_tx, _peers_rx) = tokio::sync::watch::channel(vec![]);
+        let (status_tx, status_rx) = tokio::sync::watch::channel(None);
+        let (_client_tx, client_rx) = tokio::sync::mpsc::unbounded_channel();
         let (shutdown_tx, _shutdown_rx) = tokio::sync::broadcast::channel(1);
+        let channels = Channels {
+            peer_tx: tcp_out_tx,
+            peers_tx,
+            status_tx,
+            rpc_rx,
+            peer_rx: tcp_in_rx,
+            client_rx,
+        };
         let event_loop = super::event_loop(
             get_root_logger().new(o!()),
             shutdown_tx.subscribe(),
             raft,
-            tcp_out_tx,
-            rpc_rx,
-            tcp_in_rx,
-            client_rx,
+            channels,
+            Duration::from_millis(2),
+            Duration::from_secs(10),
         );
         let raft = tokio::spawn(event_loop);
         std::thread::sleep(Duration::from_secs(2));
@@ -201,6 +374,114 @@ mod tests {
         } else {
             panic!("was not elected leader");
         }
+        assert_eq!(status_rx.borrow().as_ref().map(|status| status.role), Some(RaftRole::Leader));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn turns_away_requests_forwarded_to_follower() -> Result<()> {
+        let (rpc_tx, rpc_rx) = mpsc::unbounded_channel();
+        let (fsm_tx, _fsm_rx) = unbounded_channel();
+        let config = RaftConfig {
+            nodes: vec![Node { id: 2, addr: "127.0.0.1:6670".parse()? }],
+            ..new_config()
+        };
+        let raft = RaftHandle::new(get_root_logger().new(o!()), config, rpc_tx, fsm_tx);
+
+        let (peer_in_tx, peer_in_rx) = mpsc::unbounded_channel();
+        let (peer_out_tx, mut peer_out_rx) = mpsc::unbounded_channel();
+        let (peers_tx, _peers_rx) = tokio::sync::watch::channel(vec![]);
+        let (status_tx, _status_rx) = tokio::sync::watch::channel(None);
+        let (_client_tx, client_rx) = mpsc::unbounded_channel();
+        let (shutdown_tx, _shutdown_rx) = tokio::sync::broadcast::channel(1);
+        let channels = Channels {
+            peer_tx: peer_out_tx,
+            peers_tx,
+            status_tx,
+            rpc_rx,
+            peer_rx: peer_in_rx,
+            client_rx,
+        };
+        tokio::spawn(super::event_loop(
+            get_root_logger().new(o!()),
+            shutdown_tx.subscribe(),
+            raft,
+            channels,
+            Duration::from_millis(2),
+            Duration::from_secs(10),
+        ));
+
+        let request = Command::ClientRequest { id: vec![1], req: Request::Propose(vec![1]) };
+        peer_in_tx.send(Message::new(Address::Peer(2), Address::Peer(1), request)).unwrap();
+        let response = loop {
+            let msg = peer_out_rx.recv().await.unwrap();
+            if let Command::ClientResponse { .. } = msg.command {
+                break msg;
+            }
+        };
+        assert_eq!(response.to, Address::Peer(2));
+        assert!(matches!(
+            response.command,
+            Command::ClientResponse { res: Err(JosefineError::NotLeader { leader_id: None }), .. }
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fails_forwarded_requests_when_leader_changes() -> Result<()> {
+        let (rpc_tx, rpc_rx) = mpsc::unbounded_channel();
+        let (fsm_tx, _fsm_rx) = unbounded_channel();
+        let config = RaftConfig {
+            nodes: vec![Node { id: 2, addr: "127.0.0.1:6670".parse()? }],
+            ..new_config()
+        };
+        let raft = RaftHandle::new(get_root_logger().new(o!()), config, rpc_tx, fsm_tx);
+
+        let (peer_in_tx, peer_in_rx) = mpsc::unbounded_channel();
+        let (peer_out_tx, mut peer_out_rx) = mpsc::unbounded_channel();
+        let (peers_tx, _peers_rx) = tokio::sync::watch::channel(vec![]);
+        let (status_tx, _status_rx) = tokio::sync::watch::channel(None);
+        let (client_tx, client_rx) = mpsc::unbounded_channel();
+        let (shutdown_tx, _shutdown_rx) = tokio::sync::broadcast::channel(1);
+        let channels = Channels {
+            peer_tx: peer_out_tx,
+            peers_tx,
+            status_tx,
+            rpc_rx,
+            peer_rx: peer_in_rx,
+            client_rx,
+        };
+        tokio::spawn(super::event_loop(
+            get_root_logger().new(o!()),
+            shutdown_tx.subscribe(),
+            raft,
+            channels,
+            Duration::from_millis(2),
+            Duration::from_secs(10),
+        ));
+
+        let heartbeat = |term, leader_id| Command::Heartbeat { term, leader_id, seq: 0, leader_commit: 0 };
+        peer_in_tx.send(Message::new(Address::Peer(2), Address::Peer(1), heartbeat(1, 2))).unwrap();
+        loop {
+            let msg = peer_out_rx.recv().await.unwrap();
+            if let Command::HeartbeatResponse { .. } = msg.command {
+                break;
+            }
+        }
+        let (res_tx, res_rx) = oneshot::channel();
+        client_tx.send((Request::Propose(vec![1]), res_tx)).unwrap();
+        loop {
+            let msg = peer_out_rx.recv().await.unwrap();
+            if let Command::ClientRequest { .. } = msg.command {
+                assert_eq!(msg.to, Address::Peer(2));
+                break;
+            }
+        }
+
+        // node 2 is deposed before it answers
+        peer_in_tx.send(Message::new(Address::Peer(3), Address::Peer(1), heartbeat(2, 3))).unwrap();
+        let res = tokio::time::timeout(Duration::from_secs(1), res_rx).await.unwrap().unwrap();
+        assert_eq!(res, Err(JosefineError::NotLeader { leader_id: Some(3) }));
         Ok(())
     }
 }
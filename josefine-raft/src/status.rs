@@ -0,0 +1,171 @@
+//! A snapshot of what a node knows about itself and the cluster, for operators and tooling. The
+//! server publishes it every tick, and serves it as JSON at `/status` when
+//! `RaftConfig::admin_addr` is set.
+use tokio::sync::watch;
+
+use josefine_core::error::{JosefineError, Result};
+
+use crate::membership::ClusterConfig;
+use crate::progress::NodeProgress;
+use crate::raft::{LogIndex, NodeId, Raft, RaftHandle, RaftRole, Role, Term};
+use crate::transport::ConnectionState;
+
+/// The state of a node.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Status {
+    pub id: NodeId,
+    pub role: RaftRole,
+    /// The leader of the current term, if the node knows who it is.
+    pub leader_id: Option<NodeId>,
+    pub term: Term,
+    /// The index of the last entry in the node's log.
+    pub last_index: LogIndex,
+    pub commit_index: LogIndex,
+    /// The highest index the state machine has applied.
+    pub applied_index: LogIndex,
+    /// The cluster configuration the node is using.
+    pub membership: ClusterConfig,
+    /// How far along replication to each peer is. Only the leader tracks this, so it is empty on
+    /// other nodes.
+    pub peers: Vec<PeerStatus>,
+}
+
+/// The leader's view of replication to a peer.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PeerStatus {
+    pub id: NodeId,
+    /// The highest index known to be replicated to the peer.
+    pub match_index: LogIndex,
+    /// The index of the next entry to send the peer.
+    pub next_index: LogIndex,
+    pub replication: ReplicationState,
+    pub connection: ConnectionState,
+    /// Whether the peer has responded within the last election timeout.
+    pub active: bool,
+}
+
+/// How the leader is bringing a peer up to date.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReplicationState {
+    /// Finding where the peer's log diverges from the leader's.
+    Probe,
+    /// Streaming entries to a peer whose log matches.
+    Replicate,
+    /// Sending a snapshot to a peer that needs entries the leader has compacted.
+    Snapshot,
+}
+
+impl Status {
+    pub(crate) fn new(handle: &RaftHandle) -> Status {
+        match handle {
+            RaftHandle::Follower(raft) => Status::of(raft, handle.leader_id(), Vec::new()),
+            RaftHandle::Candidate(raft) => Status::of(raft, handle.leader_id(), Vec::new()),
+            RaftHandle::Leader(raft) => {
+                let peers = raft
+                    .peers()
+                    .into_iter()
+                    .filter_map(|node| {
+                        let progress = raft.role.progress.get(node.id)?;
+                        Some(PeerStatus {
+                            id: node.id,
+                            match_index: progress.index(),
+                            next_index: progress.next(),
+                            replication: match progress {
+                                NodeProgress::Probe(_) => ReplicationState::Probe,
+                                NodeProgress::Replicate(_) => ReplicationState::Replicate,
+                                NodeProgress::Snapshot(_) => ReplicationState::Snapshot,
+                            },
+                            connection: raft.role.progress.connection(node.id),
+                            active: raft.role.progress.responded_within(node.id, raft.config.election_timeout),
+                        })
+                    })
+                    .collect();
+                Status::of(raft, handle.leader_id(), peers)
+            }
+        }
+    }
+
+    fn of<T: Role>(raft: &Raft<T>, leader_id: Option<NodeId>, peers: Vec<PeerStatus>) -> Status {
+        Status {
+            id: raft.id,
+            role: raft.role.role(),
+            leader_id,
+            term: raft.state.current_term,
+            last_index: raft.log.next_index() - 1,
+            commit_index: raft.state.commit_index,
+            // the driver applies entries, and keeps the metrics up to date as it does
+            applied_index: raft.metrics.applied_index.get() as LogIndex,
+            membership: raft.membership.config.clone(),
+            peers,
+        }
+    }
+}
+
+/// A live view of a running node's status.
+#[derive(Clone)]
+pub struct StatusHandle {
+    rx: watch::Receiver<Option<Status>>,
+}
+
+impl StatusHandle {
+    pub(crate) fn new(rx: watch::Receiver<Option<Status>>) -> StatusHandle {
+        StatusHandle { rx }
+    }
+
+    /// The latest status of the node, or nothing if it hasn't started yet.
+    pub fn get(&self) -> Option<Status> {
+        self.rx.borrow().clone()
+    }
+
+    /// Waits for the status to change, failing once the node has stopped.
+    pub async fn changed(&mut self) -> Result<()> {
+        self.rx.changed().await.map_err(|_| JosefineError::Internal {
+            error_msg: "the node has stopped".to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{ReplicationState, Status};
+    use crate::follower::Follower;
+    use crate::logger::get_root_logger;
+    use crate::raft::{Apply, Command, Node, Raft, RaftHandle, RaftRole};
+    use crate::test::new_config;
+    use crate::transport::ConnectionState;
+
+    #[test]
+    fn reports_leader_and_peers() {
+        let config = crate::config::RaftConfig {
+            nodes: vec![Node {
+                id: 2,
+                addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            }],
+            ..new_config()
+        };
+        let (rpc_tx, _rpc_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (fsm_tx, _fsm_rx) = tokio::sync::mpsc::unbounded_channel();
+        let node: Raft<Follower> = Raft::new(config, get_root_logger().new(o!()), rpc_tx, fsm_tx).unwrap();
+        let id = node.id;
+
+        let node = RaftHandle::Follower(node).apply(Command::Timeout).unwrap();
+        let status = node.status();
+        assert_eq!(status.role, RaftRole::Candidate);
+        assert_eq!(status.leader_id, None);
+        assert!(status.peers.is_empty());
+
+        let node = node.apply(Command::VoteResponse { term: 1, from: 2, granted: true }).unwrap();
+        let status = node.status();
+        assert_eq!(status.role, RaftRole::Leader);
+        assert_eq!((status.leader_id, status.term), (Some(id), 1));
+        assert_eq!(status.membership.node_ids().len(), 2);
+        assert_eq!(status.peers.len(), 1);
+        assert_eq!(status.peers[0].id, 2);
+        assert_eq!(status.peers[0].replication, ReplicationState::Probe);
+        assert_eq!(status.peers[0].connection, ConnectionState::Connected);
+        assert!(status.peers[0].active);
+        assert_eq!(Status::new(&node), status);
+    }
+}
@@ -1,9 +1,31 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
 use crate::{raft::LogIndex};
 use josefine_core::error::Result;
 
-pub trait Store : Default {
+mod file;
+mod hard_state;
+mod snapshot;
+
+pub use file::FileStore;
+pub use hard_state::{HardState, HardStateFile};
+pub use snapshot::{Snapshot, SnapshotFile};
+
+pub trait Store {
     fn append(&mut self, entry: Vec<u8>) -> Result<LogIndex>;
 
+    /// Appends several entries, returning the index of the last one. Stores should make the whole
+    /// batch durable at once rather than entry by entry.
+    fn append_all(&mut self, entries: Vec<Vec<u8>>) -> Result<LogIndex> {
+        let mut index = self.next_index() - 1;
+        for entry in entries {
+            index = self.append(entry)?;
+        }
+        Ok(index)
+    }
+
     fn commit(&mut self, index: LogIndex) -> Result<()>;
 
     fn committed(&self) -> LogIndex;
@@ -18,6 +40,12 @@ pub trait Store : Default {
 
     fn truncate(&mut self, index: LogIndex) -> Result<LogIndex>;
 
+    /// Discards entries up to and including `index`, which are covered by a snapshot. Stores may
+    /// retain some of these entries, but must keep every entry after `index`. If `index` is
+    /// beyond the end of the log, the whole log is discarded and the next appended entry will
+    /// have index `index + 1`.
+    fn compact(&mut self, index: LogIndex) -> Result<()>;
+
     fn is_empty(&self) -> bool {
         self.len() == 0
     }
@@ -27,23 +55,27 @@ pub trait Store : Default {
     }
 }
 
+#[cfg(test)]
 pub struct MemoryStore {
     log: Vec<Vec<u8>>,
     committed: LogIndex,
 }
 
+#[cfg(test)]
 impl MemoryStore {
     pub fn new() -> Self {
         Self { log: Vec::new(), committed: 0 }
     }
 }
 
+#[cfg(test)]
 impl Default for MemoryStore {
     fn default() -> Self {
         MemoryStore::new()
     }
 }
 
+#[cfg(test)]
 impl Store for MemoryStore {
     fn append(&mut self, entry: Vec<u8>) -> Result<u64> {
         self.log.push(entry);
@@ -81,6 +113,25 @@ impl Store for MemoryStore {
         let entries = self.log.get(start as usize..end as usize).unwrap();
         Ok(entries.to_vec())
     }
+
+    fn compact(&mut self, _index: LogIndex) -> Result<()> {
+        // entries are addressed by their position, so the memory store keeps everything
+        Ok(())
+    }
+}
+
+/// Replaces the file at `path` with `bytes` such that readers see either the old or the new
+/// contents, even across a crash.
+pub(crate) fn write_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -0,0 +1,462 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use josefine_core::error::{JosefineError, Result};
+
+use crate::config::SyncPolicy;
+use crate::raft::LogIndex;
+use crate::store::Store;
+
+/// Each record is prefixed by the length of its payload and a CRC32 of the payload.
+const HEADER_SIZE: u64 = 8;
+
+/// A durable [`Store`] that keeps the log in a directory of append-only segment files.
+///
+/// Every segment is named after the index of its first entry and holds a sequence of
+/// checksummed records. When a store is opened, all segments are scanned and a torn write at
+/// the tail of the last segment (i.e. a crash mid-append) is discarded.
+pub struct FileStore {
+    dir: PathBuf,
+    segments: Vec<Segment>,
+    committed: LogIndex,
+    segment_size: u64,
+    sync_policy: SyncPolicy,
+}
+
+impl FileStore {
+    /// Opens the store in `dir`, creating it if it does not exist and recovering any existing
+    /// segments.
+    pub fn open<P: AsRef<Path>>(dir: P, segment_size: u64, sync_policy: SyncPolicy) -> Result<FileStore> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut bases = Vec::new();
+        for dir_entry in fs::read_dir(&dir)? {
+            let path = dir_entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("log") {
+                continue;
+            }
+            if let Some(base) = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse().ok()) {
+                bases.push(base);
+            }
+        }
+        bases.sort_unstable();
+
+        let mut segments: Vec<Segment> = Vec::new();
+        for (i, base) in bases.iter().enumerate() {
+            let is_tail = i == bases.len() - 1;
+            let segment = Segment::open(&dir, *base, is_tail)?;
+            if let Some(prev) = segments.last() {
+                // a compaction that stopped before removing the segments its snapshot covers
+                // leaves them ahead of the new base segment
+                if prev.next_index() < segment.base && is_tail && segment.offsets.is_empty() {
+                    for segment in segments.drain(..) {
+                        fs::remove_file(&segment.path)?;
+                    }
+                } else if prev.next_index() != segment.base {
+                    return Err(JosefineError::Internal {
+                        error_msg: format!("gap in raft log between index {} and {}", prev.next_index(), segment.base),
+                    });
+                }
+            }
+            segments.push(segment);
+        }
+
+        if segments.is_empty() {
+            segments.push(Segment::create(&dir, 1)?);
+        }
+
+        Ok(FileStore {
+            dir,
+            segments,
+            committed: 0,
+            segment_size,
+            sync_policy,
+        })
+    }
+
+    fn first_index(&self) -> LogIndex {
+        self.segments[0].base
+    }
+
+    fn last_index(&self) -> LogIndex {
+        self.active().next_index() - 1
+    }
+
+    fn active(&self) -> &Segment {
+        self.segments.last().expect("there is always an active segment")
+    }
+
+    fn active_mut(&mut self) -> &mut Segment {
+        self.segments.last_mut().expect("there is always an active segment")
+    }
+
+    fn segment_for(&self, index: LogIndex) -> Option<&Segment> {
+        if index < self.first_index() || index > self.last_index() {
+            return None;
+        }
+
+        self.segments.iter().rev().find(|segment| segment.base <= index)
+    }
+
+    /// Starts a new segment after the current active segment.
+    fn roll(&mut self) -> Result<()> {
+        self.active_mut().file.sync_all()?;
+        let base = self.last_index() + 1;
+        let segment = Segment::create(&self.dir, base)?;
+        self.segments.push(segment);
+        Ok(())
+    }
+}
+
+impl Store for FileStore {
+    fn append(&mut self, entry: Vec<u8>) -> Result<LogIndex> {
+        self.append_all(vec![entry])
+    }
+
+    fn append_all(&mut self, entries: Vec<Vec<u8>>) -> Result<LogIndex> {
+        for entry in entries {
+            if self.active().size >= self.segment_size && !self.active().offsets.is_empty() {
+                self.roll()?;
+            }
+            self.active_mut().append(&entry)?;
+        }
+
+        // rolling syncs the segments before the active one, so one sync covers the whole batch
+        if self.sync_policy == SyncPolicy::Always {
+            self.active().file.sync_data()?;
+        }
+
+        Ok(self.last_index())
+    }
+
+    fn commit(&mut self, index: LogIndex) -> Result<()> {
+        if self.sync_policy == SyncPolicy::Commit {
+            self.active().file.sync_data()?;
+        }
+
+        self.committed = index;
+        Ok(())
+    }
+
+    fn committed(&self) -> LogIndex {
+        self.committed
+    }
+
+    fn get(&self, index: LogIndex) -> Result<Option<Vec<u8>>> {
+        match self.segment_for(index) {
+            Some(segment) => Ok(Some(segment.read(index)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_range(&self, start: LogIndex, end: LogIndex) -> Result<Vec<Vec<u8>>> {
+        let start = std::cmp::max(start + 1, self.first_index());
+        let end = std::cmp::min(end, self.last_index());
+
+        let mut entries = Vec::new();
+        for index in start..=end {
+            let segment = self.segment_for(index).expect("index is within the log");
+            entries.push(segment.read(index)?);
+        }
+        Ok(entries)
+    }
+
+    fn len(&self) -> u64 {
+        self.last_index() + 1 - self.first_index()
+    }
+
+    fn size(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.size).sum()
+    }
+
+    fn truncate(&mut self, index: LogIndex) -> Result<LogIndex> {
+        while self.segments.len() > 1 && self.active().base > index {
+            let segment = self.segments.pop().unwrap();
+            fs::remove_file(&segment.path)?;
+        }
+
+        if self.active().base > index {
+            // Everything is being discarded, so start over after the requested index.
+            fs::remove_file(&self.active().path)?;
+            let segment = Segment::create(&self.dir, index + 1)?;
+            *self.active_mut() = segment;
+        } else {
+            let segment = self.active_mut();
+            let keep = (index + 1 - segment.base) as usize;
+            if keep < segment.offsets.len() {
+                segment.size = segment.offsets[keep];
+                segment.offsets.truncate(keep);
+                segment.file.set_len(segment.size)?;
+                segment.file.sync_all()?;
+            }
+        }
+
+        self.committed = std::cmp::min(self.committed, index);
+        Ok(self.len())
+    }
+
+    fn compact(&mut self, index: LogIndex) -> Result<()> {
+        if index >= self.last_index() {
+            // the new segment goes in first, so the log is never left without one
+            let base = Segment::create(&self.dir, index + 1)?;
+            for segment in self.segments.drain(..) {
+                if segment.path != base.path {
+                    fs::remove_file(&segment.path)?;
+                }
+            }
+            self.segments.push(base);
+            return Ok(());
+        }
+
+        // only whole segments can be discarded, so the first remaining segment may still hold
+        // some entries covered by the snapshot
+        while self.segments[0].next_index() <= index + 1 {
+            let segment = self.segments.remove(0);
+            fs::remove_file(&segment.path)?;
+        }
+        Ok(())
+    }
+
+    fn next_index(&self) -> LogIndex {
+        self.last_index() + 1
+    }
+}
+
+/// A single append-only file of the log.
+struct Segment {
+    /// The index of the first entry in the segment.
+    base: LogIndex,
+    path: PathBuf,
+    file: File,
+    /// The byte offset of each record in the segment.
+    offsets: Vec<u64>,
+    /// The length of the segment in bytes.
+    size: u64,
+}
+
+impl Segment {
+    fn path(dir: &Path, base: LogIndex) -> PathBuf {
+        dir.join(format!("{:020}.log", base))
+    }
+
+    fn create(dir: &Path, base: LogIndex) -> Result<Segment> {
+        let path = Self::path(dir, base);
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        file.set_len(0)?;
+
+        Ok(Segment {
+            base,
+            path,
+            file,
+            offsets: Vec::new(),
+            size: 0,
+        })
+    }
+
+    /// Opens an existing segment, validating each record. A torn record at the end of the tail
+    /// segment is discarded, anywhere else it is treated as corruption.
+    fn open(dir: &Path, base: LogIndex, is_tail: bool) -> Result<Segment> {
+        let path = Self::path(dir, base);
+        let bytes = fs::read(&path)?;
+
+        let mut offsets = Vec::new();
+        let mut pos = 0usize;
+        while let Some(len) = Self::check_record(&bytes[pos..]) {
+            offsets.push(pos as u64);
+            pos += HEADER_SIZE as usize + len;
+        }
+
+        if pos < bytes.len() && !is_tail {
+            return Err(JosefineError::Internal {
+                error_msg: format!("corrupt raft log segment {:?} at byte {}", path, pos),
+            });
+        }
+
+        let file = OpenOptions::new().read(true).append(true).open(&path)?;
+        if pos < bytes.len() {
+            file.set_len(pos as u64)?;
+            file.sync_all()?;
+        }
+
+        Ok(Segment {
+            base,
+            path,
+            file,
+            offsets,
+            size: pos as u64,
+        })
+    }
+
+    /// Returns the payload length of the record at the start of `bytes` if it is complete and
+    /// its checksum matches.
+    fn check_record(bytes: &[u8]) -> Option<usize> {
+        if bytes.len() < HEADER_SIZE as usize {
+            return None;
+        }
+
+        let len = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+        let crc = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        let payload = bytes.get(HEADER_SIZE as usize..HEADER_SIZE as usize + len)?;
+        if crc32fast::hash(payload) != crc {
+            return None;
+        }
+
+        Some(len)
+    }
+
+    fn next_index(&self) -> LogIndex {
+        self.base + self.offsets.len() as LogIndex
+    }
+
+    fn append(&mut self, payload: &[u8]) -> Result<()> {
+        let mut record = Vec::with_capacity(HEADER_SIZE as usize + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        record.extend_from_slice(&crc32fast::hash(payload).to_be_bytes());
+        record.extend_from_slice(payload);
+        self.file.write_all(&record)?;
+
+        self.offsets.push(self.size);
+        self.size += record.len() as u64;
+        Ok(())
+    }
+
+    fn read(&self, index: LogIndex) -> Result<Vec<u8>> {
+        let i = (index - self.base) as usize;
+        let start = self.offsets[i];
+        let end = self.offsets.get(i + 1).cloned().unwrap_or(self.size);
+
+        let mut record = vec![0; (end - start) as usize];
+        self.file.read_exact_at(&mut record, start)?;
+        match Self::check_record(&record) {
+            Some(_) => Ok(record.split_off(HEADER_SIZE as usize)),
+            None => Err(JosefineError::Internal {
+                error_msg: format!("corrupt raft log entry {} in {:?}", index, self.path),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    use super::{FileStore, Segment};
+    use crate::config::SyncPolicy;
+    use crate::store::Store;
+    use crate::test::temp_dir;
+
+    #[test]
+    fn append_and_reopen() {
+        let dir = temp_dir();
+        {
+            let mut store = FileStore::open(&dir, 1024, SyncPolicy::Always).unwrap();
+            assert_eq!(store.append(vec![1]).unwrap(), 1);
+            assert_eq!(store.append(vec![2, 2]).unwrap(), 2);
+        }
+
+        let store = FileStore::open(&dir, 1024, SyncPolicy::Always).unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.next_index(), 3);
+        assert_eq!(store.get(2).unwrap(), Some(vec![2, 2]));
+        assert_eq!(store.get(3).unwrap(), None);
+    }
+
+    #[test]
+    fn rolls_segments() {
+        let dir = temp_dir();
+        let mut store = FileStore::open(&dir, 1024, SyncPolicy::Never).unwrap();
+        for i in 0..100u8 {
+            store.append(vec![i; 100]).unwrap();
+        }
+        assert!(store.segments.len() > 1);
+
+        let store = FileStore::open(&dir, 1024, SyncPolicy::Never).unwrap();
+        assert_eq!(store.len(), 100);
+        assert_eq!(store.get(50).unwrap(), Some(vec![49; 100]));
+        assert_eq!(store.get_range(10, 13).unwrap(), vec![vec![10; 100], vec![11; 100], vec![12; 100]]);
+    }
+
+    #[test]
+    fn truncate() {
+        let dir = temp_dir();
+        let mut store = FileStore::open(&dir, 1024, SyncPolicy::Never).unwrap();
+        for i in 0..50u8 {
+            store.append(vec![i; 100]).unwrap();
+        }
+        assert_eq!(store.truncate(20).unwrap(), 20);
+        assert_eq!(store.get(21).unwrap(), None);
+        assert_eq!(store.append(vec![0]).unwrap(), 21);
+
+        let store = FileStore::open(&dir, 1024, SyncPolicy::Never).unwrap();
+        assert_eq!(store.len(), 21);
+        assert_eq!(store.get(20).unwrap(), Some(vec![19; 100]));
+    }
+
+    #[test]
+    fn compact() {
+        let dir = temp_dir();
+        let mut store = FileStore::open(&dir, 1024, SyncPolicy::Never).unwrap();
+        for i in 0..50u8 {
+            store.append(vec![i; 100]).unwrap();
+        }
+        store.compact(30).unwrap();
+        assert!(store.first_index() <= 31);
+        assert_eq!(store.get(31).unwrap(), Some(vec![30; 100]));
+        assert_eq!(store.get(1).unwrap(), None);
+
+        store.compact(60).unwrap();
+        assert!(store.is_empty());
+        assert_eq!(store.append(vec![0]).unwrap(), 61);
+
+        let store = FileStore::open(&dir, 1024, SyncPolicy::Never).unwrap();
+        assert_eq!(store.get(61).unwrap(), Some(vec![0]));
+    }
+
+    #[test]
+    fn reopens_after_interrupted_compaction() {
+        let dir = temp_dir();
+        {
+            let mut store = FileStore::open(&dir, 1024, SyncPolicy::Always).unwrap();
+            for i in 0..20u8 {
+                store.append(vec![i; 100]).unwrap();
+            }
+        }
+
+        // simulate a crash after the new base segment was created but before the old ones went
+        Segment::create(&dir, 61).unwrap();
+
+        let mut store = FileStore::open(&dir, 1024, SyncPolicy::Always).unwrap();
+        assert!(store.is_empty());
+        assert_eq!(store.append(vec![0]).unwrap(), 61);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn discards_torn_write() {
+        let dir = temp_dir();
+        {
+            let mut store = FileStore::open(&dir, 1024, SyncPolicy::Always).unwrap();
+            store.append(vec![1, 2, 3]).unwrap();
+            store.append(vec![4, 5, 6]).unwrap();
+        }
+
+        // simulate a crash part way through writing a record
+        let segment = std::fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path();
+        let mut file = OpenOptions::new().append(true).open(&segment).unwrap();
+        file.write_all(&[0, 0, 0, 3, 1, 2]).unwrap();
+
+        let mut store = FileStore::open(&dir, 1024, SyncPolicy::Always).unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.append(vec![7]).unwrap(), 3);
+        assert_eq!(store.get(3).unwrap(), Some(vec![7]));
+    }
+}
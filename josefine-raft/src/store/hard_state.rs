@@ -0,0 +1,96 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use josefine_core::error::Result;
+
+use crate::membership::Membership;
+use crate::raft::{LogIndex, NodeId, State, Term};
+use crate::store::write_atomic;
+
+/// The subset of [`State`] that must survive a restart for elections to remain safe.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct HardState {
+    pub current_term: Term,
+    pub voted_for: Option<NodeId>,
+    pub commit_index: LogIndex,
+    /// The cluster membership, or `None` if the node has never seen a config entry.
+    pub membership: Option<Membership>,
+}
+
+impl HardState {
+    pub fn new(state: &State, membership: &Membership) -> HardState {
+        HardState {
+            current_term: state.current_term,
+            voted_for: state.voted_for,
+            commit_index: state.commit_index,
+            membership: Some(membership.clone()),
+        }
+    }
+}
+
+/// Persists [`HardState`] to a single file, which is atomically replaced on every write so a
+/// crash can never leave a partially written state behind.
+#[derive(Debug)]
+pub struct HardStateFile {
+    path: PathBuf,
+    saved: HardState,
+}
+
+impl HardStateFile {
+    /// Opens the hard state file in `dir`, returning the last saved state or the default state
+    /// if none has been written yet.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<HardStateFile> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+
+        let path = dir.join("state");
+        let saved = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HardState::default(),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(HardStateFile { path, saved })
+    }
+
+    /// The most recently persisted state.
+    pub fn get(&self) -> HardState {
+        self.saved.clone()
+    }
+
+    /// Persists the state if it has changed since it was last saved. Returns once the new state
+    /// is on stable storage.
+    pub fn save(&mut self, state: HardState) -> Result<()> {
+        if state == self.saved {
+            return Ok(());
+        }
+
+        write_atomic(&self.path, &serde_json::to_vec(&state)?)?;
+        self.saved = state;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HardState, HardStateFile};
+    use crate::test::temp_dir;
+
+    #[test]
+    fn save_and_reopen() {
+        let dir = temp_dir();
+        let mut file = HardStateFile::open(&dir).unwrap();
+        assert_eq!(file.get(), HardState::default());
+
+        let state = HardState {
+            current_term: 3,
+            voted_for: Some(2),
+            commit_index: 7,
+            membership: None,
+        };
+        file.save(state.clone()).unwrap();
+
+        let file = HardStateFile::open(&dir).unwrap();
+        assert_eq!(file.get(), state);
+    }
+}
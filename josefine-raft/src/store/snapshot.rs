@@ -0,0 +1,178 @@
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use josefine_core::error::{JosefineError, Result};
+
+use crate::membership::ClusterConfig;
+use crate::raft::{LogIndex, Term};
+use crate::store::write_atomic;
+
+/// The last included index and term, a CRC32 of the rest of the file and the length of the
+/// cluster configuration that precedes the state machine data.
+const HEADER_SIZE: usize = 24;
+
+/// A point-in-time copy of the state machine, covering every entry up to `last_index`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    /// The index of the last entry applied to the state machine when the snapshot was taken.
+    pub last_index: LogIndex,
+    /// The term of the last entry applied to the state machine.
+    pub last_term: Term,
+    /// The latest cluster configuration applied before the snapshot was taken.
+    pub config: Option<ClusterConfig>,
+    /// The serialized state machine.
+    pub data: Vec<u8>,
+}
+
+impl Snapshot {
+    fn to_bytes(&self) -> Vec<u8> {
+        let config = match &self.config {
+            Some(config) => serde_json::to_vec(config).expect("cluster config is serializable"),
+            None => Vec::new(),
+        };
+        let mut body = Vec::with_capacity(config.len() + self.data.len());
+        body.extend_from_slice(&config);
+        body.extend_from_slice(&self.data);
+
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + body.len());
+        bytes.extend_from_slice(&self.last_index.to_be_bytes());
+        bytes.extend_from_slice(&self.last_term.to_be_bytes());
+        bytes.extend_from_slice(&crc32fast::hash(&body).to_be_bytes());
+        bytes.extend_from_slice(&(config.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&body);
+        bytes
+    }
+
+    fn from_bytes(mut bytes: Vec<u8>) -> Option<Snapshot> {
+        if bytes.len() < HEADER_SIZE {
+            return None;
+        }
+
+        let u64_at = |i: usize| {
+            let mut buf = [0; 8];
+            buf.copy_from_slice(&bytes[i..i + 8]);
+            u64::from_be_bytes(buf)
+        };
+        let u32_at = |i: usize| {
+            let mut buf = [0; 4];
+            buf.copy_from_slice(&bytes[i..i + 4]);
+            u32::from_be_bytes(buf)
+        };
+        let last_index = u64_at(0);
+        let last_term = u64_at(8);
+        let crc = u32_at(16);
+        let config_len = u32_at(20) as usize;
+
+        let mut data = bytes.split_off(HEADER_SIZE);
+        if crc32fast::hash(&data) != crc || config_len > data.len() {
+            return None;
+        }
+
+        let config = if config_len > 0 {
+            Some(serde_json::from_slice(&data[..config_len]).ok()?)
+        } else {
+            None
+        };
+        let data = data.split_off(config_len);
+
+        Some(Snapshot {
+            last_index,
+            last_term,
+            config,
+            data,
+        })
+    }
+}
+
+/// Stores the most recent [`Snapshot`] in a single, atomically replaced file.
+#[derive(Debug)]
+pub struct SnapshotFile {
+    path: PathBuf,
+}
+
+impl SnapshotFile {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<SnapshotFile> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        Ok(SnapshotFile {
+            path: dir.join("snapshot"),
+        })
+    }
+
+    /// Loads the latest snapshot, if one has been taken.
+    pub fn load(&self) -> Result<Option<Snapshot>> {
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        match Snapshot::from_bytes(bytes) {
+            Some(snapshot) => Ok(Some(snapshot)),
+            None => Err(JosefineError::Internal {
+                error_msg: format!("corrupt snapshot {:?}", self.path),
+            }),
+        }
+    }
+
+    /// The last index included in the latest snapshot, or 0 if no snapshot has been taken.
+    pub fn last_index(&self) -> Result<LogIndex> {
+        let mut file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err.into()),
+        };
+        let mut buf = [0; 8];
+        file.read_exact(&mut buf)?;
+        Ok(LogIndex::from_be_bytes(buf))
+    }
+
+    /// Replaces the latest snapshot with a newer one.
+    pub fn save(&self, snapshot: &Snapshot) -> Result<()> {
+        let last_index = self.last_index()?;
+        if snapshot.last_index <= last_index {
+            return Err(JosefineError::Internal {
+                error_msg: format!(
+                    "snapshot up to {} is no newer than the saved one up to {}",
+                    snapshot.last_index, last_index
+                ),
+            });
+        }
+        write_atomic(&self.path, &snapshot.to_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{Snapshot, SnapshotFile};
+    use crate::membership::ClusterConfig;
+    use crate::raft::Node;
+    use crate::test::temp_dir;
+
+    #[test]
+    fn save_and_load() {
+        let dir = temp_dir();
+        let file = SnapshotFile::open(&dir).unwrap();
+        assert_eq!(file.load().unwrap(), None);
+
+        let snapshot = Snapshot {
+            last_index: 10,
+            last_term: 2,
+            config: Some(ClusterConfig::new(vec![Node {
+                id: 1,
+                addr: SocketAddr::from(([127, 0, 0, 1], 6669)),
+            }])),
+            data: vec![1, 2, 3],
+        };
+        file.save(&snapshot).unwrap();
+        assert_eq!(SnapshotFile::open(&dir).unwrap().load().unwrap(), Some(snapshot.clone()));
+        assert_eq!(file.last_index().unwrap(), 10);
+
+        // an older snapshot never replaces a newer one
+        assert!(file.save(&Snapshot { last_index: 9, ..snapshot }).is_err());
+        assert_eq!(file.last_index().unwrap(), 10);
+    }
+}
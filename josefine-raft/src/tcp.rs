@@ -1,22 +1,103 @@
+use crate::codec::MessageCodec;
+use crate::config::{RaftConfig, TlsConfig};
+use crate::handshake::{self, Hello, Identity};
 use crate::error::RaftError;
-use crate::raft::{Node, NodeId};
+use crate::raft::{Command, Node, NodeId};
 use crate::rpc::{Address, Message};
-use futures::SinkExt;
-use std::collections::HashMap;
-use josefine_core::error::Result;
+use crate::tls::Tls;
+use crate::transport::{ConnectionState, DroppedMessages, Transport};
+use futures::future::BoxFuture;
+use futures::{FutureExt, SinkExt};
+use rand::Rng;
+use std::collections::{hash_map, HashMap};
+use std::net::SocketAddr;
+use josefine_core::error::{JosefineError, Result};
 
 use slog::Logger;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::{Receiver, UnboundedReceiver, UnboundedSender};
-use tokio::time::Duration;
+use tokio::sync::{oneshot, watch};
+use tokio::time::{Duration, Instant};
 use tokio_stream::StreamExt;
-use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+/// How long a peer that connected to us has to set up TLS and introduce itself.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Exchanges messages with peers over TCP, listening for them on `addr` and connecting to the
+/// addresses in the cluster configuration. Connections are made over mutual TLS when it is
+/// configured, and open with a [handshake](crate::handshake).
+pub struct TcpTransport {
+    addr: SocketAddr,
+    tls: Option<TlsConfig>,
+    hello: Hello,
+    queue_size: usize,
+    min_backoff: Duration,
+    max_backoff: Duration,
+    dropped: DroppedMessages,
+}
+
+impl TcpTransport {
+    pub fn new(config: &RaftConfig) -> Self {
+        TcpTransport {
+            addr: SocketAddr::new(config.ip, config.port),
+            tls: config.tls.clone(),
+            hello: Hello::new(config),
+            queue_size: config.peer_queue_size,
+            min_backoff: config.reconnect_backoff,
+            max_backoff: config.max_reconnect_backoff,
+            dropped: DroppedMessages::new(),
+        }
+    }
+}
 
+impl Transport for TcpTransport {
+    fn start(
+        self,
+        log: Logger,
+        shutdown: tokio::sync::broadcast::Receiver<()>,
+        _id: NodeId,
+        peers: watch::Receiver<Vec<Node>>,
+        in_tx: UnboundedSender<Message>,
+        out_rx: UnboundedReceiver<Message>,
+    ) -> BoxFuture<'static, Result<()>> {
+        async move {
+            let tls = self.tls.as_ref().map(Tls::new).transpose()?;
+            let listener = TcpListener::bind(self.addr).await?;
+            // sending stops once the event loop closes the outgoing channel
+            let context = SendContext {
+                hello: self.hello.clone(),
+                tls: tls.clone(),
+                queue_size: self.queue_size,
+                min_backoff: self.min_backoff,
+                max_backoff: self.max_backoff,
+                events: in_tx.clone(),
+                dropped: self.dropped,
+            };
+            let receive = receive_task(log.new(o!()), shutdown, listener, self.hello, tls, peers.clone(), in_tx);
+            let send = send_task(log, context, peers, out_rx);
+            futures::try_join!(receive, send)?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn dropped(&self) -> DroppedMessages {
+        self.dropped.clone()
+    }
+}
+
+/// Accepts connections from peers and forwards the messages they send to `in_tx`. Only nodes in
+/// `peers` are accepted, and with TLS they must present their certificate.
 pub async fn receive_task(
     log: Logger,
     mut shutdown: tokio::sync::broadcast::Receiver<()>,
     listener: TcpListener,
+    hello: Hello,
+    tls: Option<Tls>,
+    peers: watch::Receiver<Vec<Node>>,
     in_tx: UnboundedSender<Message>,
 ) -> Result<()> {
     loop {
@@ -27,10 +108,28 @@ pub async fn receive_task(
                 let log = log.new(o!("addr" => format!("{:?}", addr)));
                 info!(log, "peer connected");
                 let peer_in_tx = in_tx.clone();
+                let hello = hello.clone();
+                let tls = tls.clone();
+                let nodes = peers.borrow().clone();
                 tokio::spawn(async move {
-                    match stream_messages(log.clone(), s, peer_in_tx).await {
+                    // a peer that stalls before it has introduced itself doesn't get to hold on to
+                    // the connection
+                    let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+                    let res = match tls {
+                        Some(tls) => match tokio::time::timeout_at(deadline, tls.accept(s, &nodes)).await {
+                            Ok(Ok((id, s))) => {
+                                stream_messages(log.clone(), s, &hello, &nodes, Identity::Certificate(id), deadline, peer_in_tx).await
+                            }
+                            Ok(Err(err)) => Err(err),
+                            Err(_) => Err(handshake_timed_out()),
+                        },
+                        None => {
+                            stream_messages(log.clone(), s, &hello, &nodes, Identity::Address(addr.ip()), deadline, peer_in_tx).await
+                        }
+                    };
+                    match res {
                         Ok(()) => { info!(log, "peer disconnected") }
-                        Err(_) => { error!(log, "error reading from peer") }
+                        Err(err) => { error!(log, "error reading from peer"; "error" => format!("{:?}", err)) }
                     }
                 });
             }
@@ -41,43 +140,94 @@ pub async fn receive_task(
     Ok(())
 }
 
-async fn stream_messages(
+/// Reads messages from a peer's connection once it has introduced itself. A peer may only send
+/// messages from the node it introduced itself as.
+///
+/// * `nodes` - The nodes allowed to connect.
+/// * `identity` - What shows which node the peer is.
+/// * `deadline` - When the peer must have finished introducing itself by.
+async fn stream_messages<S: AsyncRead + AsyncWrite + Unpin>(
     log: Logger,
-    stream: TcpStream,
+    mut stream: S,
+    hello: &Hello,
+    nodes: &[Node],
+    identity: Identity,
+    deadline: Instant,
     in_tx: UnboundedSender<Message>,
 ) -> Result<()> {
-    let length_delimited = FramedRead::new(stream, LengthDelimitedCodec::new());
-    let mut stream = tokio_serde::SymmetricallyFramed::new(
-        length_delimited,
-        tokio_serde::formats::SymmetricalJson::default(),
-    );
+    let (peer, version) = tokio::time::timeout_at(deadline, handshake::accept(&mut stream, hello, nodes, identity))
+        .await
+        .map_err(|_| handshake_timed_out())??;
+    info!(log, "peer introduced itself"; "peer" => peer, "version" => version);
+    let mut stream = FramedRead::new(stream, MessageCodec::new(version));
 
     while let Some(message) = stream.try_next().await? {
         info!(log, "receive message"; "msg" => format!("{:?}", message));
-        in_tx.send(message).map_err(|err| RaftError::from(err))?;
+        if message.from != Address::Peer(peer) {
+            return Err(JosefineError::MessageError {
+                error_msg: format!("node {} sent a message from {:?}", peer, message.from),
+            });
+        }
+        // connection changes and the like are only ever reported by this node
+        if message.command.is_local() {
+            return Err(JosefineError::MessageError {
+                error_msg: format!("node {} sent a local command {:?}", peer, message.command),
+            });
+        }
+        in_tx.send(message).map_err(RaftError::from)?;
     }
     Ok(())
 }
 
+fn handshake_timed_out() -> JosefineError {
+    JosefineError::MessageError {
+        error_msg: "peer took too long to introduce itself".to_string(),
+    }
+}
+
+/// What the send tasks of every peer share.
+#[derive(Clone)]
+pub struct SendContext {
+    /// How this node introduces itself to peers.
+    pub hello: Hello,
+    /// Used to authenticate connections, if set.
+    pub tls: Option<Tls>,
+    /// The number of messages queued for each peer before they're dropped.
+    pub queue_size: usize,
+    /// The wait before the first attempt to reconnect to a peer.
+    pub min_backoff: Duration,
+    /// The longest wait between attempts to reconnect to a peer.
+    pub max_backoff: Duration,
+    /// Where changes in the connection to each peer are reported.
+    pub events: UnboundedSender<Message>,
+    pub dropped: DroppedMessages,
+}
+
 pub async fn send_task(
     log: Logger,
-    shutdown: tokio::sync::broadcast::Receiver<()>,
-    id: NodeId,
-    nodes: Vec<Node>,
-    out_rx: UnboundedReceiver<Message>,
+    context: SendContext,
+    mut peers: watch::Receiver<Vec<Node>>,
+    mut out_rx: UnboundedReceiver<Message>,
 ) -> Result<()> {
-    let mut node_txs: HashMap<NodeId, mpsc::Sender<Message>> = HashMap::new();
+    let mut node_txs: HashMap<NodeId, PeerSender> = HashMap::new();
+    let nodes = peers.borrow().clone();
+    update_peers(&log, &context, &nodes, &mut node_txs);
 
-    for node in nodes.iter() {
-        let (tx, rx) = mpsc::channel::<Message>(1000);
-        node_txs.insert(node.id, tx);
-        tokio::spawn(connect_and_send(*node, log.new(o!()), rx));
-    }
+    loop {
+        let mut message = tokio::select! {
+            Ok(()) = peers.changed() => {
+                let nodes = peers.borrow().clone();
+                update_peers(&log, &context, &nodes, &mut node_txs);
+                continue;
+            }
+            message = out_rx.recv() => match message {
+                Some(message) => message,
+                None => break,
+            },
+        };
 
-    let mut s = stream::UnboundedReceiverStream(out_rx);
-    while let Some(mut message) = s.next().await {
         if message.from == Address::Local {
-            message.from = Address::Peer(id)
+            message.from = Address::Peer(context.hello.id)
         }
         let to = match &message.to {
             Address::Peers => node_txs.keys().cloned().collect(),
@@ -89,9 +239,10 @@ pub async fn send_task(
         };
         for id in to {
             match node_txs.get_mut(&id) {
-                Some(tx) => match tx.try_send(message.clone()) {
+                Some(peer) => match peer.tx.try_send(message.clone()) {
                     Ok(()) => {}
                     Err(mpsc::error::TrySendError::Full(_)) => {
+                        context.dropped.record(id);
                         error!(log, "Full send buffer for peer, discarding message"; "peer" => id)
                     }
                     Err(error) => return Err(RaftError::from(error).into()),
@@ -103,18 +254,103 @@ pub async fn send_task(
     Ok(())
 }
 
+/// Starts sending to peers that have joined the cluster, and stops sending to those that have
+/// left it. Dropping a peer's sender ends its send task.
+fn update_peers(log: &Logger, context: &SendContext, nodes: &[Node], node_txs: &mut HashMap<NodeId, PeerSender>) {
+    node_txs.retain(|id, _| nodes.iter().any(|node| node.id == *id));
+    for node in nodes {
+        if let hash_map::Entry::Vacant(entry) = node_txs.entry(node.id) {
+            info!(log, "adding peer"; "peer" => node.id);
+            let (tx, rx) = mpsc::channel::<Message>(context.queue_size);
+            let (removed_tx, removed_rx) = oneshot::channel();
+            entry.insert(PeerSender { tx, _removed: removed_tx });
+            tokio::spawn(connect_and_send(*node, context.clone(), log.new(o!()), rx, removed_rx));
+        }
+    }
+}
+
+/// The sending half of a peer's send task.
+struct PeerSender {
+    tx: mpsc::Sender<Message>,
+    /// Dropped along with the sender to tell the send task the peer is gone.
+    _removed: oneshot::Sender<()>,
+}
+
+/// Jittered exponential backoff between attempts to connect to a peer, so a cluster that lost a
+/// node doesn't retry in lockstep.
+struct Backoff {
+    min: Duration,
+    max: Duration,
+    /// The wait before the next attempt, before jitter.
+    next: Duration,
+}
+
+impl Backoff {
+    fn new(min: Duration, max: Duration) -> Backoff {
+        Backoff { min, max, next: min }
+    }
+
+    /// The time to wait before the next attempt, somewhere between half and all of a wait that
+    /// doubles with each attempt.
+    fn delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = std::cmp::min(self.next * 2, self.max);
+        let jitter = rand::thread_rng().gen_range(0..=delay.as_millis() as u64 / 2);
+        delay - Duration::from_millis(jitter)
+    }
+
+    /// Whether the waits have grown as long as they get.
+    fn exhausted(&self) -> bool {
+        self.next >= self.max
+    }
+
+    fn reset(&mut self) {
+        self.next = self.min;
+    }
+}
+
+/// Tells raft about changes in the state of the connection to a peer.
+struct ConnectionReporter {
+    node_id: NodeId,
+    /// The last state reported. Raft assumes peers are connected until told otherwise.
+    state: ConnectionState,
+    events: UnboundedSender<Message>,
+}
+
+impl ConnectionReporter {
+    fn report(&mut self, state: ConnectionState) {
+        if state != self.state {
+            self.state = state;
+            let command = Command::Connection { node_id: self.node_id, state };
+            // the event loop is only gone during shutdown
+            let _ = self.events.send(Message::new(Address::Local, Address::Local, command));
+        }
+    }
+}
+
 /// Create a new send task for a given node.
 ///
 /// * `node` - The node which messages will be sent to.
+/// * `context` - What the send tasks of every peer share.
 /// * `out_rx` - The channel messages to send are written to.
+/// * `removed` - Closed when the node is no longer a peer, to stop reconnecting to it.
 async fn connect_and_send(
     node: Node,
+    context: SendContext,
     log: slog::Logger,
     mut out_rx: Receiver<Message>,
+    mut removed: oneshot::Receiver<()>,
 ) -> Result<()> {
+    let mut backoff = Backoff::new(context.min_backoff, context.max_backoff);
+    let mut reporter = ConnectionReporter {
+        node_id: node.id,
+        state: ConnectionState::Connected,
+        events: context.events.clone(),
+    };
     loop {
+        let attempted = Instant::now();
         match TcpStream::connect(node.addr).await {
-            Ok(socket) => match send_messages(socket, &mut out_rx).await {
+            Ok(socket) => match send_to_peer(&context, node.id, socket, &mut out_rx, &mut reporter).await {
                 Ok(()) => break Ok(()),
                 Err(err) => {
                     error!(log, "Failed sending to Raft peer"; "peer" => node.addr, "error" => format!("{:?}", err))
@@ -124,22 +360,50 @@ async fn connect_and_send(
                 error!(log, "Failed connecting to Raft peer"; "peer" => node.addr, "error" => format!("{:?}", err))
             }
         }
-        // TODO: use back-off
-        tokio::time::sleep(Duration::from_millis(1000)).await;
+        // a connection that stayed up for a while starts backing off from the beginning again,
+        // but a peer that drops us straight after the handshake keeps being backed off from
+        if reporter.state == ConnectionState::Connected && attempted.elapsed() >= context.max_backoff {
+            backoff.reset();
+        }
+        let delay = backoff.delay();
+        reporter.report(if backoff.exhausted() { ConnectionState::Down } else { ConnectionState::BackingOff });
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            // the peer was removed from the cluster
+            _ = &mut removed => break Ok(()),
+        }
     }
 }
 
-/// Write messages to socket in a loop.
+/// Sends messages over `socket`, first establishing TLS with node `id` if it is configured.
+async fn send_to_peer(
+    context: &SendContext,
+    id: NodeId,
+    socket: TcpStream,
+    out_rx: &mut mpsc::Receiver<Message>,
+    reporter: &mut ConnectionReporter,
+) -> Result<()> {
+    match &context.tls {
+        Some(tls) => send_messages(&context.hello, id, tls.connect(id, socket).await?, out_rx, reporter).await,
+        None => send_messages(&context.hello, id, socket, out_rx, reporter).await,
+    }
+}
+
+/// Write messages to socket in a loop, once node `id` has accepted our introduction.
 ///
-/// * `socket` - The TCP socket messages will be written to.
+/// * `socket` - The socket messages will be written to.
 /// * `out_rx` - The channel from which to receive new messages to write.
-async fn send_messages(socket: TcpStream, out_rx: &mut mpsc::Receiver<Message>) -> Result<()> {
-    // identify frames with a header indicating length
-    let length_delimited = FramedWrite::new(socket, LengthDelimitedCodec::new());
-    let mut stream = tokio_serde::SymmetricallyFramed::new(
-        length_delimited,
-        tokio_serde::formats::SymmetricalJson::default(),
-    );
+/// * `reporter` - Told once the peer has accepted the connection.
+async fn send_messages<S: AsyncRead + AsyncWrite + Unpin>(
+    hello: &Hello,
+    id: NodeId,
+    mut socket: S,
+    out_rx: &mut mpsc::Receiver<Message>,
+    reporter: &mut ConnectionReporter,
+) -> Result<()> {
+    let version = handshake::connect(&mut socket, hello, id).await?;
+    reporter.report(ConnectionState::Connected);
+    let mut stream = FramedWrite::new(socket, MessageCodec::new(version));
 
     let mut s = stream::ReceiverStream(out_rx);
     while let Some(message) = s.next().await {
@@ -151,9 +415,9 @@ async fn send_messages(socket: TcpStream, out_rx: &mut mpsc::Receiver<Message>)
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::MAX_PROTOCOL_VERSION;
     use crate::raft::Command;
     use crate::rpc::Address;
-    use bytes::Bytes;
     use futures::SinkExt;
 
     use rand::Rng;
@@ -161,6 +425,26 @@ mod tests {
     use tokio::sync::mpsc;
     use tokio_util::codec::FramedWrite;
 
+    fn hello(id: NodeId) -> Hello {
+        Hello::new(&RaftConfig {
+            id,
+            ..RaftConfig::default()
+        })
+    }
+
+    fn context(id: NodeId, tls: Option<Tls>) -> SendContext {
+        let (events, _) = mpsc::unbounded_channel();
+        SendContext {
+            hello: hello(id),
+            tls,
+            queue_size: 10,
+            min_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(40),
+            events,
+            dropped: DroppedMessages::new(),
+        }
+    }
+
     #[tokio::test]
     async fn read_message() -> Result<()> {
         let port: u32 = rand::thread_rng().gen_range(1025..65535);
@@ -168,19 +452,25 @@ mod tests {
         let listener = TcpListener::bind(&addr).await?;
         let (tx, mut rx) = mpsc::unbounded_channel();
         let (shutdown_tx, _shutdown_rx) = tokio::sync::broadcast::channel(1);
+        let (_peers_tx, peers_rx) = watch::channel(vec![Node {
+            id: 1,
+            addr: addr.parse()?,
+        }]);
         tokio::spawn(receive_task(
             get_root_logger().new(o!()),
             shutdown_tx.subscribe(),
             listener,
+            hello(2),
+            None,
+            peers_rx,
             tx,
         ));
-        let stream = TcpStream::connect(&addr).await?;
-        let out_msg = Message::new(Address::Peer(1), Address::Peer(2), Command::Tick);
+        let mut stream = TcpStream::connect(&addr).await?;
+        let out_msg = Message::new(Address::Peer(1), Address::Peer(2), Command::Noop);
 
-        let mut frame = FramedWrite::new(stream, LengthDelimitedCodec::new());
-        frame
-            .send(Bytes::from(serde_json::to_string(&out_msg)?))
-            .await?;
+        handshake::connect(&mut stream, &hello(1), 2).await?;
+        let mut frame = FramedWrite::new(stream, MessageCodec::new(MAX_PROTOCOL_VERSION));
+        frame.send(out_msg.clone()).await?;
 
         match rx.recv().await {
             Some(in_msg) => assert_eq!(out_msg, in_msg),
@@ -190,6 +480,42 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn rejects_local_commands() -> Result<()> {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (local, nodes) = (hello(2), [Node { id: 1, addr: "127.0.0.1:6669".parse()? }]);
+        let loopback = Identity::Address("127.0.0.1".parse()?);
+        let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+        let receive = stream_messages(get_root_logger().new(o!()), server, &local, &nodes, loopback, deadline, tx);
+        let send = async move {
+            handshake::connect(&mut client, &hello(1), 2).await?;
+            let mut frame = FramedWrite::new(client, MessageCodec::new(MAX_PROTOCOL_VERSION));
+            let command = Command::Connection { node_id: 2, state: ConnectionState::Down };
+            frame.send(Message::new(Address::Peer(1), Address::Local, command)).await
+        };
+
+        let (received, sent) = tokio::join!(receive, send);
+        sent?;
+        assert!(received.is_err());
+        assert!(rx.recv().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn drops_silent_peers() -> Result<()> {
+        let (_client, server) = tokio::io::duplex(1024);
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let (local, nodes) = (hello(2), [Node { id: 1, addr: "127.0.0.1:6669".parse()? }]);
+        let deadline = Instant::now() + Duration::from_millis(10);
+        let loopback = Identity::Address("127.0.0.1".parse()?);
+        let receive = stream_messages(get_root_logger().new(o!()), server, &local, &nodes, loopback, deadline, tx);
+
+        // the peer connected but never introduced itself
+        assert!(tokio::time::timeout(Duration::from_secs(1), receive).await.unwrap().is_err());
+        Ok(())
+    }
+
     use crate::logger::get_root_logger;
     use futures::StreamExt;
     use tokio_util::codec::FramedRead;
@@ -198,44 +524,185 @@ mod tests {
     async fn send_message() -> Result<()> {
         let listener = TcpListener::bind("127.0.0.1:8080").await?;
         let (tx, rx) = mpsc::unbounded_channel();
-        let (shutdown_tx, _shutdown_rx) = tokio::sync::broadcast::channel(1);
+        let (_peers_tx, peers_rx) = watch::channel(vec![Node {
+            id: 2,
+            addr: "127.0.0.1:8080".parse()?,
+        }]);
         tokio::spawn(send_task(
             get_root_logger().new(o!()),
-            shutdown_tx.subscribe(),
-            1,
-            vec![Node {
-                id: 2,
-                addr: "127.0.0.1:8080".parse()?,
-            }],
+            context(1, None),
+            peers_rx,
             rx,
         ));
 
         let out_msg = Message::new(Address::Peer(1), Address::Peer(2), Command::Tick);
         let out_msg2 = Message::new(Address::Peer(1), Address::Peer(2), Command::Tick);
-        tx.send(out_msg).map_err(|err| RaftError::from(err))?;
-
-        let mut stream = stream::ListenerStream(listener);
-        let (stream, _addr) = stream.next().await.unwrap()?;
-        let mut frame = FramedRead::new(stream, LengthDelimitedCodec::new());
+        tx.send(out_msg).map_err(RaftError::from)?;
+
+        let (mut stream, _addr) = listener.accept().await?;
+        let nodes = [Node {
+            id: 1,
+            addr: "127.0.0.1:8081".parse()?,
+        }];
+        handshake::accept(&mut stream, &hello(2), &nodes, Identity::Address("127.0.0.1".parse()?)).await?;
+        let mut frame = FramedRead::new(stream, MessageCodec::new(MAX_PROTOCOL_VERSION));
         match frame.next().await {
-            Some(Ok(mut bytes)) => {
-                let in_msg = serde_json::from_slice(&mut bytes.as_mut())?;
-                assert_eq!(out_msg2, in_msg);
-            }
+            Some(Ok(in_msg)) => assert_eq!(out_msg2, in_msg),
             _ => panic!(),
         };
 
         Ok(())
     }
+
+    /// Writes a CA and certificates for the given nodes to a temporary directory, returning the
+    /// TLS config of each node.
+    fn tls_configs(ids: &[NodeId]) -> Vec<crate::config::TlsConfig> {
+        use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+
+        let dir = crate::test::temp_dir();
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(params).unwrap();
+        let ca_path = dir.join("ca.pem");
+        std::fs::write(&ca_path, ca.serialize_pem().unwrap()).unwrap();
+
+        ids.iter()
+            .map(|id| {
+                let cert = Certificate::from_params(CertificateParams::new(vec![crate::tls::node_name(*id)])).unwrap();
+                let config = crate::config::TlsConfig {
+                    cert: dir.join(format!("{}.pem", id)),
+                    key: dir.join(format!("{}.key", id)),
+                    ca: ca_path.clone(),
+                };
+                std::fs::write(&config.cert, cert.serialize_pem_with_signer(&ca).unwrap()).unwrap();
+                std::fs::write(&config.key, cert.serialize_private_key_pem()).unwrap();
+                config
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_peer_certificates() -> Result<()> {
+        let configs = tls_configs(&[1, 2, 9]);
+        let tls: Vec<Tls> = configs.iter().map(Tls::new).collect::<Result<_>>()?;
+        let port: u16 = rand::thread_rng().gen_range(1025..65535);
+        let addr: std::net::SocketAddr = format!("127.0.0.1:{}", port).parse()?;
+        let listener = TcpListener::bind(addr).await?;
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (_peers_tx, peers_rx) = watch::channel(vec![Node { id: 2, addr }]);
+        let (shutdown_tx, _shutdown_rx) = tokio::sync::broadcast::channel(1);
+        tokio::spawn(receive_task(
+            get_root_logger().new(o!()),
+            shutdown_tx.subscribe(),
+            listener,
+            hello(1),
+            Some(tls[0].clone()),
+            peers_rx,
+            tx,
+        ));
+
+        // node 9 has a certificate from the right CA, but isn't in the cluster
+        for (tls, from) in [(&tls[2], 9), (&tls[1], 2)].iter() {
+            let (tx, mut out_rx) = mpsc::channel(1);
+            tx.send(Message::new(Address::Peer(*from), Address::Peer(1), Command::Noop)).await.unwrap();
+            drop(tx);
+            let stream = TcpStream::connect(addr).await?;
+            let context = context(*from, Some((*tls).clone()));
+            let mut reporter = ConnectionReporter {
+                node_id: 1,
+                state: ConnectionState::Connected,
+                events: context.events.clone(),
+            };
+            let _ = send_to_peer(&context, 1, stream, &mut out_rx, &mut reporter).await;
+        }
+
+        let in_msg = rx.recv().await.unwrap();
+        assert_eq!(in_msg.from, Address::Peer(2));
+        assert!(tokio::time::timeout(Duration::from_millis(100), rx.recv()).await.is_err());
+        Ok(())
+    }
+
+    async fn next_state(events: &mut UnboundedReceiver<Message>) -> ConnectionState {
+        match events.recv().await.unwrap().command {
+            Command::Connection { state, .. } => state,
+            command => panic!("unexpected {:?}", command),
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_reconnection() -> Result<()> {
+        let port: u16 = rand::thread_rng().gen_range(1025..65535);
+        let addr: std::net::SocketAddr = format!("127.0.0.1:{}", port).parse()?;
+        let (events, mut events_rx) = mpsc::unbounded_channel();
+        let (tx, out_rx) = mpsc::channel(10);
+        let (_removed_tx, removed_rx) = oneshot::channel();
+        tokio::spawn(connect_and_send(
+            Node { id: 2, addr },
+            SendContext { events, ..context(1, None) },
+            get_root_logger().new(o!()),
+            out_rx,
+            removed_rx,
+        ));
+
+        // nobody is listening yet, so the retries back off until the peer is considered down
+        assert_eq!(next_state(&mut events_rx).await, ConnectionState::BackingOff);
+        assert_eq!(next_state(&mut events_rx).await, ConnectionState::Down);
+
+        let listener = TcpListener::bind(addr).await?;
+        let (mut stream, _) = listener.accept().await?;
+        handshake::accept(&mut stream, &hello(2), &[Node { id: 1, addr }], Identity::Address(addr.ip())).await?;
+        assert_eq!(next_state(&mut events_rx).await, ConnectionState::Connected);
+
+        let out_msg = Message::new(Address::Peer(1), Address::Peer(2), Command::Tick);
+        tx.send(out_msg.clone()).await.unwrap();
+        let mut frame = FramedRead::new(stream, MessageCodec::new(MAX_PROTOCOL_VERSION));
+        assert_eq!(frame.next().await.unwrap()?, out_msg);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn backs_off_from_flapping_peer() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let (events, mut events_rx) = mpsc::unbounded_channel();
+        let (tx, out_rx) = mpsc::channel(10);
+        let (_removed_tx, removed_rx) = oneshot::channel();
+        tokio::spawn(connect_and_send(
+            Node { id: 2, addr },
+            SendContext { events, ..context(1, None) },
+            get_root_logger().new(o!()),
+            out_rx,
+            removed_rx,
+        ));
+        // a dropped connection is only noticed when something is written to it
+        tokio::spawn(async move {
+            loop {
+                let _ = tx.try_send(Message::new(Address::Peer(1), Address::Peer(2), Command::Tick));
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        });
+
+        // the peer accepts every connection and hangs up straight away
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let _ = handshake::accept(&mut stream, &hello(2), &[Node { id: 1, addr }], Identity::Address(addr.ip())).await;
+            }
+        });
+
+        let down = async {
+            while next_state(&mut events_rx).await != ConnectionState::Down {}
+        };
+        assert!(tokio::time::timeout(Duration::from_secs(2), down).await.is_ok());
+        Ok(())
+    }
 }
 
 mod stream {
     use crate::rpc::Message;
     use futures::task::{Context, Poll};
-    use std::net::SocketAddr;
     use std::pin::Pin;
-    use tokio::net::{TcpListener, TcpStream};
-    use tokio::sync::mpsc::{Receiver, UnboundedReceiver};
+    use tokio::sync::mpsc::Receiver;
     use tokio_stream::Stream;
     pub struct ReceiverStream<'a, T>(pub &'a mut Receiver<T>);
 
@@ -246,26 +713,4 @@ mod stream {
             self.0.poll_recv(cx)
         }
     }
-
-    pub struct UnboundedReceiverStream<T>(pub UnboundedReceiver<T>);
-
-    impl Stream for UnboundedReceiverStream<Message> {
-        type Item = Message;
-
-        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-            self.0.poll_recv(cx)
-        }
-    }
-
-    pub struct ListenerStream(pub TcpListener);
-
-    impl Stream for ListenerStream {
-        type Item = std::io::Result<(TcpStream, SocketAddr)>;
-
-        fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-            let stream = unsafe { Pin::map_unchecked_mut(self, |x| &mut x.0) };
-
-            stream.poll_accept(cx).map(|x| Some(x))
-        }
-    }
 }
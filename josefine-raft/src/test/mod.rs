@@ -1,8 +1,15 @@
+use std::cell::RefCell;
+use std::path::PathBuf;
+
 use tokio::sync::mpsc::{self, UnboundedReceiver};
+use uuid::Uuid;
+
+use crate::{config::RaftConfig, follower::Follower, fsm::{Fsm, Instruction}, leader::Leader, logger::get_root_logger, raft::{Apply, Command, Node, Raft, RaftHandle}, rpc::Message};
 
-use crate::{config::RaftConfig, follower::Follower, fsm::{Fsm, Instruction}, logger::get_root_logger, raft::Raft, rpc::Message};
+#[cfg(test)]
+pub(crate) mod sim;
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub(crate) struct TestFsm { state: u8 }
 
 impl Fsm for TestFsm {
@@ -12,15 +19,83 @@ impl Fsm for TestFsm {
         Ok(input)
     }
 
-    fn query(&mut self, data: Vec<u8>) -> josefine_core::error::Result<Vec<u8>> {
-        todo!()
+    fn query(&mut self, _: Vec<u8>) -> josefine_core::error::Result<Vec<u8>> {
+        Ok(vec![self.state])
+    }
+
+    fn snapshot(&self) -> josefine_core::error::Result<Vec<u8>> {
+        Ok(vec![self.state])
+    }
+
+    fn restore(&mut self, snapshot: Vec<u8>) -> josefine_core::error::Result<()> {
+        self.state = snapshot[0];
+        Ok(())
+    }
+}
+
+/// A directory under the system temp directory that is removed, with everything in it, when the
+/// guard is dropped.
+struct TempDir {
+    path: PathBuf,
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+thread_local! {
+    /// The directories handed out on this thread. Every test runs on its own thread, so they
+    /// are cleaned up once the test is over.
+    static DATA_DIRECTORIES: RefCell<Vec<TempDir>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A fresh directory that is removed once the calling test is over.
+pub(crate) fn temp_dir() -> PathBuf {
+    let path = std::env::temp_dir().join(format!("josefine-{}", Uuid::new_v4()));
+    DATA_DIRECTORIES.with(|dirs| dirs.borrow_mut().push(TempDir { path: path.clone() }));
+    path
+}
+
+/// A default config with a fresh data directory, so tests don't observe each other's logs.
+/// Proposals are appended as soon as they arrive rather than batched.
+pub(crate) fn new_config() -> RaftConfig {
+    RaftConfig {
+        data_directory: temp_dir(),
+        max_batch_bytes: 0,
+        ..RaftConfig::default()
     }
 }
 
 pub(crate) fn new_follower() -> ((UnboundedReceiver<Message>, UnboundedReceiver<Instruction>), Raft<Follower>) {
-        let config = RaftConfig::default();
+        let config = new_config();
         let log = get_root_logger();
         let (rpc_tx, rpc_rx) = mpsc::unbounded_channel();
         let (fsm_tx, fsm_rx) = mpsc::unbounded_channel();
         ((rpc_rx, fsm_rx), Raft::new(config, log.new(o!()), rpc_tx, fsm_tx).unwrap())
     }
+
+/// A follower in a cluster with one other voter, node 2.
+pub(crate) fn new_follower_with_peer() -> ((UnboundedReceiver<Message>, UnboundedReceiver<Instruction>), Raft<Follower>) {
+    let (rx, mut node) = new_follower();
+    node.membership.config.voters.push(Node {
+        id: 2,
+        addr: "127.0.0.1:6670".parse().unwrap(),
+    });
+    (rx, node)
+}
+
+/// The leader of term 1 in a cluster with one other voter, node 2, which voted for it.
+pub(crate) fn new_leader_with_peer() -> ((UnboundedReceiver<Message>, UnboundedReceiver<Instruction>), Raft<Leader>) {
+    let (rx, node) = new_follower_with_peer();
+    let node = node
+        .apply(Command::Timeout)
+        .unwrap()
+        .apply(Command::VoteResponse { term: 1, from: 2, granted: true })
+        .unwrap();
+    match node {
+        RaftHandle::Leader(leader) => (rx, *leader),
+        _ => panic!("node 2's vote should have elected the node"),
+    }
+}
@@ -0,0 +1,441 @@
+//! A deterministic simulation of a raft cluster. Every node runs on the current thread against a
+//! virtual clock, and messages between nodes pass through a simulated network that drops, delays,
+//! duplicates, reorders and partitions them using a seeded random number generator, so a failing
+//! run can be reproduced exactly from its seed.
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures::FutureExt;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+use josefine_core::error::{JosefineError, Result};
+
+use crate::clock;
+use crate::config::RaftConfig;
+use crate::fsm::{Driver, Instruction};
+use crate::history::{ClientId, Input, OpId, Recorder};
+use crate::logger::get_root_logger;
+use crate::raft::{Apply, Command, Entry, Node, NodeId, RaftHandle, State, Term};
+use crate::rpc::{Address, Message, Request, Response};
+use crate::test::{new_config, TestFsm};
+
+/// How often each node is ticked.
+const TICK: Duration = Duration::from_millis(10);
+
+/// The faults the simulated network introduces.
+#[derive(Clone, Debug)]
+pub(crate) struct Network {
+    /// The probability that a message is lost.
+    pub drop: f64,
+    /// The probability that a message is delivered twice.
+    pub duplicate: f64,
+    /// The probability that a message is held back for an extra `max_delay`, so that it arrives
+    /// after messages sent later.
+    pub reorder: f64,
+    /// The shortest time a message takes to arrive.
+    pub min_delay: Duration,
+    /// The longest time a message takes to arrive, unless it is held back.
+    pub max_delay: Duration,
+}
+
+impl Default for Network {
+    fn default() -> Self {
+        Network {
+            drop: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            min_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+        }
+    }
+}
+
+struct SimNode {
+    /// Only missing while a command is being applied.
+    raft: Option<RaftHandle>,
+    rpc_rx: UnboundedReceiver<Message>,
+    fsm_rx: UnboundedReceiver<Instruction>,
+    driver: Driver<TestFsm>,
+}
+
+/// A message on its way through the network.
+struct InFlight {
+    deliver_at: Duration,
+    /// Breaks ties between messages delivered at the same time, in the order they were sent.
+    seq: u64,
+    to: NodeId,
+    message: Message,
+}
+
+pub(crate) struct Simulation {
+    rng: StdRng,
+    /// How much virtual time has passed.
+    now: Duration,
+    pub network: Network,
+    nodes: BTreeMap<NodeId, SimNode>,
+    in_flight: Vec<InFlight>,
+    seq: u64,
+    /// While partitioned, nodes in this set can only reach each other.
+    partition: Option<BTreeSet<NodeId>>,
+    /// The leader elected in each term, to check that there is never more than one.
+    leaders: BTreeMap<Term, NodeId>,
+    /// The responses to client requests, in the order they were received.
+    pub responses: Vec<(Vec<u8>, Result<Response>)>,
+    /// The proposals and queries clients made, to check they saw a linearizable history.
+    pub history: Recorder,
+    /// The operation in the history of each request that hasn't been answered yet.
+    pending: HashMap<Vec<u8>, OpId>,
+}
+
+impl Simulation {
+    /// Starts a cluster of `size` nodes, with ids from 1, on a network without faults.
+    pub fn new(seed: u64, size: NodeId) -> Simulation {
+        clock::simulate(seed);
+
+        let addr = |id: NodeId| SocketAddr::from(([127, 0, 0, 1], 7000 + id as u16));
+        let mut nodes = BTreeMap::new();
+        for id in 1..=size {
+            let config = RaftConfig {
+                id,
+                ip: addr(id).ip(),
+                port: addr(id).port(),
+                nodes: (1..=size)
+                    .filter(|peer| *peer != id)
+                    .map(|peer| Node { id: peer, addr: addr(peer) })
+                    .collect(),
+                ..new_config()
+            };
+
+            let logger = get_root_logger().new(o!());
+            let (rpc_tx, rpc_rx) = mpsc::unbounded_channel();
+            let (fsm_tx, fsm_rx) = mpsc::unbounded_channel();
+            // instructions are handed to the driver directly, so its own channel goes unused
+            let (_, driver_rx) = mpsc::unbounded_channel();
+            let raft = RaftHandle::new(logger.clone(), config.clone(), rpc_tx.clone(), fsm_tx);
+            let driver = Driver::new(logger, &config, driver_rx, rpc_tx, TestFsm::default(), raft.metrics())
+                .expect("could not create driver");
+            nodes.insert(id, SimNode { raft: Some(raft), rpc_rx, fsm_rx, driver });
+        }
+
+        let mut sim = Simulation {
+            rng: StdRng::seed_from_u64(seed),
+            now: Duration::from_secs(0),
+            network: Network::default(),
+            nodes,
+            in_flight: Vec::new(),
+            seq: 0,
+            partition: None,
+            leaders: BTreeMap::new(),
+            responses: Vec::new(),
+            history: Recorder::new(),
+            pending: HashMap::new(),
+        };
+        // replay anything the nodes recovered from disk
+        for id in sim.node_ids() {
+            sim.pump(id);
+        }
+        sim
+    }
+
+    pub fn node_ids(&self) -> Vec<NodeId> {
+        self.nodes.keys().cloned().collect()
+    }
+
+    pub fn node(&self, id: NodeId) -> &RaftHandle {
+        self.nodes[&id].raft.as_ref().expect("node is being applied to")
+    }
+
+    /// The current leader, if any node believes it is one. A leader that was deposed without
+    /// hearing about it yet is ignored in favour of the one with the later term.
+    pub fn leader(&self) -> Option<NodeId> {
+        self.nodes
+            .keys()
+            .cloned()
+            .filter(|id| self.node(*id).is_leader())
+            .max_by_key(|id| state(self.node(*id)).current_term)
+    }
+
+    /// The entries in a node's log that haven't been compacted.
+    pub fn log(&self, id: NodeId) -> Vec<Entry> {
+        let entries = match self.node(id) {
+            RaftHandle::Follower(raft) => raft.log.get_range(raft.log.snapshot_index(), raft.log.next_index() - 1),
+            RaftHandle::Candidate(raft) => raft.log.get_range(raft.log.snapshot_index(), raft.log.next_index() - 1),
+            RaftHandle::Leader(raft) => raft.log.get_range(raft.log.snapshot_index(), raft.log.next_index() - 1),
+        };
+        entries.expect("could not read log")
+    }
+
+    /// Isolates `nodes` from the rest of the cluster, including messages already in flight.
+    pub fn partition(&mut self, nodes: &[NodeId]) {
+        self.partition = Some(nodes.iter().cloned().collect());
+    }
+
+    pub fn heal(&mut self) {
+        self.partition = None;
+    }
+
+    /// Sends a client request to a node. Proposals and queries are recorded in the history, with
+    /// the node they were sent to standing in for the client.
+    pub fn request(&mut self, to: NodeId, id: Vec<u8>, req: Request) {
+        let input = match &req {
+            Request::Propose(data) => Some(Input::Mutate(data.clone())),
+            Request::Query(data) => Some(Input::Query(data.clone())),
+            _ => None,
+        };
+        if let Some(input) = input {
+            let op = self.history.invoke(to as ClientId, input);
+            self.pending.insert(id.clone(), op);
+        }
+        self.apply(to, Command::ClientRequest { id, req });
+    }
+
+    /// Advances virtual time, ticking every node and delivering messages as they arrive.
+    pub fn run_for(&mut self, duration: Duration) {
+        let until = self.now + duration;
+        while self.now < until {
+            self.now += TICK;
+            clock::advance(TICK);
+
+            self.in_flight.sort_by_key(|msg| (msg.deliver_at, msg.seq));
+            let due = self.in_flight.iter().take_while(|msg| msg.deliver_at <= self.now).count();
+            for msg in self.in_flight.drain(..due).collect::<Vec<_>>() {
+                let from = match msg.message.from {
+                    Address::Peer(from) => from,
+                    _ => continue,
+                };
+                if self.connected(from, msg.to) {
+                    self.apply(msg.to, msg.message.command);
+                }
+            }
+
+            for id in self.node_ids() {
+                self.apply(id, Command::Tick);
+            }
+        }
+    }
+
+    /// Runs until a leader has been elected, failing if that takes longer than `timeout`.
+    pub fn run_until_leader(&mut self, timeout: Duration) -> NodeId {
+        let until = self.now + timeout;
+        while self.now < until {
+            if let Some(leader) = self.leader() {
+                return leader;
+            }
+            self.run_for(TICK);
+        }
+        panic!("no leader was elected within {:?}", timeout);
+    }
+
+    fn connected(&self, a: NodeId, b: NodeId) -> bool {
+        match &self.partition {
+            Some(partition) => partition.contains(&a) == partition.contains(&b),
+            None => true,
+        }
+    }
+
+    fn apply(&mut self, id: NodeId, cmd: Command) {
+        let node = self.nodes.get_mut(&id).expect("no such node");
+        let raft = node.raft.take().expect("node is being applied to");
+        let raft = raft.apply(cmd).expect("could not apply command");
+        if raft.is_leader() {
+            let term = state(&raft).current_term;
+            let leader = *self.leaders.entry(term).or_insert(id);
+            assert_eq!(leader, id, "two leaders were elected in term {}", term);
+        }
+        node.raft = Some(raft);
+        self.pump(id);
+    }
+
+    /// Handles everything a node has produced: instructions for its state machine, and messages
+    /// for itself, its peers or clients.
+    fn pump(&mut self, id: NodeId) {
+        loop {
+            let node = self.nodes.get_mut(&id).expect("no such node");
+            if let Some(Some(instruction)) = node.fsm_rx.recv().now_or_never() {
+                futures::executor::block_on(node.driver.exec(instruction)).expect("could not apply to state machine");
+                continue;
+            }
+
+            let msg = match node.rpc_rx.recv().now_or_never() {
+                Some(Some(msg)) => msg,
+                _ => break,
+            };
+            match msg.to {
+                Address::Local => self.apply(id, msg.command),
+                Address::Client => {
+                    if let Command::ClientResponse { id, res } = msg.command {
+                        if let Some(op) = self.pending.remove(&id) {
+                            let output = res.clone().and_then(|res| match res {
+                                Response::State(state) => Ok(state),
+                                res => Err(JosefineError::Internal {
+                                    error_msg: format!("unexpected response {:?}", res),
+                                }),
+                            });
+                            self.history.complete(op, output);
+                        }
+                        self.responses.push((id, res));
+                    }
+                }
+                Address::Peer(to) => self.send(id, to, msg.command),
+                Address::Peers => {
+                    for peer in self.node(id).peers() {
+                        self.send(id, peer.id, msg.command.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, from: NodeId, to: NodeId, command: Command) {
+        if !self.nodes.contains_key(&to) || !self.connected(from, to) || self.rng.gen_bool(self.network.drop) {
+            return;
+        }
+
+        let copies = if self.rng.gen_bool(self.network.duplicate) { 2 } else { 1 };
+        for _ in 0..copies {
+            let mut delay = self.rng.gen_range(self.network.min_delay..=self.network.max_delay);
+            if self.rng.gen_bool(self.network.reorder) {
+                delay += self.network.max_delay;
+            }
+            self.seq += 1;
+            self.in_flight.push(InFlight {
+                deliver_at: self.now + delay,
+                seq: self.seq,
+                to,
+                message: Message::new(Address::Peer(from), Address::Peer(to), command.clone()),
+            });
+        }
+    }
+}
+
+/// The state shared by every role.
+fn state(raft: &RaftHandle) -> &State {
+    match raft {
+        RaftHandle::Follower(raft) => &raft.state,
+        RaftHandle::Candidate(raft) => &raft.state,
+        RaftHandle::Leader(raft) => &raft.state,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rand::Rng;
+
+    use super::{Network, Simulation};
+    use crate::history::check;
+    use crate::raft::EntryType;
+    use crate::rpc::Request;
+    use crate::test::TestFsm;
+
+    fn lossy() -> Network {
+        Network {
+            drop: 0.1,
+            duplicate: 0.1,
+            reorder: 0.1,
+            ..Network::default()
+        }
+    }
+
+    #[test]
+    fn elects_a_leader_despite_faults() {
+        for seed in 0..10 {
+            let mut sim = Simulation::new(seed, 3);
+            sim.network = lossy();
+            sim.run_until_leader(Duration::from_secs(10));
+        }
+    }
+
+    #[test]
+    fn replicates_across_a_partition() {
+        let mut sim = Simulation::new(42, 5);
+        sim.network = lossy();
+        let leader = sim.run_until_leader(Duration::from_secs(10));
+
+        // the old leader is cut off with one follower, and the majority elects a new one
+        let follower = sim.node_ids().into_iter().find(|id| *id != leader).unwrap();
+        sim.partition(&[leader, follower]);
+        sim.run_for(Duration::from_secs(5));
+        let new_leader = sim.run_until_leader(Duration::from_secs(10));
+        assert!(new_leader != leader && new_leader != follower);
+
+        for i in 0..10u8 {
+            sim.request(new_leader, vec![i], Request::Propose(vec![i]));
+        }
+        sim.heal();
+        sim.run_for(Duration::from_secs(10));
+
+        // healing may have cost the leader its term, and entries from an earlier term are only
+        // committed along with one from the current term
+        let leader = sim.leader().expect("no leader after healing");
+        sim.request(leader, vec![10], Request::Propose(vec![10]));
+        sim.run_for(Duration::from_secs(2));
+
+        // every node ends up with the same log, which holds every write that was acknowledged;
+        // the others were never committed, so a later leader was free to discard them
+        let log = sim.log(leader);
+        for id in sim.node_ids() {
+            assert_eq!(sim.log(id), log);
+        }
+        let answered: Vec<_> = sim.responses.iter().filter(|(_, res)| res.is_ok()).map(|(id, _)| id[0]).collect();
+        assert!(answered.contains(&10));
+        for id in answered {
+            assert!(log.iter().any(|entry| entry.entry_type == EntryType::Entry { data: vec![id] }));
+        }
+    }
+
+    #[test]
+    fn histories_are_linearizable() {
+        for seed in 0..10 {
+            let mut sim = Simulation::new(seed, 3);
+            sim.network = lossy();
+            sim.run_until_leader(Duration::from_secs(10));
+
+            for i in 0..60u8 {
+                // the leader is cut off for a while, so some requests reach it after it was deposed
+                match i {
+                    20 => {
+                        let leader = sim.leader().unwrap();
+                        sim.partition(&[leader]);
+                    }
+                    40 => sim.heal(),
+                    _ => {}
+                }
+
+                if let Some(leader) = sim.leader() {
+                    let req = if sim.rng.gen_bool(0.5) {
+                        Request::Propose(vec![i])
+                    } else {
+                        Request::Query(vec![])
+                    };
+                    sim.request(leader, vec![i], req);
+                }
+                let pause = Duration::from_millis(sim.rng.gen_range(0..200));
+                sim.run_for(pause);
+            }
+            sim.run_for(Duration::from_secs(5));
+
+            if let Err(violation) = check(&sim.history.events(), TestFsm::default) {
+                panic!("seed {}: {}", seed, violation);
+            }
+        }
+    }
+
+    #[test]
+    fn runs_are_reproducible() {
+        let run = || {
+            let mut sim = Simulation::new(7, 3);
+            sim.network = lossy();
+            let leader = sim.run_until_leader(Duration::from_secs(10));
+            sim.request(leader, vec![1], Request::Propose(vec![1]));
+            sim.run_for(Duration::from_secs(2));
+            let terms: Vec<_> = sim.node_ids().iter().map(|id| super::state(sim.node(*id)).current_term).collect();
+            (leader, terms, sim.log(leader), sim.responses.len())
+        };
+        assert_eq!(run(), run());
+    }
+}
@@ -0,0 +1,112 @@
+//! Mutual TLS between peers. Both ends of a connection present a certificate issued by the
+//! cluster's CA, and a certificate is tied to a node by being valid for its name, `node-<id>`.
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{
+    AllowAnyAuthenticatedClient, Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig, Session,
+};
+use tokio_rustls::webpki::{DNSNameRef, EndEntityCert};
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+
+use josefine_core::error::{JosefineError, Result};
+
+use crate::config::TlsConfig;
+use crate::raft::{Node, NodeId};
+
+/// The name a node's certificate must be valid for.
+pub fn node_name(id: NodeId) -> String {
+    format!("node-{}", id)
+}
+
+/// Accepts and opens authenticated connections between peers.
+#[derive(Clone)]
+pub struct Tls {
+    acceptor: TlsAcceptor,
+    connector: TlsConnector,
+}
+
+impl Tls {
+    pub fn new(config: &TlsConfig) -> Result<Tls> {
+        let certs = read_certs(&config.cert)?;
+        let key = read_key(&config.key)?;
+        let mut roots = RootCertStore::empty();
+        for cert in read_certs(&config.ca)? {
+            roots.add(&cert).map_err(|err| config_error(&config.ca, err))?;
+        }
+
+        let mut server = ServerConfig::new(AllowAnyAuthenticatedClient::new(roots.clone()));
+        server
+            .set_single_cert(certs.clone(), key.clone())
+            .map_err(|err| config_error(&config.cert, err))?;
+        let mut client = ClientConfig::new();
+        client.root_store = roots;
+        client
+            .set_single_client_cert(certs, key)
+            .map_err(|err| config_error(&config.cert, err))?;
+
+        Ok(Tls {
+            acceptor: TlsAcceptor::from(Arc::new(server)),
+            connector: TlsConnector::from(Arc::new(client)),
+        })
+    }
+
+    /// Accepts a connection from a peer, returning which of `nodes` its certificate belongs to.
+    pub async fn accept(&self, stream: TcpStream, nodes: &[Node]) -> Result<(NodeId, server::TlsStream<TcpStream>)> {
+        let stream = self.acceptor.accept(stream).await?;
+        let (_, session) = stream.get_ref();
+        let id = session
+            .get_peer_certificates()
+            .and_then(|certs| certs.into_iter().next())
+            .and_then(|cert| peer_id(&cert, nodes))
+            .ok_or_else(|| JosefineError::MessageError {
+                error_msg: "peer certificate does not belong to a node in the cluster".to_string(),
+            })?;
+        Ok((id, stream))
+    }
+
+    /// Connects to a peer, which must present the certificate for node `id`.
+    pub async fn connect(&self, id: NodeId, stream: TcpStream) -> Result<client::TlsStream<TcpStream>> {
+        let name = node_name(id);
+        let name = DNSNameRef::try_from_ascii_str(&name).map_err(|err| JosefineError::MessageError {
+            error_msg: err.to_string(),
+        })?;
+        Ok(self.connector.connect(name, stream).await?)
+    }
+}
+
+/// The node a verified peer certificate was issued to.
+fn peer_id(cert: &Certificate, nodes: &[Node]) -> Option<NodeId> {
+    let cert = EndEntityCert::from(&cert.0).ok()?;
+    nodes.iter().map(|node| node.id).find(|id| {
+        let name = node_name(*id);
+        DNSNameRef::try_from_ascii_str(&name).is_ok_and(|name| cert.verify_is_valid_for_dns_name(name).is_ok())
+    })
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    match pemfile::certs(&mut reader) {
+        Ok(certs) if !certs.is_empty() => Ok(certs),
+        _ => Err(config_error(path, "no certificates found")),
+    }
+}
+
+fn read_key(path: &Path) -> Result<PrivateKey> {
+    let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(path)?)).unwrap_or_default();
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut BufReader::new(File::open(path)?)).unwrap_or_default();
+    }
+    keys.into_iter().next().ok_or_else(|| config_error(path, "no private key found"))
+}
+
+fn config_error<E: std::fmt::Display>(path: &Path, err: E) -> JosefineError {
+    JosefineError::ConfigError {
+        file_path: path.display().to_string(),
+        error_msg: err.to_string(),
+    }
+}
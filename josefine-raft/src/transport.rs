@@ -0,0 +1,208 @@
+//! How raft nodes exchange messages. The server hands a transport the messages raft wants sent to
+//! its peers and a channel to deliver the messages it receives from them, so the same event loop
+//! can run over TCP, in-process channels, or anything else that can move a [`Message`].
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use slog::Logger;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::{broadcast, watch};
+
+use josefine_core::error::Result;
+
+use crate::raft::{Node, NodeId};
+use crate::rpc::{Address, Message};
+
+pub use crate::tcp::TcpTransport;
+
+/// Carries messages between this node and its peers.
+pub trait Transport: Send + 'static {
+    /// Starts exchanging messages, returning a task that runs until shutdown.
+    ///
+    /// * `id` - The id of this node, which outgoing messages are sent from.
+    /// * `peers` - The nodes in the current cluster configuration, which changes as nodes join
+    ///   and leave.
+    /// * `in_tx` - Where messages received from peers are delivered.
+    /// * `out_rx` - Messages to send, addressed to a single peer or to all of them. Closed when
+    ///   the event loop stops.
+    fn start(
+        self,
+        log: Logger,
+        shutdown: broadcast::Receiver<()>,
+        id: NodeId,
+        peers: watch::Receiver<Vec<Node>>,
+        in_tx: UnboundedSender<Message>,
+        out_rx: UnboundedReceiver<Message>,
+    ) -> BoxFuture<'static, Result<()>>;
+
+    /// The messages the transport has given up on sending, which are reported in the metrics.
+    fn dropped(&self) -> DroppedMessages {
+        DroppedMessages::new()
+    }
+}
+
+/// The state of a transport's connection to a peer, which it reports to raft by delivering a
+/// [`Command::Connection`](crate::raft::Command::Connection) whenever it changes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConnectionState {
+    /// Messages are being sent to the peer.
+    Connected,
+    /// The connection failed and is being retried.
+    BackingOff,
+    /// The peer has been unreachable for long enough that retries are as far apart as they get.
+    Down,
+}
+
+/// Counts the messages a transport has given up on sending to each peer.
+#[derive(Clone, Debug, Default)]
+pub struct DroppedMessages {
+    counts: Arc<Mutex<HashMap<NodeId, u64>>>,
+}
+
+impl DroppedMessages {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, node_id: NodeId) {
+        *self.counts.lock().unwrap().entry(node_id).or_insert(0) += 1;
+    }
+
+    /// The number of messages dropped for the peer.
+    pub fn get(&self, node_id: NodeId) -> u64 {
+        self.counts.lock().unwrap().get(&node_id).copied().unwrap_or(0)
+    }
+
+    /// The number of messages dropped for each peer that has had any dropped.
+    pub fn counts(&self) -> HashMap<NodeId, u64> {
+        self.counts.lock().unwrap().clone()
+    }
+}
+
+/// Connects nodes running in the same process. Each node gets its transport from a shared
+/// network, and messages to nodes that aren't running are dropped as they would be by a lost
+/// connection.
+#[derive(Clone, Default)]
+pub struct ChannelNetwork {
+    nodes: Arc<Mutex<HashMap<NodeId, UnboundedSender<Message>>>>,
+}
+
+impl ChannelNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A transport for a node on this network.
+    pub fn transport(&self) -> ChannelTransport {
+        ChannelTransport {
+            network: self.clone(),
+        }
+    }
+}
+
+/// A node's connection to a [`ChannelNetwork`].
+pub struct ChannelTransport {
+    network: ChannelNetwork,
+}
+
+impl Transport for ChannelTransport {
+    fn start(
+        self,
+        log: Logger,
+        mut shutdown: broadcast::Receiver<()>,
+        id: NodeId,
+        peers: watch::Receiver<Vec<Node>>,
+        in_tx: UnboundedSender<Message>,
+        mut out_rx: UnboundedReceiver<Message>,
+    ) -> BoxFuture<'static, Result<()>> {
+        async move {
+            let nodes = self.network.nodes;
+            nodes.lock().unwrap().insert(id, in_tx);
+
+            loop {
+                let mut message = tokio::select! {
+                    _ = shutdown.recv() => break,
+                    message = out_rx.recv() => match message {
+                        Some(message) => message,
+                        None => break,
+                    },
+                };
+
+                if message.from == Address::Local {
+                    message.from = Address::Peer(id)
+                }
+                let peers: Vec<NodeId> = peers.borrow().iter().map(|node| node.id).collect();
+                let to = match &message.to {
+                    Address::Peers => peers,
+                    Address::Peer(peer) if peers.contains(peer) => vec![*peer],
+                    addr => {
+                        error!(log, "received outbound message for unknown address"; "addr" => format!("{:?}", addr));
+                        continue;
+                    }
+                };
+
+                let nodes = nodes.lock().unwrap();
+                for peer in to {
+                    if let Some(tx) = nodes.get(&peer) {
+                        // the peer may be shutting down, which is no different to it being down
+                        let _ = tx.send(message.clone());
+                    }
+                }
+            }
+
+            nodes.lock().unwrap().remove(&id);
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use josefine_core::error::Result;
+
+    use super::ChannelNetwork;
+    use crate::config::RaftConfig;
+    use crate::raft::{Node, RaftHandle};
+    use crate::server::Server;
+    use crate::test::{new_config, TestFsm};
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 3)]
+    async fn elects_over_channels() -> Result<()> {
+        let network = ChannelNetwork::new();
+        let nodes: Vec<Node> = (1..=3)
+            .map(|id| Node {
+                id,
+                addr: SocketAddr::new(RaftConfig::default().ip, 0),
+            })
+            .collect();
+
+        let servers: Vec<_> = nodes
+            .iter()
+            .map(|node| {
+                let config = RaftConfig {
+                    id: node.id,
+                    nodes: nodes.iter().filter(|n| n.id != node.id).cloned().collect(),
+                    ..new_config()
+                };
+                let (_client_tx, client_rx) = tokio::sync::mpsc::unbounded_channel();
+                let server = Server::with_transport(config, network.transport());
+                tokio::spawn(server.run(Some(Duration::from_secs(2)), TestFsm::default(), client_rx))
+            })
+            .collect();
+
+        let mut leaders = 0;
+        for server in servers {
+            if let RaftHandle::Leader(_) = server.await?? {
+                leaders += 1;
+            }
+        }
+        assert_eq!(leaders, 1);
+        Ok(())
+    }
+}
@@ -1,20 +1,45 @@
 extern crate josefine_raft;
 
 use std::time::Duration;
-use josefine_core::error::Result;
+use josefine_core::error::{JosefineError, Result};
 use josefine_raft::config::RaftConfig;
 use josefine_raft::raft::{Node, RaftHandle};
 use josefine_raft::JosefineRaft;
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::thread::JoinHandle;
+use uuid::Uuid;
 
-fn new_cluster(ids: Vec<u32>) -> Vec<JosefineRaft> {
-    ids.iter()
-        .map(|id| {
+/// A data directory that is removed once the test is over.
+struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    fn new() -> TempDir {
+        TempDir {
+            path: std::env::temp_dir().join(format!("josefine-it-{}", Uuid::new_v4())),
+        }
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+fn new_cluster(ids: Vec<u32>) -> (Vec<JosefineRaft>, Vec<TempDir>) {
+    let dirs: Vec<TempDir> = ids.iter().map(|_| TempDir::new()).collect();
+    let nodes = ids
+        .iter()
+        .zip(&dirs)
+        .map(|(id, dir)| {
             let default = RaftConfig::default();
             let config = RaftConfig {
                 id: *id,
+                data_directory: dir.path.clone(),
                 port: default.port + *id as u16,
                 nodes: ids
                     .iter()
@@ -28,7 +53,8 @@ fn new_cluster(ids: Vec<u32>) -> Vec<JosefineRaft> {
             };
             JosefineRaft::new(config)
         })
-        .collect()
+        .collect();
+    (nodes, dirs)
 }
 
 #[derive(Debug)]
@@ -47,14 +73,25 @@ impl josefine_raft::fsm::Fsm for IntegrationFsm {
         Ok(input)
     }
 
-    fn query(&mut self, data: Vec<u8>) -> Result<Vec<u8>> {
+    fn query(&mut self, _data: Vec<u8>) -> Result<Vec<u8>> {
         todo!()
     }
+
+    fn snapshot(&self) -> Result<Vec<u8>> {
+        Ok(vec![self.state])
+    }
+
+    fn restore(&mut self, snapshot: Vec<u8>) -> Result<()> {
+        self.state = *snapshot.first().ok_or_else(|| JosefineError::Internal {
+            error_msg: "the snapshot is empty".to_string(),
+        })?;
+        Ok(())
+    }
 }
 
 #[test]
 fn it_elects() {
-    let cluster = new_cluster(vec![1, 2, 3]);
+    let (cluster, _dirs) = new_cluster(vec![1, 2, 3]);
 
     let join_handles: Vec<JoinHandle<Result<RaftHandle>>> = cluster
         .into_iter()